@@ -0,0 +1,116 @@
+//! Measures the driver with many requests in flight at once.
+//!
+//! The connections answer the commands one at a time, like a connection reading the replies off
+//! a socket, so that the driver is woken once for every reply while the others are pending.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::{
+    channel::{mpsc, oneshot},
+    future,
+    prelude::*,
+};
+use redis_cluster_async::{
+    redis::{aio::ConnectionLike, cmd, Cmd, IntoConnectionInfo, Pipeline, RedisFuture, Value},
+    testing::{MockConnection, MockEnv, MockSlots},
+    Connect,
+};
+
+#[derive(Clone)]
+struct QueuedConnection {
+    inner: MockConnection,
+    // Takes the replies which are ready to be read
+    replies: mpsc::UnboundedSender<oneshot::Sender<()>>,
+}
+
+impl Connect for QueuedConnection {
+    fn connect<'a, T>(info: T) -> RedisFuture<'a, Self>
+    where
+        T: IntoConnectionInfo + Send + 'a,
+    {
+        MockConnection::connect(info)
+            .map_ok(|inner| {
+                let (replies, mut queue) = mpsc::unbounded::<oneshot::Sender<()>>();
+                tokio::spawn(async move {
+                    while let Some(reply) = queue.next().await {
+                        let _ = reply.send(());
+                        tokio::task::yield_now().await
+                    }
+                });
+                QueuedConnection { inner, replies }
+            })
+            .boxed()
+    }
+}
+
+impl QueuedConnection {
+    // Waits for the reply of the previous commands to be read
+    fn queue(&self) -> impl Future<Output = ()> {
+        let (sender, receiver) = oneshot::channel();
+        let _ = self.replies.unbounded_send(sender);
+        receiver.map(|_| ())
+    }
+}
+
+impl ConnectionLike for QueuedConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        self.queue()
+            .then(move |()| self.inner.req_packed_command(cmd))
+            .boxed()
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        self.queue()
+            .then(move |()| self.inner.req_packed_commands(pipeline, offset, count))
+            .boxed()
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+fn concurrent_requests(c: &mut Criterion) {
+    let slots = MockSlots::single("bench_driver", 6379);
+    let mut env = MockEnv::new("bench_driver", move |cmd: &[u8], _port| {
+        slots.respond(cmd)?;
+        Err(Ok(Value::Data(b"1".to_vec())))
+    });
+    let connection = env
+        .runtime
+        .block_on(env.client.get_generic_connection::<QueuedConnection>())
+        .unwrap();
+
+    let mut group = c.benchmark_group("concurrent_requests");
+    group.sample_size(10);
+    for &requests in &[100, 1_000, 10_000] {
+        group.throughput(Throughput::Elements(requests as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(requests),
+            &requests,
+            |b, &requests| {
+                b.iter(|| {
+                    env.runtime
+                        .block_on(future::join_all((0..requests).map(|i| {
+                            let mut connection = connection.clone();
+                            async move {
+                                cmd("GET")
+                                    .arg(i)
+                                    .query_async::<_, i32>(&mut connection)
+                                    .await
+                                    .unwrap()
+                            }
+                        })))
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_requests);
+criterion_main!(benches);
@@ -0,0 +1,497 @@
+//! Typed views of the cluster introspection commands.
+//!
+//! `ClusterNode`, `ClusterInfo` and `ClusterShard` implement `FromRedisValue`, so they can be read
+//! from any connection, while the methods on `Connection` route the commands through the cluster.
+//! The keys of a slot can be listed with `Connection::keys_in_slot` and counted for every slot
+//! with `Connection::slot_key_counts`.
+//! Slots can be moved between masters with `Connection::migrate_slot` and
+//! `Connection::rebalance`, replicas promoted with `Connection::failover`, and new clusters can be
+//! formed with `ClusterSetup`.
+
+use std::{collections::HashMap, str::FromStr};
+
+use redis::{aio::ConnectionLike, cmd, ErrorKind, FromRedisValue, RedisError, RedisResult, Value};
+
+use crate::Connection;
+
+pub use self::{
+    bootstrap::ClusterSetup,
+    failover::{FailoverMode, FailoverReport},
+    migrate::{MigrationProgress, RebalancePlan, SlotMove},
+};
+
+mod bootstrap;
+mod failover;
+mod keys;
+mod migrate;
+
+/// The state of a node's link to the cluster bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    Connected,
+    Disconnected,
+}
+
+/// A node as reported by `CLUSTER NODES`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterNode {
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+    pub cluster_bus_port: Option<u16>,
+    pub hostname: Option<String>,
+    /// Flags such as `myself`, `master`, `slave`, `fail?` or `fail`.
+    pub flags: Vec<String>,
+    /// The id of the master, if this node is a replica.
+    pub master_id: Option<String>,
+    pub ping_sent: u64,
+    pub pong_recv: u64,
+    pub config_epoch: u64,
+    pub link_state: LinkState,
+    /// Inclusive slot ranges served by the node.
+    pub slots: Vec<(u16, u16)>,
+    /// Slots which are being migrated to the node with the given id.
+    pub migrating: Vec<(u16, String)>,
+    /// Slots which are being imported from the node with the given id.
+    pub importing: Vec<(u16, String)>,
+}
+
+impl ClusterNode {
+    /// Returns true if the node has `flag` set.
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f == flag)
+    }
+
+    pub fn is_master(&self) -> bool {
+        self.has_flag("master")
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.has_flag("slave")
+    }
+
+    /// Returns true if this is the node which answered the command.
+    pub fn is_myself(&self) -> bool {
+        self.has_flag("myself")
+    }
+
+    /// Returns true if the node is considered failing, or possibly failing.
+    pub fn is_failing(&self) -> bool {
+        self.has_flag("fail") || self.has_flag("fail?")
+    }
+
+    /// The address of the node in the same `redis://host:port` form used by the slot map.
+    pub fn url(&self) -> String {
+        format!("redis://{}:{}", self.ip, self.port)
+    }
+}
+
+fn parse_error(what: &'static str, detail: String) -> RedisError {
+    RedisError::from((ErrorKind::TypeError, what, detail))
+}
+
+fn node_error(line: &str) -> RedisError {
+    parse_error("Invalid CLUSTER NODES line", line.to_string())
+}
+
+impl FromStr for ClusterNode {
+    type Err = RedisError;
+
+    fn from_str(line: &str) -> RedisResult<Self> {
+        let mut parts = line.split_whitespace();
+        let mut next = || parts.next().ok_or_else(|| node_error(line));
+
+        let id = next()?.to_string();
+
+        // ip:port@cport[,hostname]
+        let address = next()?;
+        let (address, hostname) = match address.split_once(',') {
+            Some((address, hostname)) if !hostname.is_empty() => {
+                (address, Some(hostname.to_string()))
+            }
+            Some((address, _)) => (address, None),
+            None => (address, None),
+        };
+        let (address, cluster_bus_port) = match address.split_once('@') {
+            Some((address, cport)) => (address, Some(cport.parse().map_err(|_| node_error(line))?)),
+            None => (address, None),
+        };
+        let (ip, port) = address.rsplit_once(':').ok_or_else(|| node_error(line))?;
+        let port = port.parse().map_err(|_| node_error(line))?;
+
+        let flags = next()?
+            .split(',')
+            .filter(|flag| !flag.is_empty() && *flag != "noflags")
+            .map(String::from)
+            .collect();
+        let master_id = match next()? {
+            "-" => None,
+            master_id => Some(master_id.to_string()),
+        };
+        let mut number = || -> RedisResult<u64> { next()?.parse().map_err(|_| node_error(line)) };
+        let ping_sent = number()?;
+        let pong_recv = number()?;
+        let config_epoch = number()?;
+        let link_state = match parts.next() {
+            Some("connected") => LinkState::Connected,
+            Some("disconnected") => LinkState::Disconnected,
+            _ => return Err(node_error(line)),
+        };
+
+        let mut slots = Vec::new();
+        let mut migrating = Vec::new();
+        let mut importing = Vec::new();
+        for slot in parts {
+            if let Some(transfer) = slot.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+                if let Some((slot, node)) = transfer.split_once("->-") {
+                    migrating.push((
+                        slot.parse().map_err(|_| node_error(line))?,
+                        node.to_string(),
+                    ));
+                } else if let Some((slot, node)) = transfer.split_once("-<-") {
+                    importing.push((
+                        slot.parse().map_err(|_| node_error(line))?,
+                        node.to_string(),
+                    ));
+                } else {
+                    return Err(node_error(line));
+                }
+                continue;
+            }
+            let range = match slot.split_once('-') {
+                Some((start, end)) => (start.parse(), end.parse()),
+                None => (slot.parse(), slot.parse()),
+            };
+            match range {
+                (Ok(start), Ok(end)) => slots.push((start, end)),
+                _ => return Err(node_error(line)),
+            }
+        }
+
+        Ok(ClusterNode {
+            id,
+            ip: ip.to_string(),
+            port,
+            cluster_bus_port,
+            hostname,
+            flags,
+            master_id,
+            ping_sent,
+            pong_recv,
+            config_epoch,
+            link_state,
+            slots,
+            migrating,
+            importing,
+        })
+    }
+}
+
+fn parse_nodes(text: &str) -> RedisResult<Vec<ClusterNode>> {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+impl FromRedisValue for ClusterNode {
+    fn from_redis_value(v: &Value) -> RedisResult<Self> {
+        String::from_redis_value(v)?.trim().parse()
+    }
+
+    // `CLUSTER NODES` returns every node in a single string, one per line
+    fn from_byte_vec(vec: &[u8]) -> Option<Vec<Self>> {
+        parse_nodes(&String::from_utf8_lossy(vec)).ok()
+    }
+}
+
+/// The state of the cluster as reported by `CLUSTER INFO`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterInfo {
+    /// True if `cluster_state` is `ok`.
+    pub state_ok: bool,
+    pub slots_assigned: u32,
+    pub slots_ok: u32,
+    pub slots_pfail: u32,
+    pub slots_fail: u32,
+    pub known_nodes: u32,
+    /// The number of masters serving at least one slot.
+    pub size: u32,
+    pub current_epoch: u64,
+    pub my_epoch: u64,
+    /// Every field of the reply, including the ones above.
+    pub fields: HashMap<String, String>,
+}
+
+impl FromStr for ClusterInfo {
+    type Err = RedisError;
+
+    fn from_str(text: &str) -> RedisResult<Self> {
+        let fields: HashMap<String, String> = text
+            .lines()
+            .filter_map(|line| line.trim().split_once(':'))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        fn field<T: FromStr>(fields: &HashMap<String, String>, name: &str) -> RedisResult<T> {
+            fields
+                .get(name)
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| {
+                    parse_error("Invalid CLUSTER INFO reply", format!("Missing `{}`", name))
+                })
+        }
+
+        let state: String = field(&fields, "cluster_state")?;
+        Ok(ClusterInfo {
+            state_ok: state == "ok",
+            slots_assigned: field(&fields, "cluster_slots_assigned")?,
+            slots_ok: field(&fields, "cluster_slots_ok")?,
+            slots_pfail: field(&fields, "cluster_slots_pfail")?,
+            slots_fail: field(&fields, "cluster_slots_fail")?,
+            known_nodes: field(&fields, "cluster_known_nodes")?,
+            size: field(&fields, "cluster_size")?,
+            current_epoch: field(&fields, "cluster_current_epoch")?,
+            my_epoch: field(&fields, "cluster_my_epoch")?,
+            fields,
+        })
+    }
+}
+
+impl FromRedisValue for ClusterInfo {
+    fn from_redis_value(v: &Value) -> RedisResult<Self> {
+        String::from_redis_value(v)?.parse()
+    }
+}
+
+/// A node of a shard as reported by `CLUSTER SHARDS`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardNode {
+    pub id: String,
+    pub endpoint: String,
+    pub ip: String,
+    pub hostname: Option<String>,
+    pub port: Option<u16>,
+    pub tls_port: Option<u16>,
+    /// Either `master` or `replica`.
+    pub role: String,
+    pub replication_offset: u64,
+    /// One of `online`, `failed` or `loading`.
+    pub health: String,
+}
+
+impl ShardNode {
+    pub fn is_master(&self) -> bool {
+        self.role == "master"
+    }
+}
+
+/// A master and its replicas as reported by `CLUSTER SHARDS` (redis 7 and later).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterShard {
+    /// Inclusive slot ranges served by the shard.
+    pub slots: Vec<(u16, u16)>,
+    pub nodes: Vec<ShardNode>,
+}
+
+fn shard_error(detail: &str) -> RedisError {
+    parse_error("Invalid CLUSTER SHARDS reply", detail.to_string())
+}
+
+// `CLUSTER SHARDS` describes each item as a flat list of alternating names and values
+fn named_values(v: &Value) -> RedisResult<HashMap<String, &Value>> {
+    match v {
+        Value::Bulk(items) if items.len() % 2 == 0 => items
+            .chunks(2)
+            .map(|pair| Ok((String::from_redis_value(&pair[0])?, &pair[1])))
+            .collect(),
+        _ => Err(shard_error("Expected a list of names and values")),
+    }
+}
+
+impl FromRedisValue for ShardNode {
+    fn from_redis_value(v: &Value) -> RedisResult<Self> {
+        let fields = named_values(v)?;
+        fn required<T: FromRedisValue>(
+            fields: &HashMap<String, &Value>,
+            name: &str,
+        ) -> RedisResult<T> {
+            match fields.get(name) {
+                Some(value) => T::from_redis_value(value),
+                None => Err(shard_error(&format!("Missing `{}`", name))),
+            }
+        }
+        fn optional<T: FromRedisValue>(
+            fields: &HashMap<String, &Value>,
+            name: &str,
+        ) -> RedisResult<Option<T>> {
+            fields
+                .get(name)
+                .map(|value| T::from_redis_value(value))
+                .transpose()
+        }
+
+        Ok(ShardNode {
+            id: required(&fields, "id")?,
+            endpoint: required(&fields, "endpoint")?,
+            ip: required(&fields, "ip")?,
+            hostname: optional::<String>(&fields, "hostname")?.filter(|name| !name.is_empty()),
+            port: optional(&fields, "port")?,
+            tls_port: optional(&fields, "tls-port")?,
+            role: required(&fields, "role")?,
+            replication_offset: required(&fields, "replication-offset")?,
+            health: required(&fields, "health")?,
+        })
+    }
+}
+
+impl FromRedisValue for ClusterShard {
+    fn from_redis_value(v: &Value) -> RedisResult<Self> {
+        let fields = named_values(v)?;
+        let bounds: Vec<u16> = match fields.get("slots") {
+            Some(slots) => FromRedisValue::from_redis_value(slots)?,
+            None => return Err(shard_error("Missing `slots`")),
+        };
+        if !bounds.chunks_exact(2).remainder().is_empty() {
+            return Err(shard_error("Expected pairs of slot bounds"));
+        }
+        let nodes = match fields.get("nodes") {
+            Some(nodes) => FromRedisValue::from_redis_value(nodes)?,
+            None => return Err(shard_error("Missing `nodes`")),
+        };
+        Ok(ClusterShard {
+            slots: bounds.chunks(2).map(|pair| (pair[0], pair[1])).collect(),
+            nodes,
+        })
+    }
+}
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// Runs `CLUSTER NODES` on a random master and returns its view of the cluster.
+    pub async fn cluster_nodes(&mut self) -> RedisResult<Vec<ClusterNode>> {
+        let nodes: String = self.query_random(cmd("CLUSTER").arg("NODES")).await?;
+        parse_nodes(&nodes)
+    }
+
+    /// Runs `CLUSTER INFO` on a random master.
+    pub async fn cluster_info(&mut self) -> RedisResult<ClusterInfo> {
+        self.query_random(cmd("CLUSTER").arg("INFO")).await
+    }
+
+    /// Runs `CLUSTER SHARDS` on a random master. Requires redis 7 or later.
+    pub async fn cluster_shards(&mut self) -> RedisResult<Vec<ClusterShard>> {
+        self.query_random(cmd("CLUSTER").arg("SHARDS")).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODES: &str = "\
+07c37dfeb235213a872192d90877d0cd55635b91 127.0.0.1:30004@31004,host4 slave e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca 0 1426238317239 4 connected
+67ed2db8d677e59ec4a4cefb06858cf2a1a89fa1 127.0.0.1:30002@31002 master - 0 1426238316232 2 connected 5461-10922
+e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca 127.0.0.1:30001@31001 myself,master - 0 0 1 connected 0-5460 [5461-<-67ed2db8d677e59ec4a4cefb06858cf2a1a89fa1]
+292f8b365bb7edb5e285caf0b7e6ddc7265d2f4f 127.0.0.1:30003 master,fail? - 0 1426238318243 3 disconnected 10923 10924-16383 [93->-67ed2db8d677e59ec4a4cefb06858cf2a1a89fa1]
+";
+
+    #[test]
+    fn parse_cluster_nodes() {
+        let nodes: Vec<ClusterNode> =
+            FromRedisValue::from_redis_value(&Value::Data(NODES.as_bytes().to_vec())).unwrap();
+        assert_eq!(nodes.len(), 4);
+
+        assert!(nodes[0].is_replica());
+        assert_eq!(nodes[0].hostname.as_deref(), Some("host4"));
+        assert_eq!(
+            nodes[0].master_id.as_deref(),
+            Some("e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca")
+        );
+        assert!(nodes[0].slots.is_empty());
+
+        assert_eq!(nodes[1].url(), "redis://127.0.0.1:30002");
+        assert_eq!(nodes[1].cluster_bus_port, Some(31002));
+        assert_eq!(nodes[1].slots, vec![(5461, 10922)]);
+
+        assert!(nodes[2].is_myself() && nodes[2].is_master());
+        assert_eq!(
+            nodes[2].importing,
+            vec![(5461, "67ed2db8d677e59ec4a4cefb06858cf2a1a89fa1".to_string())]
+        );
+
+        assert!(nodes[3].is_failing());
+        assert_eq!(nodes[3].cluster_bus_port, None);
+        assert_eq!(nodes[3].link_state, LinkState::Disconnected);
+        assert_eq!(nodes[3].slots, vec![(10923, 10923), (10924, 16383)]);
+        assert_eq!(
+            nodes[3].migrating,
+            vec![(93, "67ed2db8d677e59ec4a4cefb06858cf2a1a89fa1".to_string())]
+        );
+    }
+
+    #[test]
+    fn parse_cluster_info() {
+        let info: ClusterInfo = FromRedisValue::from_redis_value(&Value::Data(
+            b"cluster_state:ok\r\ncluster_slots_assigned:16384\r\ncluster_slots_ok:16384\r\n\
+cluster_slots_pfail:0\r\ncluster_slots_fail:0\r\ncluster_known_nodes:6\r\ncluster_size:3\r\n\
+cluster_current_epoch:6\r\ncluster_my_epoch:2\r\ncluster_stats_messages_sent:1483972\r\n"
+                .to_vec(),
+        ))
+        .unwrap();
+        assert!(info.state_ok);
+        assert_eq!(info.slots_assigned, 16384);
+        assert_eq!(info.known_nodes, 6);
+        assert_eq!(info.size, 3);
+        assert_eq!(info.my_epoch, 2);
+        assert_eq!(
+            info.fields
+                .get("cluster_stats_messages_sent")
+                .map(|s| &s[..]),
+            Some("1483972")
+        );
+    }
+
+    #[test]
+    fn parse_cluster_shards() {
+        fn data(s: &str) -> Value {
+            Value::Data(s.as_bytes().to_vec())
+        }
+        let node = |id: &str, port: i64, role: &str| {
+            Value::Bulk(vec![
+                data("id"),
+                data(id),
+                data("port"),
+                Value::Int(port),
+                data("ip"),
+                data("127.0.0.1"),
+                data("endpoint"),
+                data("127.0.0.1"),
+                data("role"),
+                data(role),
+                data("replication-offset"),
+                Value::Int(72156),
+                data("health"),
+                data("online"),
+            ])
+        };
+        let shards: Vec<ClusterShard> =
+            FromRedisValue::from_redis_value(&Value::Bulk(vec![Value::Bulk(vec![
+                data("slots"),
+                Value::Bulk(vec![Value::Int(0), Value::Int(5460)]),
+                data("nodes"),
+                Value::Bulk(vec![
+                    node("a", 30001, "master"),
+                    node("b", 30004, "replica"),
+                ]),
+            ])]))
+            .unwrap();
+        assert_eq!(shards.len(), 1);
+        assert_eq!(shards[0].slots, vec![(0, 5460)]);
+        assert!(shards[0].nodes[0].is_master());
+        assert_eq!(shards[0].nodes[1].port, Some(30004));
+        assert_eq!(shards[0].nodes[1].hostname, None);
+    }
+}
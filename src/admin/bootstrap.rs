@@ -0,0 +1,255 @@
+//! Forming a cluster out of standalone `cluster-enabled` nodes.
+
+use std::{io, sync::Arc, time::Duration};
+
+use futures::future;
+use log::trace;
+use redis::{
+    aio::{ConnectionLike, MultiplexedConnection},
+    cmd, ConnectionAddr, ConnectionInfo, ErrorKind, IntoConnectionInfo, RedisError, RedisResult,
+};
+
+use super::{parse_nodes, ClusterInfo, ClusterNode};
+use crate::{
+    clock::{Clock, Time},
+    with_timeout, Client, Connect, SLOT_SIZE,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+// How often the nodes are polled while waiting for the cluster to form
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Creates a cluster from redis processes which run with `cluster-enabled yes` but are not yet
+/// part of a cluster.
+///
+/// The first nodes become masters and share the slots evenly, the rest are assigned as
+/// replicas to the masters in turn. Slots are assigned with `CLUSTER ADDSLOTSRANGE` and, on nodes
+/// older than redis 7 which do not know it, with `CLUSTER ADDSLOTS`.
+///
+/// # Example
+/// ```rust,no_run
+/// use redis_cluster_async::admin::ClusterSetup;
+///
+/// #[tokio::main]
+/// async fn main() -> redis::RedisResult<()> {
+///     let nodes = (7000..7006).map(|port| format!("redis://127.0.0.1:{}/", port)).collect();
+///     let client = ClusterSetup::open(nodes)?.set_replicas(1).create().await?;
+///     let mut connection = client.get_connection().await?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClusterSetup {
+    nodes: Vec<ConnectionInfo>,
+    replicas: usize,
+    timeout: Duration,
+    clock: Time,
+}
+
+impl ClusterSetup {
+    pub fn open<T: IntoConnectionInfo>(nodes: Vec<T>) -> RedisResult<ClusterSetup> {
+        Ok(ClusterSetup {
+            nodes: nodes
+                .into_iter()
+                .map(|node| node.into_connection_info())
+                .collect::<RedisResult<_>>()?,
+            replicas: 0,
+            timeout: DEFAULT_TIMEOUT,
+            clock: Time::default(),
+        })
+    }
+
+    /// Set the number of replicas for each master (default 0).
+    pub fn set_replicas(&mut self, replicas: usize) -> &mut Self {
+        self.replicas = replicas;
+        self
+    }
+
+    /// Set how long to wait for the cluster to form (default 30 seconds).
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the clock to wait on while the cluster forms, which the returned `Client` uses as
+    /// well (default the clock of the tokio runtime).
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.clock = Time::new(clock);
+        self
+    }
+
+    /// Forms the cluster and waits until every node reports `cluster_state:ok`.
+    pub async fn create(&self) -> RedisResult<Client> {
+        self.create_generic::<MultiplexedConnection>().await
+    }
+
+    /// Like `create`, but talks to the nodes with any connection type.
+    pub async fn create_generic<C>(&self) -> RedisResult<Client>
+    where
+        C: ConnectionLike + Connect + Send + 'static,
+    {
+        let masters = self.nodes.len() / (self.replicas + 1);
+        if masters == 0 {
+            return Err(RedisError::from((
+                ErrorKind::InvalidClientConfig,
+                "redis_cluster: Not enough nodes for a master and its replicas",
+            )));
+        }
+
+        let mut conns = future::try_join_all(
+            self.nodes
+                .iter()
+                .map(|node| C::connect(node.clone())),
+        )
+        .await?;
+        let mut ids = Vec::with_capacity(conns.len());
+        for (node, conn) in self.nodes.iter().zip(&mut conns) {
+            let info: ClusterInfo = cmd("CLUSTER").arg("INFO").query_async(conn).await?;
+            if info.known_nodes > 1 || info.slots_assigned > 0 {
+                return Err(RedisError::from((
+                    ErrorKind::InvalidClientConfig,
+                    "redis_cluster: Node is already part of a cluster",
+                    format!("{:?}", node.addr),
+                )));
+            }
+            let id: String = cmd("CLUSTER").arg("MYID").query_async(conn).await?;
+            ids.push(id);
+        }
+
+        for (conn, (start, end)) in conns.iter_mut().zip(slot_ranges(masters)) {
+            trace!("Assigning slots {}-{}", start, end);
+            let assigned = cmd("CLUSTER")
+                .arg("ADDSLOTSRANGE")
+                .arg(start)
+                .arg(end)
+                .query_async(conn)
+                .await;
+            match assigned {
+                // ADDSLOTSRANGE was added in redis 7, older nodes need every slot listed
+                Err(ref err) if err.code() == Some("ERR") => {
+                    trace!("ADDSLOTSRANGE failed, assigning slots one by one: {}", err);
+                    let () = cmd("CLUSTER")
+                        .arg("ADDSLOTS")
+                        .arg((start..=end).collect::<Vec<_>>())
+                        .query_async(conn)
+                        .await?;
+                }
+                result => result?,
+            }
+        }
+
+        // Introducing every node to the first one is enough, the gossip spreads the rest
+        for node in &self.nodes[1..] {
+            let (ip, port) = resolve(node).await?;
+            let () = cmd("CLUSTER")
+                .arg("MEET")
+                .arg(ip)
+                .arg(port)
+                .query_async(&mut conns[0])
+                .await?;
+        }
+        let node_count = conns.len();
+        self.wait_for(&mut conns, |_, nodes| nodes.len() == node_count)
+            .await?;
+
+        for (i, conn) in conns.iter_mut().enumerate().skip(masters) {
+            let master = &ids[i % masters];
+            trace!("Replicating {} from node {}", master, i);
+            let () = cmd("CLUSTER")
+                .arg("REPLICATE")
+                .arg(master)
+                .query_async(conn)
+                .await?;
+        }
+        let replicas = node_count - masters;
+        self.wait_for(&mut conns, |info, nodes| {
+            info.state_ok && nodes.iter().filter(|node| node.is_replica()).count() == replicas
+        })
+        .await?;
+
+        let mut client = Client::open(self.nodes.clone())?;
+        client.params.clock = self.clock.clone();
+        Ok(client)
+    }
+
+    // Polls `CLUSTER INFO` and `CLUSTER NODES` on every node until `done` holds for all of them
+    async fn wait_for<C: ConnectionLike>(
+        &self,
+        conns: &mut [C],
+        done: impl Fn(&ClusterInfo, &[ClusterNode]) -> bool,
+    ) -> RedisResult<()> {
+        let poll = async {
+            loop {
+                let mut converged = true;
+                for conn in conns.iter_mut() {
+                    let info: ClusterInfo = cmd("CLUSTER").arg("INFO").query_async(conn).await?;
+                    let nodes: String = cmd("CLUSTER").arg("NODES").query_async(conn).await?;
+                    converged &= done(&info, &parse_nodes(&nodes)?);
+                }
+                if converged {
+                    return Ok(());
+                }
+                self.clock.delay(POLL_INTERVAL).await;
+            }
+        };
+        with_timeout(&self.clock, Some(self.timeout), poll, || {
+            RedisError::from(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "redis_cluster: Timed out waiting for the cluster to form",
+            ))
+        })
+        .await
+    }
+}
+
+// CLUSTER MEET only accepts IP addresses
+async fn resolve(node: &ConnectionInfo) -> RedisResult<(String, u16)> {
+    match *node.addr {
+        ConnectionAddr::Tcp(ref host, port) => {
+            let addr = tokio::net::lookup_host((&host[..], port))
+                .await?
+                .next()
+                .ok_or_else(|| {
+                    RedisError::from((
+                        ErrorKind::InvalidClientConfig,
+                        "redis_cluster: Unable to resolve host",
+                        host.clone(),
+                    ))
+                })?;
+            Ok((addr.ip().to_string(), port))
+        }
+        _ => Err(RedisError::from((
+            ErrorKind::InvalidClientConfig,
+            "redis_cluster: Clusters can only be formed from TCP nodes",
+        ))),
+    }
+}
+
+// Splits the slots into `masters` inclusive ranges whose sizes differ by at most one
+fn slot_ranges(masters: usize) -> Vec<(u16, u16)> {
+    let base = SLOT_SIZE / masters;
+    let extra = SLOT_SIZE % masters;
+    let mut start = 0;
+    (0..masters)
+        .map(|i| {
+            let len = base + usize::from(i < extra);
+            let range = (start as u16, (start + len - 1) as u16);
+            start += len;
+            range
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn even_slot_ranges() {
+        assert_eq!(
+            slot_ranges(3),
+            vec![(0, 5461), (5462, 10922), (10923, 16383)]
+        );
+        assert_eq!(slot_ranges(1), vec![(0, 16383)]);
+    }
+}
@@ -0,0 +1,148 @@
+//! Promoting a replica to master.
+
+use std::{io, time::Duration};
+
+use log::trace;
+use redis::{aio::ConnectionLike, cmd, ErrorKind, RedisError, RedisResult};
+
+use super::{parse_nodes, ClusterNode};
+use crate::{connect_and_check, metrics::Metrics, with_timeout, Connect, Connection};
+
+// How long to wait for every node to agree on the new master
+const CONVERGE_TIMEOUT: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How `CLUSTER FAILOVER` promotes the replica.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailoverMode {
+    /// Coordinate with the master, so no acknowledged writes are lost. The master must be
+    /// reachable.
+    Default,
+    /// Do not coordinate with the master, but still get the failover authorized by a majority
+    /// of the masters.
+    Force,
+    /// Promote the replica without any agreement from the other masters.
+    Takeover,
+}
+
+/// The result of `Connection::failover`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailoverReport {
+    /// The promoted replica, as `redis://host:port`.
+    pub new_master: String,
+    /// The master which the replica replicated, as `redis://host:port`.
+    pub old_master: String,
+    /// The time from issuing `CLUSTER FAILOVER` until every node agreed on the new master.
+    pub duration: Duration,
+}
+
+fn failover_error(desc: &'static str, detail: String) -> RedisError {
+    RedisError::from((ErrorKind::ClientError, desc, detail))
+}
+
+// Reads `CLUSTER NODES` from the node at `addr`, connecting to it first unless `conn` is open
+async fn peer_nodes<C>(conn: &mut Option<C>, addr: &str, metrics: &Metrics) -> RedisResult<String>
+where
+    C: ConnectionLike + Connect + Send + 'static,
+{
+    let conn = match conn {
+        Some(conn) => conn,
+        None => conn.get_or_insert(connect_and_check(addr, metrics).await?),
+    };
+    cmd("CLUSTER").arg("NODES").query_async(conn).await
+}
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Connect + Send + 'static,
+{
+    /// Promotes the replica at `replica`, given as `redis://host:port`, to master of its shard.
+    ///
+    /// After issuing `CLUSTER FAILOVER` the nodes are polled until every node which was not
+    /// failing agrees that `replica` is a master and serves the slots of its old master. The slot
+    /// map of this connection is then reloaded, so requests go straight to the new master.
+    pub async fn failover(
+        &mut self,
+        replica: &str,
+        mode: FailoverMode,
+    ) -> RedisResult<FailoverReport> {
+        let nodes: String = self
+            .query_node(replica, cmd("CLUSTER").arg("NODES"))
+            .await?;
+        let nodes = parse_nodes(&nodes)?;
+        let myself = nodes.iter().find(|node| node.is_myself()).ok_or_else(|| {
+            failover_error("Invalid CLUSTER NODES reply", "Missing `myself`".into())
+        })?;
+        let master = match (&myself.master_id, myself.is_replica()) {
+            (Some(master_id), true) => nodes.iter().find(|node| &node.id == master_id),
+            _ => None,
+        }
+        .ok_or_else(|| failover_error("Node is not a replica", replica.to_string()))?;
+        let id = myself.id.clone();
+        let old_master = master.url();
+        let slots = master.slots.clone();
+        let peers = nodes
+            .iter()
+            .filter(|node| !node.is_failing())
+            .map(ClusterNode::url)
+            .collect::<Vec<_>>();
+
+        let mut failover = cmd("CLUSTER");
+        failover.arg("FAILOVER");
+        match mode {
+            FailoverMode::Default => (),
+            FailoverMode::Force => {
+                failover.arg("FORCE");
+            }
+            FailoverMode::Takeover => {
+                failover.arg("TAKEOVER");
+            }
+        }
+        trace!("Failing over {} to {} ({:?})", old_master, replica, mode);
+        let clock = self.clock.clone();
+        let start = clock.now();
+        let () = self.query_node(replica, &failover).await?;
+
+        let promoted =
+            |node: &ClusterNode| node.id == id && node.is_master() && node.slots == slots;
+        let converge = async {
+            for peer in &peers {
+                // The peers may be outside the pool, so each is polled on a connection of its own
+                // instead of opening one for every poll
+                let mut conn = None::<C>;
+                loop {
+                    let converged = match peer_nodes(&mut conn, peer, &self.metrics).await {
+                        Ok(nodes) => parse_nodes(&nodes)?.iter().any(&promoted),
+                        // The old master may be the reason for the failover
+                        Err(err) if mode != FailoverMode::Default && peer == &old_master => {
+                            trace!("Unable to reach the old master {}: {}", peer, err);
+                            true
+                        }
+                        Err(err) => return Err(err),
+                    };
+                    if converged {
+                        break;
+                    }
+                    clock.delay(POLL_INTERVAL).await;
+                }
+            }
+            Ok(())
+        };
+        with_timeout(&clock, Some(CONVERGE_TIMEOUT), converge, || {
+            RedisError::from(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "redis_cluster: Timed out waiting for the failover",
+            ))
+        })
+        .await?;
+        let duration = clock.now() - start;
+        trace!("{} is the new master after {:?}", replica, duration);
+
+        self.refresh_slots().await?;
+        Ok(FailoverReport {
+            new_master: replica.to_string(),
+            old_master,
+            duration,
+        })
+    }
+}
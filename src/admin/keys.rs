@@ -0,0 +1,87 @@
+//! Listing and counting the keys of each slot.
+
+use futures::{
+    future,
+    prelude::*,
+    stream::{self, BoxStream},
+};
+use redis::{aio::ConnectionLike, cmd, FromRedisValue, RedisResult, Value};
+
+use crate::{Connection, SLOT_SIZE};
+
+// How many more keys each GETKEYSINSLOT asks for
+const KEYS_PAGE: usize = 100;
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// Returns the keys stored in `slot`, read from the master which serves the slot.
+    ///
+    /// The keys are read page by page as the stream is polled, so a stream which is dropped early
+    /// does not read the whole slot. `CLUSTER GETKEYSINSLOT` has no cursor, so each page asks for
+    /// the keys already returned plus the next 100 and skips the former: reading `n` keys
+    /// transfers about `n * n / 200` keys in total. Keys which are added or removed while the
+    /// stream is read may therefore be returned twice or missed.
+    pub fn keys_in_slot<T>(&mut self, slot: u16) -> BoxStream<'static, RedisResult<T>>
+    where
+        T: FromRedisValue + Send + 'static,
+    {
+        let connection = self.clone();
+        stream::unfold(Some((connection, 0)), move |state| async move {
+            let (mut connection, seen) = state?;
+            let result = connection
+                .query_slot::<Vec<Value>>(
+                    slot,
+                    cmd("CLUSTER")
+                        .arg("GETKEYSINSLOT")
+                        .arg(slot)
+                        .arg(seen + KEYS_PAGE),
+                )
+                .await;
+            match result {
+                Ok(keys) => {
+                    let next = if keys.len() < seen + KEYS_PAGE {
+                        None
+                    } else {
+                        Some((connection, keys.len()))
+                    };
+                    let page = keys
+                        .iter()
+                        .skip(seen)
+                        .map(T::from_redis_value)
+                        .collect::<Vec<_>>();
+                    Some((stream::iter(page), next))
+                }
+                Err(err) => Some((stream::iter(vec![Err(err)]), None)),
+            }
+        })
+        .flatten()
+        .boxed()
+    }
+
+    /// Counts the keys in every slot with `CLUSTER COUNTKEYSINSLOT`. The returned vector has an
+    /// entry for each of the 16384 slots.
+    ///
+    /// The slots of each range of the slot map are counted with one pipeline, and the ranges are
+    /// queried in parallel.
+    pub async fn slot_key_counts(&mut self) -> RedisResult<Vec<u64>> {
+        let slots = self.topology().await?.slots;
+        let ranges = future::try_join_all(slots.ranges().map(|(start, end, _)| {
+            let mut connection = self.clone();
+            let mut pipeline = redis::pipe();
+            for slot in start..=end {
+                pipeline.cmd("CLUSTER").arg("COUNTKEYSINSLOT").arg(slot);
+            }
+            async move { connection.query_slot_pipeline(start, &pipeline).await }
+        }))
+        .await?;
+
+        let mut counts = vec![0; SLOT_SIZE];
+        for ((start, ..), range) in slots.ranges().zip(ranges) {
+            let start = usize::from(start);
+            counts[start..start + range.len()].copy_from_slice(&range);
+        }
+        Ok(counts)
+    }
+}
@@ -0,0 +1,394 @@
+//! Moving slots, and the keys in them, between masters.
+
+use std::collections::BTreeMap;
+
+use futures::{
+    channel::mpsc,
+    future,
+    prelude::*,
+    stream::{self, BoxStream},
+};
+use log::trace;
+use redis::{
+    aio::ConnectionLike, cmd, ConnectionAddr, IntoConnectionInfo, RedisError, RedisResult, Value,
+};
+
+use super::ClusterNode;
+use crate::{Connection, SLOT_SIZE};
+
+// How many keys are moved with each MIGRATE
+const MIGRATE_BATCH: usize = 100;
+const MIGRATE_TIMEOUT_MS: u64 = 5000;
+// MIGRATE is retried this many times when it times out
+const MIGRATE_ATTEMPTS: u32 = 3;
+
+/// Progress reported while slots are migrated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrationProgress {
+    /// The slot was marked as importing on the target and as migrating on the source.
+    Started { slot: u16, from: String, to: String },
+    /// A batch of `count` keys was moved. `total` counts every key moved so far for the slot.
+    KeysMoved {
+        slot: u16,
+        count: usize,
+        total: usize,
+    },
+    /// The slot is now served by the target.
+    Finished { slot: u16, keys: usize },
+}
+
+/// Moves the (inclusive) range of `slots` from one master to another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotMove {
+    pub slots: (u16, u16),
+    pub from: String,
+    pub to: String,
+}
+
+/// A list of slot moves to run with `Connection::rebalance`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RebalancePlan {
+    moves: Vec<SlotMove>,
+}
+
+impl RebalancePlan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a move of the (inclusive) range of `slots` from the master at `from` to the master at
+    /// `to`, both given as `redis://host:port`.
+    pub fn move_slots(
+        &mut self,
+        slots: (u16, u16),
+        from: impl Into<String>,
+        to: impl Into<String>,
+    ) -> &mut Self {
+        self.moves.push(SlotMove {
+            slots,
+            from: from.into(),
+            to: to.into(),
+        });
+        self
+    }
+
+    pub fn moves(&self) -> &[SlotMove] {
+        &self.moves
+    }
+
+    /// Creates a plan which spreads the slots evenly over the healthy masters in `nodes`, as
+    /// returned by `Connection::cluster_nodes`. Masters without any slots are included so this
+    /// can be used to fill up newly added masters.
+    pub fn even(nodes: &[ClusterNode]) -> Self {
+        let mut masters = nodes
+            .iter()
+            .filter(|node| node.is_master() && !node.is_failing())
+            .collect::<Vec<_>>();
+        masters.sort_by_key(|node| node.url());
+
+        let mut plan = RebalancePlan::new();
+        if masters.is_empty() {
+            return plan;
+        }
+
+        let base = SLOT_SIZE / masters.len();
+        let extra = SLOT_SIZE % masters.len();
+        let mut surplus = Vec::new();
+        let mut missing = Vec::new();
+        for (i, master) in masters.iter().enumerate() {
+            let target = base + usize::from(i < extra);
+            let mut slots = master
+                .slots
+                .iter()
+                .flat_map(|&(start, end)| start..=end)
+                .collect::<Vec<_>>();
+            if slots.len() > target {
+                // Give away the highest slots
+                surplus.extend(
+                    slots
+                        .split_off(target)
+                        .into_iter()
+                        .map(|slot| (slot, master.url())),
+                );
+            } else {
+                missing.extend(std::iter::repeat_n(master.url(), target - slots.len()));
+            }
+        }
+
+        // Assign slot by slot and merge consecutive slots with the same source and target
+        let assigned = surplus
+            .into_iter()
+            .zip(missing)
+            .map(|((slot, from), to)| (slot, (from, to)))
+            .collect::<BTreeMap<_, _>>();
+        for (slot, (from, to)) in assigned {
+            match plan.moves.last_mut() {
+                Some(last) if last.slots.1 + 1 == slot && last.from == from && last.to == to => {
+                    last.slots.1 = slot;
+                }
+                _ => {
+                    plan.move_slots((slot, slot), from, to);
+                }
+            }
+        }
+        plan
+    }
+}
+
+type ProgressSender = mpsc::UnboundedSender<RedisResult<MigrationProgress>>;
+
+fn report(progress: &ProgressSender, event: MigrationProgress) {
+    trace!("Migration {:?}", event);
+    // The stream is driven by its receiver so it can't be gone while we are sending
+    let _ = progress.unbounded_send(Ok(event));
+}
+
+// Runs `run` as part of the returned stream, so the migration only progresses while the stream is
+// polled. Progress is sent through a channel followed by the error, if any, which ended the run.
+fn progress_stream<'a, F>(
+    run: impl FnOnce(ProgressSender) -> F,
+) -> BoxStream<'a, RedisResult<MigrationProgress>>
+where
+    F: Future<Output = RedisResult<()>> + Send + 'a,
+{
+    let (sender, receiver) = mpsc::unbounded();
+    let errors = sender.clone();
+    let run = run(sender).map(move |result| {
+        if let Err(err) = result {
+            let _ = errors.unbounded_send(Err(err));
+        }
+    });
+    stream::select(
+        receiver,
+        run.into_stream().filter_map(|()| future::ready(None)),
+    )
+    .boxed()
+}
+
+fn host_and_port(addr: &str) -> RedisResult<(String, u16)> {
+    match *addr.into_connection_info()?.addr {
+        ConnectionAddr::Tcp(ref host, port) => Ok((host.clone(), port)),
+        _ => Err(RedisError::from((
+            redis::ErrorKind::InvalidClientConfig,
+            "Slots can only be migrated between TCP nodes",
+        ))),
+    }
+}
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// Moves `slot` and every key in it from the master at `from` to the master at `to`, both
+    /// given as `redis://host:port`.
+    ///
+    /// The migration runs while the returned stream is polled and reports its progress through
+    /// it. Requests for keys in the slot keep working during the migration by following the ASK
+    /// redirects of the source node.
+    ///
+    /// If marking the slot fails, the slot is reset on both nodes. If moving the keys fails, the
+    /// slot is left migrating so that the migration can be resumed by calling `migrate_slot`
+    /// again.
+    pub fn migrate_slot<'a>(
+        &'a mut self,
+        slot: u16,
+        from: &'a str,
+        to: &'a str,
+    ) -> BoxStream<'a, RedisResult<MigrationProgress>> {
+        progress_stream(move |progress| async move {
+            self.run_migration(slot, from, to, &progress).await
+        })
+    }
+
+    /// Runs every move in `plan`, one slot at a time, stopping at the first error.
+    pub fn rebalance<'a>(
+        &'a mut self,
+        plan: &'a RebalancePlan,
+    ) -> BoxStream<'a, RedisResult<MigrationProgress>> {
+        progress_stream(move |progress| async move {
+            for SlotMove { slots, from, to } in &plan.moves {
+                for slot in slots.0..=slots.1 {
+                    self.run_migration(slot, from, to, &progress).await?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    async fn run_migration(
+        &mut self,
+        slot: u16,
+        from: &str,
+        to: &str,
+        progress: &ProgressSender,
+    ) -> RedisResult<()> {
+        let (host, port) = host_and_port(to)?;
+        let from_id: String = self.query_node(from, cmd("CLUSTER").arg("MYID")).await?;
+        let to_id: String = self.query_node(to, cmd("CLUSTER").arg("MYID")).await?;
+
+        // Mark the target first, so it accepts the ASK redirects from the source
+        let marked = async {
+            let () = self
+                .query_node(
+                    to,
+                    cmd("CLUSTER")
+                        .arg("SETSLOT")
+                        .arg(slot)
+                        .arg("IMPORTING")
+                        .arg(&from_id),
+                )
+                .await?;
+            self.query_node::<()>(
+                from,
+                cmd("CLUSTER")
+                    .arg("SETSLOT")
+                    .arg(slot)
+                    .arg("MIGRATING")
+                    .arg(&to_id),
+            )
+            .await
+        }
+        .await;
+        if let Err(err) = marked {
+            for node in &[from, to] {
+                let _ = self
+                    .query_node::<()>(node, cmd("CLUSTER").arg("SETSLOT").arg(slot).arg("STABLE"))
+                    .await;
+            }
+            return Err(err);
+        }
+        report(
+            progress,
+            MigrationProgress::Started {
+                slot,
+                from: from.to_string(),
+                to: to.to_string(),
+            },
+        );
+
+        let mut total = 0;
+        loop {
+            let keys: Vec<Vec<u8>> = self
+                .query_node(
+                    from,
+                    cmd("CLUSTER")
+                        .arg("GETKEYSINSLOT")
+                        .arg(slot)
+                        .arg(MIGRATE_BATCH),
+                )
+                .await?;
+            if keys.is_empty() {
+                break;
+            }
+
+            let mut migrate = cmd("MIGRATE");
+            migrate
+                .arg(&host)
+                .arg(port)
+                .arg("")
+                .arg(0)
+                .arg(MIGRATE_TIMEOUT_MS)
+                // A key may already be on the target if an earlier attempt timed out after copying
+                // it, or if this migration resumes one which was interrupted
+                .arg("REPLACE")
+                .arg("KEYS")
+                .arg(&keys[..]);
+            let mut attempt = 1;
+            loop {
+                match self.query_node::<Value>(from, &migrate).await {
+                    Err(ref err) if err.code() == Some("IOERR") && attempt < MIGRATE_ATTEMPTS => {
+                        trace!("MIGRATE of slot {} failed, retrying: {}", slot, err);
+                        attempt += 1;
+                    }
+                    result => {
+                        result?;
+                        break;
+                    }
+                }
+            }
+
+            total += keys.len();
+            report(
+                progress,
+                MigrationProgress::KeysMoved {
+                    slot,
+                    count: keys.len(),
+                    total,
+                },
+            );
+        }
+
+        // Assign the target first, so the slot is served even if updating the source fails
+        for node in &[to, from] {
+            let () = self
+                .query_node(
+                    node,
+                    cmd("CLUSTER")
+                        .arg("SETSLOT")
+                        .arg(slot)
+                        .arg("NODE")
+                        .arg(&to_id),
+                )
+                .await?;
+        }
+        report(progress, MigrationProgress::Finished { slot, keys: total });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn master(port: u16, slots: Vec<(u16, u16)>) -> ClusterNode {
+        format!(
+            "id{} 127.0.0.1:{}@1{} master - 0 0 1 connected",
+            port, port, port
+        )
+        .parse::<ClusterNode>()
+        .map(|node| ClusterNode { slots, ..node })
+        .unwrap()
+    }
+
+    #[test]
+    fn even_plan() {
+        let nodes = vec![
+            master(7000, vec![(0, 8191)]),
+            master(7001, vec![(8192, 16383)]),
+            master(7002, vec![]),
+        ];
+        let plan = RebalancePlan::even(&nodes);
+        assert_eq!(
+            plan.moves(),
+            &[
+                SlotMove {
+                    slots: (5462, 8191),
+                    from: "redis://127.0.0.1:7000".into(),
+                    to: "redis://127.0.0.1:7002".into(),
+                },
+                SlotMove {
+                    slots: (13653, 16383),
+                    from: "redis://127.0.0.1:7001".into(),
+                    to: "redis://127.0.0.1:7002".into(),
+                },
+            ][..]
+        );
+
+        let moved: usize = plan
+            .moves()
+            .iter()
+            .map(|m| usize::from(m.slots.1 - m.slots.0) + 1)
+            .sum();
+        assert_eq!(moved, 5461);
+    }
+
+    #[test]
+    fn even_plan_is_empty_when_balanced() {
+        let nodes = vec![
+            master(7000, vec![(0, 5461)]),
+            master(7001, vec![(5462, 10922)]),
+            master(7002, vec![(10923, 16383)]),
+        ];
+        assert_eq!(RebalancePlan::even(&nodes), RebalancePlan::new());
+    }
+}
@@ -0,0 +1,157 @@
+//! Limits on the requests which a connection holds at once.
+//!
+//! Requests first wait in the queue of the task which drives the connection. Once picked up they
+//! count towards `Client::set_max_in_flight` until they are answered, and each attempt counts
+//! towards `Client::set_max_in_flight_per_node` for the node it is sent to until the node answers
+//! it. An attempt which finds its node at the limit waits in the queue of the node, and counts
+//! towards the limit as well, until the node has room. When a limit is reached or the queue of a
+//! node holds `Client::set_max_queued_per_node` requests, the next request either waits for room
+//! or fails at once, as chosen with `Client::set_overload_policy`.
+//!
+//! A request which waits for room on its node keeps its place among the requests to its slot.
+
+use std::{collections::VecDeque, error::Error, fmt, io};
+
+use redis::{aio::ConnectionLike, RedisError};
+
+use crate::{Connect, Pipeline, Redirected, Request, RequestInfo};
+
+/// What happens to a request which finds a limit reached. See `Client::set_overload_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverloadPolicy {
+    /// Wait until there is room for the request.
+    #[default]
+    Wait,
+    /// Fail the request with an error for which `is_overloaded` returns true.
+    Fail,
+}
+
+/// Returns true if `err` is the error of a request which was turned away because a limit was
+/// reached and the overload policy is `OverloadPolicy::Fail`. Retrying later may succeed.
+pub fn is_overloaded(err: &RedisError) -> bool {
+    // As with `ClusterError::from_redis_error` the `io::Error` only forwards to the source of the
+    // error it holds
+    #[allow(deprecated)]
+    let source = err.cause().and_then(Error::source);
+    matches!(source, Some(source) if source.is::<Overloaded>())
+}
+
+pub(crate) fn overloaded(limit: &'static str) -> RedisError {
+    io::Error::new(io::ErrorKind::WouldBlock, Wrapper(Overloaded(limit))).into()
+}
+
+#[derive(Debug)]
+struct Overloaded(&'static str);
+
+impl fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "redis_cluster: Overloaded, {} was reached", self.0)
+    }
+}
+
+impl Error for Overloaded {}
+
+#[derive(Debug)]
+struct Wrapper(Overloaded);
+
+impl fmt::Display for Wrapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for Wrapper {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl<C> Pipeline<C>
+where
+    C: ConnectionLike + Connect + Clone + Send + 'static,
+{
+    // The node which the next attempt of `info` goes to, if the requests in flight to it and those
+    // waiting for it reached its limit. An attempt which is sent to a random node only waits for
+    // the first of them if they all have.
+    pub(crate) fn saturated_node(&self, info: &RequestInfo<C>) -> Option<String> {
+        let limit = self.params.max_in_flight_per_node?;
+        let addr = match (&info.node, &info.redirect) {
+            (Some(addr), _)
+            | (None, Some(Redirected::Moved(addr)))
+            | (None, Some(Redirected::Ask(addr))) => addr,
+            (None, None) => match info.slot {
+                Some(slot) if info.excludes.is_empty() => &self.slots.get(slot)?.master,
+                _ => self.random_candidates(Some(&info.excludes)).first()?,
+            },
+        };
+        let pool = self.connections.get(addr)?;
+        let queued = self.node_queues.get(addr).map_or(0, VecDeque::len);
+        if pool.in_flight() + queued >= limit.get() {
+            Some(addr.clone())
+        } else {
+            None
+        }
+    }
+
+    // Called with a request whose node has reached its limit. With `OverloadPolicy::Wait` the
+    // queue of the node may grow past its depth through retries and redirects, since only new
+    // requests are held back by `poll_ready`.
+    pub(crate) fn node_overloaded(&mut self, addr: String, mut request: Request<C>) {
+        let queued = self.node_queues.get(&addr).map_or(0, VecDeque::len);
+        match self.params.overload {
+            OverloadPolicy::Fail if queued >= self.params.max_queued_per_node => {
+                request.respond(Err(overloaded("the limit of requests to a node")));
+                self.finished_attempt(&request);
+                self.answered(&request);
+            }
+            _ => self.node_queues.entry(addr).or_default().push_back(request),
+        }
+    }
+
+    // Whether `poll_ready` holds back new requests with `OverloadPolicy::Wait`
+    pub(crate) fn is_full(&self) -> bool {
+        let depth = self.params.max_queued_per_node;
+        let in_flight = match self.params.max_in_flight {
+            Some(limit) => self.unanswered >= limit.get(),
+            None => false,
+        };
+        in_flight || self.node_queues.values().any(|queue| queue.len() >= depth)
+    }
+
+    // Starts the requests which wait for `addr`, as long as it stays below its limit
+    pub(crate) fn release_node(&mut self, addr: &str) {
+        let limit = match self.params.max_in_flight_per_node {
+            Some(limit) => limit.get(),
+            None => return,
+        };
+        loop {
+            let in_flight = match self.connections.get(addr) {
+                Some(pool) => pool.in_flight(),
+                None => 0,
+            };
+            if in_flight >= limit {
+                return;
+            }
+            // Sent without checking the limit again, which counts the requests still waiting
+            match self.node_queues.get_mut(addr).and_then(VecDeque::pop_front) {
+                Some(request) => self.send_attempt(request),
+                None => {
+                    self.node_queues.remove(addr);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Starts the requests which wait for a node again, since a new slot map may send them
+    // elsewhere or their node may be gone
+    pub(crate) fn release_nodes(&mut self) {
+        let mut addrs = self.node_queues.keys().cloned().collect::<Vec<_>>();
+        addrs.sort();
+        for addr in addrs {
+            for request in self.node_queues.remove(&addr).unwrap_or_default() {
+                self.start_request(request);
+            }
+        }
+    }
+}
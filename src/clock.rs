@@ -0,0 +1,65 @@
+//! The source of time of the cluster connection.
+//!
+//! The driver waits on a `Clock` for the backoff of `TRYAGAIN` and `CLUSTERDOWN` and for the
+//! timeout of requests, and reads it to measure latencies. Registering a clock with
+//! `Client::set_clock` lets tests control time instead of sleeping, for instance with
+//! `testing::VirtualClock`.
+
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::future::{BoxFuture, FutureExt};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Returns a future which finishes once `duration` has passed.
+    fn delay(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The clock of the tokio runtime. Used unless another clock is registered.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn delay(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::delay_for(duration).boxed()
+    }
+}
+
+// The clock registered on a `Client`
+#[derive(Clone)]
+pub(crate) struct Time(Arc<dyn Clock>);
+
+impl Default for Time {
+    fn default() -> Self {
+        Time(Arc::new(TokioClock))
+    }
+}
+
+impl fmt::Debug for Time {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Time")
+    }
+}
+
+impl Time {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Time(clock)
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        self.0.now()
+    }
+
+    pub(crate) fn delay(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.0.delay(duration)
+    }
+}
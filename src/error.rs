@@ -0,0 +1,390 @@
+//! The error returned when a request could not be served despite retries and redirects.
+
+use std::{
+    error::Error,
+    fmt, io,
+    sync::{Arc, Mutex},
+};
+
+use redis::{parse_redis_value, ErrorKind, RedisError};
+
+/// A failed attempt at serving a request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attempt {
+    /// The node which the request was sent to, as `redis://host:port`.
+    pub node: String,
+    /// The kind of the error the node answered with, or `ErrorKind::IoError` if it could not be
+    /// reached.
+    pub kind: ErrorKind,
+    /// The error code, for instance `MOVED`, `ASK` or `TRYAGAIN`.
+    pub code: Option<String>,
+    /// The error as displayed, including its detail.
+    pub message: String,
+}
+
+impl Attempt {
+    fn new(node: &str, err: &RedisError) -> Self {
+        Attempt {
+            node: node.to_string(),
+            kind: err.kind(),
+            code: err.code().map(String::from),
+            message: err.to_string(),
+        }
+    }
+}
+
+/// Describes every attempt of a request which failed after being retried or redirected, or
+/// which timed out.
+///
+/// A request which times out returns an `ErrorKind::IoError` which carries the `ClusterError`. A
+/// request which fails after being retried or redirected keeps the kind and code of its last
+/// error, so only an IO error can carry the `ClusterError` itself, and the other kinds list the
+/// attempts in the detail of the error instead. `ClusterError::from_redis_error` returns the
+/// attempts in either case. Requests which fail on their first and only attempt return the error
+/// of the node as is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterError {
+    attempts: Vec<Attempt>,
+    timed_out: bool,
+}
+
+impl ClusterError {
+    /// Returns the `ClusterError` which `err` carries or lists in its detail, if any.
+    pub fn from_redis_error(err: &RedisError) -> Option<ClusterError> {
+        // `RedisError` only exposes its `io::Error` as a borrowed cause. The `io::Error` in turn
+        // forwards to the source of its inner error, which is why the `ClusterError` is wrapped.
+        #[allow(deprecated)]
+        let carried = err
+            .cause()
+            .and_then(Error::source)
+            .and_then(|source| source.downcast_ref::<ClusterError>());
+        match carried {
+            Some(carried) => Some(carried.clone()),
+            None => Some(ClusterError {
+                attempts: parse_summary(err.detail()?)?,
+                timed_out: false,
+            }),
+        }
+    }
+
+    /// Every failed attempt, in the order they were made.
+    pub fn attempts(&self) -> &[Attempt] {
+        &self.attempts
+    }
+
+    /// The node of the last failed attempt.
+    pub fn node(&self) -> Option<&str> {
+        self.attempts.last().map(|attempt| &attempt.node[..])
+    }
+
+    /// The error of the last failed attempt.
+    pub fn last_error(&self) -> Option<&Attempt> {
+        self.attempts.last()
+    }
+
+    /// Returns true if the request did not finish within the timeout set with
+    /// `Client::set_timeout`.
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+}
+
+impl fmt::Display for ClusterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.timed_out {
+            write!(f, "redis_cluster: Request timed out")?;
+        } else {
+            write!(f, "redis_cluster: Request failed")?;
+        }
+        write!(f, " after {} failed attempts", self.attempts.len())?;
+        if let Some(last) = self.attempts.last() {
+            write!(f, ", last on {}: {}", last.node, last.message)?;
+        }
+        Ok(())
+    }
+}
+
+// Where `Summary` starts in the detail of an error
+const SUMMARY: &str = " (redis_cluster: ";
+
+// Every kind, to read them back by name
+const KINDS: [ErrorKind; 16] = [
+    ErrorKind::ResponseError,
+    ErrorKind::AuthenticationFailed,
+    ErrorKind::TypeError,
+    ErrorKind::ExecAbortError,
+    ErrorKind::BusyLoadingError,
+    ErrorKind::NoScriptError,
+    ErrorKind::InvalidClientConfig,
+    ErrorKind::Moved,
+    ErrorKind::Ask,
+    ErrorKind::TryAgain,
+    ErrorKind::ClusterDown,
+    ErrorKind::CrossSlot,
+    ErrorKind::MasterDown,
+    ErrorKind::IoError,
+    ErrorKind::ClientError,
+    ErrorKind::ExtensionError,
+];
+
+// Lists every attempt for the detail of the last error, as its node, its error code or else the
+// name of its kind, and its message quoted. `parse_summary` reads it back.
+struct Summary<'a>(&'a [Attempt]);
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "redis_cluster: {} failed attempts", self.0.len())?;
+        for (i, attempt) in self.0.iter().enumerate() {
+            let separator = if i == 0 { ": " } else { ", " };
+            write!(f, "{}{} ", separator, attempt.node)?;
+            match &attempt.code {
+                Some(code) => write!(f, "{}", code)?,
+                None => write!(f, "{:?}", attempt.kind)?,
+            }
+            // Escaped so the detail stays on one line and the message can be found again
+            let message = attempt
+                .message
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\r', "\\r")
+                .replace('\n', "\\n");
+            write!(f, " \"{}\"", message)?;
+        }
+        Ok(())
+    }
+}
+
+// The attempts listed by `Summary` at the end of `detail`. The detail of the last error and the
+// messages may contain `SUMMARY` too, so every occurrence is tried.
+fn parse_summary(detail: &str) -> Option<Vec<Attempt>> {
+    detail
+        .match_indices(SUMMARY)
+        .find_map(|(start, _)| parse_attempts(&detail[start + SUMMARY.len()..]))
+}
+
+fn parse_attempts(summary: &str) -> Option<Vec<Attempt>> {
+    let summary = summary.strip_suffix(')')?;
+    let (count, mut rest) = summary.split_once(" failed attempts: ")?;
+    let count = count.parse().ok()?;
+    let mut attempts = Vec::with_capacity(count);
+    for i in 0..count {
+        if i > 0 {
+            rest = rest.strip_prefix(", ")?;
+        }
+        let (node, after) = rest.split_once(' ')?;
+        let (name, after) = after.split_once(" \"")?;
+        let mut message = String::new();
+        let mut chars = after.char_indices();
+        rest = loop {
+            match chars.next()? {
+                (end, '"') => break &after[end + 1..],
+                (_, '\\') => message.push(match chars.next()?.1 {
+                    'r' => '\r',
+                    'n' => '\n',
+                    c => c,
+                }),
+                (_, c) => message.push(c),
+            }
+        };
+        let (kind, code) = match KINDS.iter().find(|kind| format!("{:?}", kind) == name) {
+            Some(kind) => (*kind, None),
+            None => {
+                let err = parse_redis_value(format!("-{} \r\n", name).as_bytes()).err()?;
+                (err.kind(), Some(name.to_string()))
+            }
+        };
+        attempts.push(Attempt {
+            node: node.to_string(),
+            kind,
+            code,
+            message,
+        });
+    }
+    if rest.is_empty() {
+        Some(attempts)
+    } else {
+        None
+    }
+}
+
+impl Error for ClusterError {}
+
+// The error stored in the `io::Error`. See `ClusterError::from_redis_error`.
+#[derive(Debug)]
+struct Wrapper(ClusterError);
+
+impl fmt::Display for Wrapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for Wrapper {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// The failed attempts of a request. Shared between the driver, which records the attempts, and
+/// the caller, which needs them if the request times out.
+#[derive(Clone, Default)]
+pub(crate) struct History(Arc<Mutex<Vec<Attempt>>>);
+
+impl History {
+    pub(crate) fn record(&self, node: &str, err: &RedisError) {
+        self.0.lock().unwrap().push(Attempt::new(node, err));
+    }
+
+    /// The error for a request which failed with `err` on its last attempt. It keeps the kind
+    /// and code of `err`.
+    pub(crate) fn failed(&self, err: RedisError) -> RedisError {
+        let attempts = self.0.lock().unwrap().clone();
+        if attempts.len() <= 1 {
+            return err;
+        }
+        if err.is_io_error() {
+            // `RedisError` does not hand out its `io::Error`, so the kind is recovered from the
+            // checks it offers
+            let kind = if err.is_timeout() {
+                io::ErrorKind::TimedOut
+            } else if err.is_connection_refusal() {
+                io::ErrorKind::ConnectionRefused
+            } else if err.is_connection_dropped() {
+                io::ErrorKind::ConnectionReset
+            } else {
+                io::ErrorKind::Other
+            };
+            return into_redis_error(
+                kind,
+                ClusterError {
+                    attempts,
+                    timed_out: false,
+                },
+            );
+        }
+
+        let summary = Summary(&attempts);
+        match err.code() {
+            // Errors of the server are parsed again with the attempts added to their detail.
+            // This is the only way to build an `ErrorKind::ExtensionError` with its code.
+            Some(code) => {
+                let detail = format!("{} ({})", err.detail().unwrap_or_default(), summary)
+                    .replace(&['\r', '\n'][..], " ");
+                parse_redis_value(format!("-{} {}\r\n", code, detail).as_bytes())
+                    .err()
+                    .unwrap_or(err)
+            }
+            None => RedisError::from((
+                err.kind(),
+                "redis_cluster: Request failed",
+                format!("{} ({})", err, summary),
+            )),
+        }
+    }
+
+    pub(crate) fn timed_out(&self) -> RedisError {
+        let attempts = self.0.lock().unwrap().clone();
+        into_redis_error(
+            io::ErrorKind::TimedOut,
+            ClusterError {
+                attempts,
+                timed_out: true,
+            },
+        )
+    }
+}
+
+fn into_redis_error(kind: io::ErrorKind, err: ClusterError) -> RedisError {
+    io::Error::new(kind, Wrapper(err)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_redis_error() {
+        let history = History::default();
+        let moved = RedisError::from((ErrorKind::Moved, "moved", "1 b:6379".to_string()));
+        history.record("redis://a:6379", &moved);
+        let try_again = RedisError::from((ErrorKind::TryAgain, "try again"));
+        history.record("redis://b:6379", &try_again);
+
+        let err = history.timed_out();
+        assert_eq!(err.kind(), ErrorKind::IoError);
+        let cluster_error = ClusterError::from_redis_error(&err).unwrap();
+        assert!(cluster_error.timed_out());
+        assert_eq!(cluster_error.node(), Some("redis://b:6379"));
+        let codes = cluster_error
+            .attempts()
+            .iter()
+            .map(|attempt| attempt.code.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(codes, vec![Some("MOVED"), Some("TRYAGAIN")]);
+
+        let io_error = RedisError::from(io::Error::new(io::ErrorKind::ConnectionRefused, "io"));
+        let err = history.failed(io_error);
+        assert!(err.is_connection_refusal());
+        assert!(!ClusterError::from_redis_error(&err).unwrap().timed_out());
+
+        let single = RedisError::from((ErrorKind::TypeError, "type"));
+        assert!(ClusterError::from_redis_error(&single).is_none());
+    }
+
+    #[test]
+    fn failed_keeps_kind_and_code() {
+        let history = History::default();
+        let moved = RedisError::from((ErrorKind::Moved, "moved", "1 b:6379".to_string()));
+        history.record("redis://a:6379", &moved);
+        let try_again = parse_redis_value(b"-TRYAGAIN busy\r\n").unwrap_err();
+        history.record("redis://b:6379", &try_again);
+
+        let err = history.failed(try_again);
+        assert_eq!(err.kind(), ErrorKind::TryAgain);
+        assert_eq!(err.code(), Some("TRYAGAIN"));
+        assert_eq!(
+            err.detail(),
+            Some(
+                "busy (redis_cluster: 2 failed attempts: \
+                 redis://a:6379 MOVED \"moved: 1 b:6379\", \
+                 redis://b:6379 TRYAGAIN \"An error was signalled by the server: busy\")"
+            )
+        );
+        let cluster_error = ClusterError::from_redis_error(&err).unwrap();
+        assert!(!cluster_error.timed_out());
+        assert_eq!(cluster_error.attempts(), &history.0.lock().unwrap()[..]);
+
+        let extension = parse_redis_value(b"-CUSTOM nope\r\n").unwrap_err();
+        let err = history.failed(extension);
+        assert_eq!(err.kind(), ErrorKind::ExtensionError);
+        assert_eq!(err.code(), Some("CUSTOM"));
+
+        let type_error = RedisError::from((ErrorKind::TypeError, "type"));
+        let err = history.failed(type_error);
+        assert_eq!(err.kind(), ErrorKind::TypeError);
+        assert!(err
+            .to_string()
+            .starts_with("redis_cluster: Request failed: type ("));
+    }
+
+    #[test]
+    fn attempts_from_detail() {
+        let history = History::default();
+        let errors = vec![
+            parse_redis_value(b"-ERR wrong\r\n").unwrap_err(),
+            parse_redis_value(b"-CUSTOM \"quoted\" \\ (redis_cluster: 1)\r\n").unwrap_err(),
+            RedisError::from((ErrorKind::TypeError, "type", "a\r\nb".to_string())),
+        ];
+        for err in &errors {
+            history.record("redis://a:6379", err);
+        }
+        let attempts = history.0.lock().unwrap().clone();
+        assert_eq!(attempts[0].code.as_deref(), Some("ERR"));
+
+        for err in errors {
+            let err = history.failed(err);
+            let cluster_error = ClusterError::from_redis_error(&err).unwrap();
+            assert_eq!(cluster_error.attempts(), &attempts[..], "{}", err);
+        }
+    }
+}
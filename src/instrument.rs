@@ -0,0 +1,133 @@
+//! The spans emitted with the `tracing` feature.
+//!
+//! Without the feature `Span` is an empty struct and every function does nothing, so the rest of
+//! the crate can create and pass spans around without `cfg` attributes.
+
+use futures::prelude::*;
+use redis::RedisResult;
+
+use crate::{CmdArg, Route};
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Span;
+
+/// The span of a request, from the moment it is sent to the driver until it is answered.
+#[cfg(feature = "tracing")]
+pub(crate) fn request<C>(cmd: &CmdArg<C>, route: Option<&Route>) -> Span {
+    let (slot, node) = match route {
+        Some(Route::Slot(slot)) => (Some(*slot), None),
+        Some(Route::Node(node)) => (None, Some(&node[..])),
+        Some(Route::Random) => (None, None),
+        None => (cmd.slot(), None),
+    };
+    tracing::debug_span!(
+        "redis_cluster.request",
+        command = cmd.name(),
+        slot,
+        node,
+        attempts = tracing::field::Empty,
+    )
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn request<C>(_cmd: &CmdArg<C>, _route: Option<&Route>) -> Span {
+    Span
+}
+
+/// The span of one attempt at sending a request to a node. `redirect` is the kind of redirect
+/// which caused the attempt, if any.
+#[cfg(feature = "tracing")]
+pub(crate) fn attempt(request: &Span, retry: u32, redirect: Option<&'static str>) -> Span {
+    request.record("attempts", retry + 1);
+    tracing::debug_span!(
+        parent: request,
+        "redis_cluster.attempt",
+        retry,
+        redirect,
+        node = tracing::field::Empty,
+        error = tracing::field::Empty,
+    )
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn attempt(_request: &Span, _retry: u32, _redirect: Option<&'static str>) -> Span {
+    Span
+}
+
+/// Records the node which an attempt was sent to.
+#[cfg(feature = "tracing")]
+pub(crate) fn record_node(attempt: &Span, node: &str) {
+    attempt.record("node", node);
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn record_node(_attempt: &Span, _node: &str) {}
+
+/// Records the error of a failed attempt or refresh.
+#[cfg(feature = "tracing")]
+pub(crate) fn record_result<T>(span: &Span, result: &RedisResult<T>) {
+    if let Err(err) = result {
+        span.record("error", tracing::field::display(err));
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn record_result<T>(_span: &Span, _result: &RedisResult<T>) {}
+
+/// The span of the recovery of the driver, from the moment it starts refreshing the slot map
+/// until a refresh succeeds. `reason` tells what triggered it.
+#[cfg(feature = "tracing")]
+pub(crate) fn recover(reason: &'static str) -> Span {
+    tracing::debug_span!(
+        parent: None,
+        "redis_cluster.recover",
+        reason,
+        failures = tracing::field::Empty,
+    )
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn recover(_reason: &'static str) -> Span {
+    Span
+}
+
+/// Records how many refreshes failed so far during a recovery.
+#[cfg(feature = "tracing")]
+pub(crate) fn record_failures(recovery: &Span, failures: u32) {
+    recovery.record("failures", failures);
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn record_failures(_recovery: &Span, _failures: u32) {}
+
+/// The span of a slot map refresh, a child of `recovery` if it is part of one. `reason` tells
+/// what triggered it.
+#[cfg(feature = "tracing")]
+pub(crate) fn refresh_slots(reason: &'static str, recovery: Option<&Span>) -> Span {
+    tracing::debug_span!(
+        parent: recovery.and_then(Span::id),
+        "redis_cluster.refresh_slots",
+        reason,
+        error = tracing::field::Empty,
+    )
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn refresh_slots(_reason: &'static str, _recovery: Option<&Span>) -> Span {
+    Span
+}
+
+/// Runs `future` inside `span`.
+#[cfg(feature = "tracing")]
+pub(crate) fn instrument<F: Future>(future: F, span: Span) -> impl Future<Output = F::Output> {
+    tracing::Instrument::instrument(future, span)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn instrument<F: Future>(future: F, _span: Span) -> impl Future<Output = F::Output> {
+    future
+}
@@ -0,0 +1,161 @@
+//! Hash slots of keys and building keys which share a slot.
+
+use std::fmt;
+
+use crc16::{State, XMODEM};
+use redis::{aio::ConnectionLike, ErrorKind, RedisError, RedisResult};
+
+use crate::{Connection, SLOT_SIZE};
+
+/// Returns the hash slot of `key`, taking hash tags into account.
+///
+/// ```rust
+/// use redis_cluster_async::key_slot;
+///
+/// assert_eq!(key_slot(b"foo"), 12182);
+/// assert_eq!(key_slot(b"{user:42}:profile"), key_slot(b"{user:42}:sessions"));
+/// ```
+pub fn key_slot(key: &[u8]) -> u16 {
+    let key = sub_key(key);
+    State::<XMODEM>::calculate(key) % SLOT_SIZE as u16
+}
+
+// If a key contains `{` and `}`, everything between the first occurence is the only thing that
+// determines the hash slot
+pub(crate) fn sub_key(key: &[u8]) -> &[u8] {
+    key.iter()
+        .position(|b| *b == b'{')
+        .and_then(|open| {
+            let after_open = open + 1;
+            key[after_open..]
+                .iter()
+                .position(|b| *b == b'}')
+                .and_then(|close_offset| {
+                    if close_offset != 0 {
+                        Some(&key[after_open..after_open + close_offset])
+                    } else {
+                        None
+                    }
+                })
+        })
+        .unwrap_or(key)
+}
+
+/// Builds keys which share a hash tag and are therefore stored in the same slot, such as
+/// `{user:42}:profile` and `{user:42}:sessions`.
+///
+/// ```rust
+/// use redis_cluster_async::{key_slot, HashTagKey};
+///
+/// let user = HashTagKey::new("user:42");
+/// assert_eq!(user.key("profile"), "{user:42}:profile");
+/// assert_eq!(key_slot(user.key("profile").as_bytes()), user.slot());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HashTagKey {
+    tag: String,
+}
+
+impl HashTagKey {
+    /// Panics if `tag` is empty or contains `}`, since the slot of the keys would then not be
+    /// determined by `tag` alone.
+    pub fn new(tag: impl Into<String>) -> Self {
+        let tag = tag.into();
+        assert!(
+            !tag.is_empty() && !tag.contains('}'),
+            "Invalid hash tag `{}`",
+            tag
+        );
+        HashTagKey { tag }
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Returns `{tag}:suffix`.
+    pub fn key(&self, suffix: impl fmt::Display) -> String {
+        format!("{}:{}", self, suffix)
+    }
+
+    /// The slot of every key built from this tag.
+    pub fn slot(&self) -> u16 {
+        key_slot(self.tag.as_bytes())
+    }
+}
+
+/// Displays the bare hash tag, `{tag}`.
+impl fmt::Display for HashTagKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{{}}}", self.tag)
+    }
+}
+
+/// Panics if `keys` are not all in the same slot. Does nothing in release builds.
+///
+/// Commands and pipelines with keys in several slots are rejected by the cluster with
+/// `CROSSSLOT`, or sent to a random node. This catches them where the keys are built.
+pub fn debug_assert_same_slot<I>(keys: I)
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    if cfg!(debug_assertions) {
+        let mut slots = keys.into_iter().map(|key| {
+            let key = key.as_ref();
+            (key_slot(key), String::from_utf8_lossy(key).into_owned())
+        });
+        if let Some((slot, first)) = slots.next() {
+            for (other_slot, other) in slots {
+                assert_eq!(
+                    slot, other_slot,
+                    "`{}` is in slot {} but `{}` is in slot {}",
+                    first, slot, other, other_slot
+                );
+            }
+        }
+    }
+}
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// Returns the address of the master which currently serves the slot of `key`.
+    pub async fn node_for_key(&mut self, key: &[u8]) -> RedisResult<String> {
+        let slot = key_slot(key);
+        self.slot_addrs(slot)
+            .await?
+            .map(|addrs| addrs.master)
+            .ok_or_else(|| {
+                RedisError::from((
+                    ErrorKind::ClusterDown,
+                    "redis_cluster: No node serves the slot",
+                    slot.to_string(),
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_tags() {
+        assert_eq!(sub_key(b"{user:42}:profile"), b"user:42");
+        assert_eq!(sub_key(b"{}:profile"), b"{}:profile");
+        assert_eq!(sub_key(b"a{b}{c}"), b"b");
+
+        let user = HashTagKey::new("user:42");
+        assert_eq!(user.to_string(), "{user:42}");
+        debug_assert_same_slot(&[user.key("profile"), user.key(7), user.to_string()]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "is in slot")]
+    fn different_slots() {
+        debug_assert_same_slot(&["{a}1", "{b}1"]);
+    }
+}
@@ -5,31 +5,43 @@
 //! So you can use redis-rs's access methods.
 //! If you want more information, read document of redis-rs.
 //!
-//! Note that this library is currently not have features of Pubsub.
+//! Note that this library is currently not have features of Pubsub, apart from
+//! `Client::subscribe_keyspace_events` which subscribes to a pattern on every master.
+//!
+//! Commands sent on a connection (and its clones) to the same slot reach the cluster in the order
+//! they were sent, including when they are redirected, retried or wait for the slot map to be
+//! refreshed. This holds as long as each node is served by one connection, see
+//! `Client::set_pool_size`.
+//!
+//! With the `tracing` feature every request gets a `redis_cluster.request` span with a
+//! `redis_cluster.attempt` child span for each node it was sent to, including the redirect which
+//! led there. Slot map refreshes get a `redis_cluster.refresh_slots` span. When the driver
+//! recovers from a redirect or an error, its refreshes are children of a `redis_cluster.recover`
+//! span which lasts until one of them succeeds.
+//!
+//! The `testing` feature adds the `testing` module, a mock cluster which runs in the same
+//! process, for testing code which uses this library.
 //!
 //! # Example
-//! ```rust
-//! use redis_cluster_async::{Client, redis::{Commands, cmd}};
+//! ```rust,no_run
+//! use redis_cluster_async::{Client, redis::cmd};
 //!
 //! #[tokio::main]
 //! async fn main() -> redis::RedisResult<()> {
 //! #   let _ = env_logger::try_init();
 //!     let nodes = vec!["redis://127.0.0.1:7000/", "redis://127.0.0.1:7001/", "redis://127.0.0.1:7002/"];
 //!
-//!     let mut runtime = Runtime::new().unwrap();
-//!     runtime.block_on(async {
-//!         let client = Client::open(nodes)?;
-//!         let mut connection = client.get_connection().await?;
-//!         let () = cmd("SET").arg("test").arg("test_data").query_async(&mut connection).await?;
-//!         let res: String = cmd("GET").arg("test").query_async(&mut connection).await?;
-//!         assert_eq!(res, "test_data");
-//!         Ok(())
-//!     }).map_err(|err: redis::RedisError| err).unwrap();
+//!     let client = Client::open(nodes)?;
+//!     let mut connection = client.get_connection().await?;
+//!     let () = cmd("SET").arg("test").arg("test_data").query_async(&mut connection).await?;
+//!     let res: String = cmd("GET").arg("test").query_async(&mut connection).await?;
+//!     assert_eq!(res, "test_data");
+//!     Ok(())
 //! }
 //! ```
 //!
 //! # Pipelining
-//! ```rust
+//! ```rust,no_run
 //! use redis_cluster_async::{Client, redis::pipe};
 //!
 //! #[tokio::main]
@@ -37,59 +49,134 @@
 //! #   let _ = env_logger::try_init();
 //!     let nodes = vec!["redis://127.0.0.1:7000/", "redis://127.0.0.1:7001/", "redis://127.0.0.1:7002/"];
 //!
-//!     let mut runtime = Runtime::new().unwrap();
-//!     runtime.block_on(async move {
-//!         let client = Client::open(nodes)?;
-//!         let mut connection = client.get_connection().await?;
-//!         let key = "test2";
+//!     let client = Client::open(nodes)?;
+//!     let mut connection = client.get_connection().await?;
+//!     let key = "test2";
 //!
-//!         let mut pipe = pipe();
-//!         pipe.rpush(key, "123").ignore()
-//!             .ltrim(key, -10, -1).ignore()
-//!             .expire(key, 60).ignore();
-//!         pipe.query_async(&mut connection)
-//!             .await
-//!             .map(|()| ())
-//!     }).unwrap();
+//!     let mut pipe = pipe();
+//!     pipe.rpush(key, "123").ignore()
+//!         .ltrim(key, -10, -1).ignore()
+//!         .expire(key, 60).ignore();
+//!     pipe.query_async(&mut connection).await
 //! }
 //! ```
 
 pub use redis;
 
+pub use crate::{
+    backpressure::{is_overloaded, OverloadPolicy},
+    error::{Attempt, ClusterError},
+    key::{debug_assert_same_slot, key_slot, HashTagKey},
+    packed::Packed,
+    pool::PoolStrategy,
+    pubsub::{KeyspaceEvents, KeyspaceMessage},
+    topology::{NodesSnapshot, SlotRange, SlotsSnapshot, TopologyEvent},
+};
+
+pub mod admin;
+mod backpressure;
+pub mod clock;
+mod error;
+mod instrument;
+mod key;
+pub mod metrics;
+mod ordering;
+mod packed;
+mod pool;
+mod pubsub;
+mod slot_map;
+pub mod sync;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod topology;
+
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
     fmt, io,
     iter::Iterator,
     marker::Unpin,
     mem,
+    num::NonZeroUsize,
     pin::Pin,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
     error::Error,
 };
 
-use crc16::*;
+use crate::{
+    backpressure::overloaded,
+    clock::{Clock, Time},
+    error::History,
+    instrument::Span,
+    metrics::{ClusterMetrics, Metrics, Redirect},
+    ordering::{Resend, SlotQueue},
+    pool::{Health, Lease, NodePool},
+    slot_map::{SlotAddrs, SlotMap},
+    topology::{Topology, TopologyRequest},
+};
 use futures::{
     channel::{mpsc, oneshot},
     future::{self, BoxFuture},
     prelude::*,
-    ready, stream, task, task::Poll,
+    ready,
+    stream::{self, FuturesUnordered},
+    task,
+    task::Poll,
 };
 use log::{ trace };
-use rand::seq::IteratorRandom;
-use rand::thread_rng;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 use redis::{
-    aio::ConnectionLike, Cmd, ConnectionAddr, ConnectionInfo, ErrorKind, IntoConnectionInfo,
-    RedisError, RedisFuture, RedisResult, Value,
+    aio::ConnectionLike, Cmd, ConnectionAddr, ConnectionInfo, ErrorKind, FromRedisValue,
+    IntoConnectionInfo, RedisError, RedisFuture, RedisResult, Value,
 };
 
 const SLOT_SIZE: usize = 16384;
 const DEFAULT_RETRIES: u32 = 16;
 
 /// This is a Redis cluster client.
+#[derive(Clone)]
 pub struct Client {
     initial_nodes: Vec<ConnectionInfo>,
+    params: ClusterParams,
+}
+
+// Settings shared by the connections created from a `Client`.
+#[derive(Clone)]
+struct ClusterParams {
     retries: Option<u32>,
+    timeout: Option<Duration>,
+    metrics: Metrics,
+    clock: Time,
+    rng_seed: Option<u64>,
+    auto_pipeline: Option<Duration>,
+    pool_size: usize,
+    pool_strategy: PoolStrategy,
+    queue_depth: usize,
+    max_in_flight: Option<NonZeroUsize>,
+    max_in_flight_per_node: Option<NonZeroUsize>,
+    max_queued_per_node: usize,
+    overload: OverloadPolicy,
+}
+
+impl Default for ClusterParams {
+    fn default() -> Self {
+        ClusterParams {
+            retries: Some(DEFAULT_RETRIES),
+            timeout: None,
+            metrics: Metrics::default(),
+            clock: Time::default(),
+            rng_seed: None,
+            auto_pipeline: None,
+            pool_size: 1,
+            pool_strategy: PoolStrategy::default(),
+            queue_depth: 100,
+            max_in_flight: None,
+            max_in_flight_per_node: None,
+            max_queued_per_node: 100,
+            overload: OverloadPolicy::default(),
+        }
+    }
 }
 
 impl Client {
@@ -113,14 +200,119 @@ impl Client {
 
         Ok(Client {
             initial_nodes: nodes,
-            retries: Some(DEFAULT_RETRIES),
+            params: ClusterParams::default(),
         })
     }
 
     /// Set how many times we should retry a query. Set `None` to retry forever.
     /// Default: 16
     pub fn set_retries(&mut self, retries: Option<u32>) -> &mut Self {
-        self.retries = retries;
+        self.params.retries = retries;
+        self
+    }
+
+    /// Set how long to wait for the response to a query, including all of its retries and
+    /// redirects, before failing with a timeout error. Set `None` to wait forever.
+    /// Default: None
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.params.timeout = timeout;
+        self
+    }
+
+    /// Hold the commands sent within `window` of each other and send them together, so that the
+    /// commands for a node are written to it as one pipeline instead of one write per command. A
+    /// zero window collects the commands received before the connection is polled again.
+    /// Redirects and retries are still handled per command, and are sent without waiting.
+    ///
+    /// A pipeline only returns its first error, so once a command of a pipeline fails each of its
+    /// commands is sent again on its own for its own reply. The commands which did not fail run
+    /// twice then, so only enable this for commands which can be repeated.
+    /// Default: off
+    pub fn set_auto_pipelining(&mut self, window: Duration) -> &mut Self {
+        self.params.auto_pipeline = Some(window);
+        self
+    }
+
+    /// Open `size` connections to each master and spread its requests over them, so that a large
+    /// or slow reply only holds up the requests sent over the same connection. A connection which
+    /// fails with an I/O error is reconnected while the others keep serving the node. Commands to
+    /// the same slot may then overtake each other when they are in flight on different
+    /// connections.
+    /// Default: 1
+    pub fn set_pool_size(&mut self, size: NonZeroUsize) -> &mut Self {
+        self.params.pool_size = size.get();
+        self
+    }
+
+    /// Set how the connection to a node is picked for each request.
+    /// Default: `PoolStrategy::LeastLoaded`
+    pub fn set_pool_strategy(&mut self, strategy: PoolStrategy) -> &mut Self {
+        self.params.pool_strategy = strategy;
+        self
+    }
+
+    /// Set how many requests can wait for the task which drives a connection to pick them up,
+    /// plus one for each clone of the connection.
+    /// Default: 100
+    pub fn set_queue_depth(&mut self, depth: usize) -> &mut Self {
+        self.params.queue_depth = depth;
+        self
+    }
+
+    /// Limit the number of requests which were picked up and are not yet answered, including
+    /// those which wait to be retried. Set `None` for no limit.
+    /// Default: None
+    pub fn set_max_in_flight(&mut self, limit: Option<NonZeroUsize>) -> &mut Self {
+        self.params.max_in_flight = limit;
+        self
+    }
+
+    /// Limit the number of requests which are sent to each node and not yet answered by it. Set
+    /// `None` for no limit.
+    /// Default: None
+    pub fn set_max_in_flight_per_node(&mut self, limit: Option<NonZeroUsize>) -> &mut Self {
+        self.params.max_in_flight_per_node = limit;
+        self
+    }
+
+    /// Set how many requests can wait for a node which reached the limit set with
+    /// `set_max_in_flight_per_node`. Once a node has that many waiting, the connection takes no
+    /// other requests until one of them is sent, or fails the requests to that node with
+    /// `OverloadPolicy::Fail`.
+    /// Default: 100
+    pub fn set_max_queued_per_node(&mut self, depth: usize) -> &mut Self {
+        self.params.max_queued_per_node = depth;
+        self
+    }
+
+    /// Set whether a request waits or fails when the queue is full or a limit on the requests in
+    /// flight is reached. Failed requests return an error for which `is_overloaded` is true.
+    /// Default: `OverloadPolicy::Wait`
+    pub fn set_overload_policy(&mut self, policy: OverloadPolicy) -> &mut Self {
+        self.params.overload = policy;
+        self
+    }
+
+    /// Register an observer which is told about the requests, redirects, retries, connections
+    /// and slot refreshes of the connections created from now on.
+    /// Default: None
+    pub fn set_metrics(&mut self, metrics: Arc<dyn ClusterMetrics>) -> &mut Self {
+        self.params.metrics = Metrics::new(metrics);
+        self
+    }
+
+    /// Set the clock which the connections wait on for retries and timeouts.
+    /// Default: the clock of the tokio runtime
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.params.clock = Time::new(clock);
+        self
+    }
+
+    /// Seed the random generator which picks the node for requests without a known slot and
+    /// for retries, so that the same requests are sent to the same nodes every time.
+    /// Default: seeded from the operating system
+    pub fn set_rng_seed(&mut self, seed: u64) -> &mut Self {
+        self.params.rng_seed = Some(seed);
         self
     }
 
@@ -130,7 +322,7 @@ impl Client {
     ///
     /// If it is failed to open connections and to create slots, an error is returned.
     pub async fn get_connection(&self) -> RedisResult<Connection> {
-        Connection::new(&self.initial_nodes, self.retries).await
+        Connection::new(&self.initial_nodes, self.params.clone()).await
     }
 
     #[doc(hidden)]
@@ -138,13 +330,90 @@ impl Client {
     where
         C: ConnectionLike + Connect + Clone + Send + Unpin + 'static,
     {
-        Connection::new(&self.initial_nodes, self.retries).await
+        Connection::new(&self.initial_nodes, self.params.clone()).await
+    }
+
+    /// Open a blocking Redis cluster connection which drives the cluster on its own runtime.
+    ///
+    /// # Errors
+    ///
+    /// If it is failed to open connections and to create slots, an error is returned.
+    pub fn get_sync_connection(&self) -> RedisResult<sync::ClusterConnection> {
+        sync::ClusterConnection::new(&self.initial_nodes, self.params.clone())
+    }
+
+    #[doc(hidden)]
+    pub fn get_generic_sync_connection<C>(&self) -> RedisResult<sync::ClusterConnection<C>>
+    where
+        C: ConnectionLike + Connect + Clone + Send + Unpin + 'static,
+    {
+        sync::ClusterConnection::new(&self.initial_nodes, self.params.clone())
+    }
+
+    /// Subscribe to `patterns` on every master of the cluster, for instance
+    /// `__keyspace@0__:*` to watch keyspace notifications.
+    ///
+    /// Messages from all masters are merged into a single stream and tagged with the address of
+    /// the node they came from. Masters which join or leave the cluster are subscribed to or
+    /// unsubscribed from automatically. Each master is subscribed to on a connection of its own,
+    /// opened with the password and database of the first initial node and subject to the timeout
+    /// set with `set_timeout`.
+    ///
+    /// # Errors
+    ///
+    /// If it is failed to open connections and to create slots, an error is returned.
+    pub async fn subscribe_keyspace_events<P: Into<String>>(
+        &self,
+        patterns: Vec<P>,
+    ) -> RedisResult<KeyspaceEvents> {
+        self.subscribe_keyspace_events_generic::<
+            redis::aio::MultiplexedConnection,
+            redis::aio::Connection,
+            P,
+        >(patterns)
+        .await
+    }
+
+    #[doc(hidden)]
+    pub async fn subscribe_keyspace_events_generic<C, S, P>(
+        &self,
+        patterns: Vec<P>,
+    ) -> RedisResult<KeyspaceEvents>
+    where
+        C: ConnectionLike + Connect + Clone + Send + 'static,
+        S: ConnectionLike + Connect + Send + 'static,
+        P: Into<String>,
+    {
+        let patterns = patterns.into_iter().map(Into::into).collect();
+        let params = self.params.clone();
+        pubsub::subscribe::<C, S>(&self.initial_nodes, params, patterns).await
     }
 }
 
 /// This is a connection of Redis cluster.
-#[derive(Clone)]
-pub struct Connection<C = redis::aio::MultiplexedConnection>(mpsc::Sender<Message<C>>);
+pub struct Connection<C = redis::aio::MultiplexedConnection> {
+    sender: mpsc::Sender<Message<C>>,
+    timeout: Option<Duration>,
+    clock: Time,
+    overload: OverloadPolicy,
+    metrics: Metrics,
+    // Set if `C` sends packed commands, see `Connect::sends_packed`
+    send_packed: Option<SendPacked<C>>,
+}
+
+// Every clone talks to the same driver so `C` does not need to be `Clone`
+impl<C> Clone for Connection<C> {
+    fn clone(&self) -> Self {
+        Connection {
+            sender: self.sender.clone(),
+            timeout: self.timeout,
+            clock: self.clock.clone(),
+            overload: self.overload,
+            metrics: self.metrics.clone(),
+            send_packed: self.send_packed,
+        }
+    }
+}
 
 impl<C> Connection<C>
 where
@@ -152,27 +421,84 @@ where
 {
     async fn new(
         initial_nodes: &[ConnectionInfo],
-        retries: Option<u32>,
+        params: ClusterParams,
     ) -> RedisResult<Connection<C>> {
-        Pipeline::new(initial_nodes, retries)
-            .map_ok(|pipeline| {
-                let (tx, rx) = mpsc::channel::<Message<_>>(100);
+        let timeout = params.timeout;
+        let clock = params.clock.clone();
+        let overload = params.overload;
+        let metrics = params.metrics.clone();
+        let queue_depth = params.queue_depth;
+        let send_packed = if C::sends_packed() {
+            let send: SendPacked<C> = |mut conn, packed, offset, count| {
+                Box::pin(async move { conn.req_packed(&packed, offset, count).await })
+            };
+            Some(send)
+        } else {
+            None
+        };
+        Pipeline::new(initial_nodes, params)
+            .map_ok(move |pipeline| {
+                let (tx, rx) = mpsc::channel::<Message<_>>(queue_depth);
                 tokio::spawn(rx.map(Ok).forward(pipeline).map(|_| ()));
-                Connection(tx)
+                Connection {
+                    sender: tx,
+                    timeout,
+                    clock,
+                    overload,
+                    metrics,
+                    send_packed,
+                }
             })
             .await
     }
 }
 
-type SlotMap = BTreeMap<(u16, u16), String>;
+type RequestFuture = BoxFuture<'static, (String, RedisResult<Response>)>;
+
+// The connections to each node, by address
+type Connections<C> = HashMap<String, NodePool<C>>;
+
+// The requests of a batch for each node, with a lease of its connection for each request
+type Batches<C> = Vec<(String, Vec<(Request<C>, Lease<C>)>)>;
+
+// Reopens a broken connection of a node's pool
+type Reconnect<C> = BoxFuture<'static, (String, Arc<Health>, RedisResult<C>)>;
 
 struct Pipeline<C> {
-    connections: HashMap<String, C>,
+    connections: Connections<C>,
+    reconnects: FuturesUnordered<Reconnect<C>>,
     slots: SlotMap,
     state: ConnectionState<C>,
-    in_flight_requests:
-        Vec<Request<BoxFuture<'static, (String, RedisResult<Response>)>, Response, C>>,
-    retries: Option<u32>,
+    in_flight_requests: FuturesUnordered<PendingRequest<C>>,
+    // Requests which failed in a way that needs a new slot map, sent again once it is loaded
+    queued_requests: Vec<Request<C>>,
+    // First attempts held back by auto-pipelining, sent together once `batch_timer` finishes
+    batch: Vec<Request<C>>,
+    batch_timer: Option<BoxFuture<'static, ()>>,
+    // Requests waiting for their node to drop below its limit, see `backpressure`
+    node_queues: HashMap<String, VecDeque<Request<C>>>,
+    // Requests which were received and not yet answered
+    unanswered: usize,
+    // The slots with requests in flight, see `ordering`
+    slot_queues: HashMap<u16, SlotQueue<C>>,
+    next_request_id: u64,
+    // Refreshes which were requested but not yet started
+    refresh_requests: Vec<oneshot::Sender<RedisResult<()>>>,
+    // Set when a redirect showed that the slot map is outdated
+    refresh_needed: bool,
+    // Waiting for the refresh which is currently running
+    refresh_waiters: Vec<oneshot::Sender<RedisResult<()>>>,
+    // Refreshes which failed in a row, reset when one succeeds
+    refresh_failures: u32,
+    // The span of the running recovery, the parent of its refreshes
+    recovery: Option<Span>,
+    // Topology requests received during a refresh, answered once it finishes
+    topology_requests: Vec<TopologyRequest>,
+    topology_subscribers: Vec<mpsc::UnboundedSender<TopologyEvent>>,
+    refreshed_at: Instant,
+    // Picks the nodes of requests which can be sent anywhere
+    rng: RefCell<StdRng>,
+    params: ClusterParams,
 }
 
 #[derive(Clone)]
@@ -187,9 +513,216 @@ enum CmdArg<C> {
         count: usize,
         func: fn(C, Arc<redis::Pipeline>, usize, usize) -> RedisFuture<'static, Response>,
     },
+    // Packed once for connections which write packed commands as they are, see `Connect`
+    Packed {
+        packed: Packed,
+        offset: usize,
+        count: usize,
+        // Answered with the only reply instead of a list
+        single: bool,
+        slot: Option<u16>,
+        func: SendPacked<C>,
+    },
+}
+
+// Sends packed commands with `Connect::req_packed`
+type SendPacked<C> = fn(C, Packed, usize, usize) -> RedisFuture<'static, Vec<Value>>;
+
+impl<C> CmdArg<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    // The command outlives the borrow it is given with, so it is copied once here, or packed if
+    // the connection sends packed commands. Every attempt borrows the copy, so retries and
+    // redirects do not copy it again.
+    fn cmd(cmd: &Cmd, send_packed: Option<SendPacked<C>>) -> Self {
+        if let Some(func) = send_packed {
+            return CmdArg::Packed {
+                packed: Packed::new(cmd.get_packed_command()),
+                offset: 0,
+                count: 1,
+                single: true,
+                slot: slot_for_command(cmd),
+                func,
+            };
+        }
+        CmdArg::Cmd {
+            cmd: Arc::new(cmd.clone()),
+            func: |mut conn, cmd| {
+                Box::pin(async move { conn.req_packed_command(&cmd).map_ok(Response::Single).await })
+            },
+        }
+    }
+
+    // Copied or packed once, like `cmd`
+    fn pipeline(
+        pipeline: &redis::Pipeline,
+        offset: usize,
+        count: usize,
+        send_packed: Option<SendPacked<C>>,
+    ) -> Self {
+        if let Some(func) = send_packed {
+            return CmdArg::Packed {
+                packed: Packed::new(pipeline.get_packed_pipeline()),
+                offset,
+                count,
+                single: false,
+                slot: slot_for_pipeline(pipeline),
+                func,
+            };
+        }
+        Self::shared_pipeline(Arc::new(pipeline.clone()), offset, count)
+    }
+
+    fn shared_pipeline(pipeline: Arc<redis::Pipeline>, offset: usize, count: usize) -> Self {
+        CmdArg::Pipeline {
+            pipeline,
+            offset,
+            count,
+            func: |mut conn, pipeline, offset, count| {
+                Box::pin(async move {
+                    conn.req_packed_commands(&pipeline, offset, count)
+                        .map_ok(Response::Multiple)
+                        .await
+                })
+            },
+        }
+    }
+
+    // The single commands `cmds` as one pipeline, which shares the bytes of packed commands
+    fn batch(cmds: &[Self]) -> Self {
+        if let Some(Self::Packed { func, .. }) = cmds.first() {
+            let packed = cmds.iter().filter_map(|cmd| match cmd {
+                Self::Packed { packed, .. } => Some(packed),
+                _ => None,
+            });
+            return CmdArg::Packed {
+                packed: Packed::join(packed),
+                offset: 0,
+                count: cmds.len(),
+                single: false,
+                slot: None,
+                func: *func,
+            };
+        }
+        let mut pipeline = redis::pipe();
+        for cmd in cmds {
+            if let Self::Cmd { cmd, .. } = cmd {
+                pipeline.add_command((**cmd).clone());
+            }
+        }
+        Self::shared_pipeline(Arc::new(pipeline), 0, cmds.len())
+    }
+
+    // ASKING only applies to the next command on the connection so it is written together with
+    // the command, as a single pipeline. This copies the command unless it is packed, so it is
+    // only built once per request.
+    fn asking(&self) -> Asking<C> {
+        if let Self::Packed {
+            packed,
+            offset,
+            count,
+            single,
+            slot,
+            func,
+        } = self
+        {
+            // A transaction is packed with its MULTI and EXEC, so ASKING only goes in front
+            return Asking::Packed(CmdArg::Packed {
+                packed: packed.asking(),
+                offset: offset + 1,
+                count: *count,
+                single: *single,
+                slot: *slot,
+                func: *func,
+            });
+        }
+        let mut asking = redis::pipe();
+        asking.cmd("ASKING");
+        let (offset, count) = match self {
+            Self::Cmd { cmd, .. } => {
+                asking.add_command((**cmd).clone());
+                (1, 1)
+            }
+            Self::Pipeline {
+                pipeline,
+                offset,
+                count,
+                ..
+            } => {
+                // Transactions are read from the EXEC reply, after every queued command
+                let atomic = *offset == pipeline.cmd_iter().count() + 1 && *count == 1;
+                if atomic {
+                    asking.cmd("MULTI");
+                }
+                for cmd in pipeline.cmd_iter() {
+                    asking.add_command(cmd.clone());
+                }
+                if atomic {
+                    asking.cmd("EXEC");
+                }
+                (offset + 1, *count)
+            }
+            Self::Packed { .. } => unreachable!(),
+        };
+        Asking::Copied {
+            pipeline: Arc::new(asking),
+            offset,
+            count,
+            single: matches!(self, Self::Cmd { .. }),
+        }
+    }
+}
+
+// A request preceded by ASKING, see `CmdArg::asking`
+#[derive(Clone)]
+enum Asking<C> {
+    Copied {
+        pipeline: Arc<redis::Pipeline>,
+        offset: usize,
+        count: usize,
+        single: bool,
+    },
+    Packed(CmdArg<C>),
+}
+
+impl<C> Asking<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    fn exec(&self, mut con: C) -> RedisFuture<'static, Response> {
+        let (pipeline, offset, count, single) = match self {
+            Asking::Copied {
+                pipeline,
+                offset,
+                count,
+                single,
+            } => (pipeline.clone(), *offset, *count, *single),
+            Asking::Packed(cmd) => return cmd.exec(con),
+        };
+        Box::pin(async move {
+            let values = con.req_packed_commands(&pipeline, offset, count).await?;
+            Ok(Response::new(values, single))
+        })
+    }
 }
 
 impl<C> CmdArg<C> {
+    // The name reported to `ClusterMetrics`
+    fn name(&self) -> &str {
+        match self {
+            Self::Cmd { cmd, .. } => match cmd.args_iter().next() {
+                Some(redis::Arg::Simple(name)) => std::str::from_utf8(name).unwrap_or("?"),
+                _ => "?",
+            },
+            Self::Pipeline { .. } => "PIPELINE",
+            Self::Packed { packed, single, .. } => match single {
+                true => packed.name().unwrap_or("?"),
+                false => "PIPELINE",
+            },
+        }
+    }
+
     fn exec(&self, con: C) -> RedisFuture<'static, Response> {
         match self {
             Self::Cmd { cmd, func } => func(con, cmd.clone()),
@@ -199,85 +732,133 @@ impl<C> CmdArg<C> {
                 count,
                 func,
             } => func(con, pipeline.clone(), *offset, *count),
+            Self::Packed {
+                packed,
+                offset,
+                count,
+                single,
+                func,
+                ..
+            } => {
+                let values = func(con, packed.clone(), *offset, *count);
+                let single = *single;
+                Box::pin(async move { Ok(Response::new(values.await?, single)) })
+            }
         }
     }
 
     fn slot(&self) -> Option<u16> {
-        fn get_cmd_arg(cmd: &Cmd, arg_num: usize) -> Option<&[u8]> {
-            cmd.args_iter().nth(arg_num).and_then(|arg| match arg {
-                redis::Arg::Simple(arg) => Some(arg),
-                redis::Arg::Cursor => None,
-            })
-        }
-        fn slot_for_command(cmd: &Cmd) -> Option<u16> {
-            match get_cmd_arg(cmd, 0) {
-                Some(b"EVAL") | Some(b"EVALSHA") => {
-                    get_cmd_arg(cmd, 2).and_then(|key_count_bytes| {
-                        let key_count_res = std::str::from_utf8(key_count_bytes)
-                            .ok()
-                            .and_then(|key_count_str| key_count_str.parse::<usize>().ok());
-                        key_count_res.and_then(|key_count| {
-                            if key_count > 0 {
-                                get_cmd_arg(cmd, 3).map(|key| slot_for_key(key))
-                            } else {
-                                // TODO need to handle sending to all masters
-                                None
-                            }
-                        })
-                    })
-                }
-                Some(b"SCRIPT") => {
-                    // TODO need to handle sending to all masters
-                    None
-                }
-                Some(b"XREAD") => {
-                    let streams_idx = cmd.args_iter()
-                        .enumerate()
-                        .find(|(_, arg)| match arg {
-                            // TODO: proper recognition of STREAMS call
-                            redis::Arg::Simple(b"STREAMS") => true,
-                            _ => false
-                        }).map(|(i, _)| i);
-                    if let Some(idx) = streams_idx {
-                        if let Some(redis::Arg::Simple(key)) = cmd.args_iter().nth(idx + 1) {
-                            // TODO: balancing for key [key] id [id] in https://redis.io/commands/xread
-                            return Some(slot_for_key(key))
-                        }
-                    }
-                    None
-                }
-                _ => get_cmd_arg(cmd, 1).map(|key| slot_for_key(key)),
-            }
-        }
         match self {
             Self::Cmd { cmd, .. } => slot_for_command(cmd),
-            Self::Pipeline { pipeline, .. } => {
-                let mut iter = pipeline.cmd_iter();
-                let slot = iter.next().map(slot_for_command)?;
-                for cmd in iter {
-                    if slot != slot_for_command(cmd) {
-                        return None;
+            Self::Pipeline { pipeline, .. } => slot_for_pipeline(pipeline),
+            Self::Packed { slot, .. } => *slot,
+        }
+    }
+}
+
+fn get_cmd_arg(cmd: &Cmd, arg_num: usize) -> Option<&[u8]> {
+    cmd.args_iter().nth(arg_num).and_then(|arg| match arg {
+        redis::Arg::Simple(arg) => Some(arg),
+        redis::Arg::Cursor => None,
+    })
+}
+
+fn slot_for_command(cmd: &Cmd) -> Option<u16> {
+    match get_cmd_arg(cmd, 0) {
+        Some(b"EVAL") | Some(b"EVALSHA") => {
+            get_cmd_arg(cmd, 2).and_then(|key_count_bytes| {
+                let key_count_res = std::str::from_utf8(key_count_bytes)
+                    .ok()
+                    .and_then(|key_count_str| key_count_str.parse::<usize>().ok());
+                key_count_res.and_then(|key_count| {
+                    if key_count > 0 {
+                        get_cmd_arg(cmd, 3).map(key_slot)
+                    } else {
+                        // TODO need to handle sending to all masters
+                        None
                     }
+                })
+            })
+        }
+        Some(b"SCRIPT") => {
+            // TODO need to handle sending to all masters
+            None
+        }
+        Some(b"XREAD") => {
+            let streams_idx = cmd
+                .args_iter()
+                .enumerate()
+                .find(|(_, arg)| match arg {
+                    // TODO: proper recognition of STREAMS call
+                    redis::Arg::Simple(b"STREAMS") => true,
+                    _ => false,
+                })
+                .map(|(i, _)| i);
+            if let Some(idx) = streams_idx {
+                if let Some(redis::Arg::Simple(key)) = cmd.args_iter().nth(idx + 1) {
+                    // TODO: balancing for key [key] id [id] in https://redis.io/commands/xread
+                    return Some(key_slot(key));
                 }
-                slot
             }
+            None
         }
+        _ => get_cmd_arg(cmd, 1).map(key_slot),
     }
 }
 
+// The slot of the commands of `pipeline`, if they all have the same one
+fn slot_for_pipeline(pipeline: &redis::Pipeline) -> Option<u16> {
+    let mut iter = pipeline.cmd_iter();
+    let slot = iter.next().map(slot_for_command)?;
+    for cmd in iter {
+        if slot != slot_for_command(cmd) {
+            return None;
+        }
+    }
+    slot
+}
+
 enum Response {
     Single(Value),
     Multiple(Vec<Value>),
 }
 
-struct Message<C> {
-    cmd: CmdArg<C>,
-    sender: oneshot::Sender<RedisResult<Response>>,
+impl Response {
+    // The replies to a pipeline, or to a single command sent as one
+    fn new(mut values: Vec<Value>, single: bool) -> Self {
+        if single {
+            Response::Single(values.pop().unwrap_or(Value::Nil))
+        } else {
+            Response::Multiple(values)
+        }
+    }
+}
+
+// Overrides where a request is sent instead of routing it by its key
+enum Route {
+    Slot(u16),
+    Node(String),
+    // Any node, for commands which read state every node has, such as `CLUSTER NODES`
+    Random,
+}
+
+enum Message<C> {
+    Request {
+        cmd: CmdArg<C>,
+        sender: oneshot::Sender<RedisResult<Response>>,
+        route: Option<Route>,
+        span: Span,
+        history: History,
+    },
+    // Reload the slot map even though no request failed
+    RefreshSlots(oneshot::Sender<RedisResult<()>>),
+    Topology(TopologyRequest),
+    TopologyEvents(oneshot::Sender<RedisResult<mpsc::UnboundedReceiver<TopologyEvent>>>),
 }
 
 enum ConnectionState<C> {
     PollComplete,
-    Recover(RedisFuture<'static, (SlotMap, HashMap<String, C>)>),
+    Recover(RedisFuture<'static, (SlotMap, Connections<C>)>),
 }
 
 impl<C> fmt::Debug for ConnectionState<C> {
@@ -297,20 +878,60 @@ struct RequestInfo<C> {
     cmd: CmdArg<C>,
     slot: Option<u16>,
     excludes: HashSet<String>,
+    node: Option<String>,
+    // Where a redirect sent the next attempt
+    redirect: Option<Redirected>,
+    // Built on the first ASK and reused by the later ones
+    asking: Option<Asking<C>>,
+    span: Span,
+    history: History,
+}
+
+enum Redirected {
+    Moved(String),
+    Ask(String),
 }
 
-enum RequestState<F> {
-    None,
-    Future(F),
-    Delay(tokio::time::Delay),
+enum RequestState {
+    Future(RequestFuture),
+    Delay(BoxFuture<'static, ()>),
 }
 
-struct Request<F, I, C> {
+struct Request<C> {
+    // Increases with every request received
+    id: u64,
+    // The slot whose requests this one must not overtake
+    ordered_slot: Option<u16>,
     retry: u32,
     max_retries: Option<u32>,
-    sender: Option<oneshot::Sender<RedisResult<I>>>,
+    sender: Option<oneshot::Sender<RedisResult<Response>>>,
     info: RequestInfo<C>,
-    future: RequestState<F>,
+}
+
+// A request which is being sent or waiting to be retried. Finishes with the request and the result
+// of the attempt, or `None` once the wait is over.
+struct PendingRequest<C> {
+    request: Option<Request<C>>,
+    state: RequestState,
+}
+
+// The request is only moved out, never polled in place
+impl<C> Unpin for PendingRequest<C> {}
+
+impl<C> Future for PendingRequest<C> {
+    type Output = (Request<C>, Option<(String, RedisResult<Response>)>);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Self::Output> {
+        let result = match &mut self.state {
+            RequestState::Future(future) => Some(ready!(future.as_mut().poll(cx))),
+            RequestState::Delay(delay) => {
+                ready!(delay.as_mut().poll(cx));
+                None
+            }
+        };
+        let request = self.request.take().expect("Request polled after completion");
+        (request, result).into()
+    }
 }
 
 #[must_use]
@@ -318,78 +939,98 @@ enum Next {
     Moved{ slot: u16, addr: String },
     Ask{ slot: u16, addr: String },
     TryNewConnection,
+    // Retries once the duration has passed
+    Delay(Duration),
     Done,
 }
 
-fn parse_ask_or_moved(err_str: &str) -> Result<(u16, String), Box<dyn Error>> {
-    let parts = err_str.split(' ').collect::<Vec<_>>();
-    if parts.len() != 3 {
-        return Err(format!("unexpected error message format '{}'", err_str).into());
+// Parses the `<slot> <host>:<port>` detail of a MOVED or ASK error.
+fn parse_ask_or_moved(err: &RedisError) -> Result<(u16, String), Box<dyn Error>> {
+    let detail = err.detail().unwrap_or_default();
+    let parts = detail.split(' ').collect::<Vec<_>>();
+    if parts.len() != 2 {
+        return Err(format!("unexpected error message format '{}'", err).into());
     }
-    let slot = parts[1].parse::<u16>()?;
-    let addr = parts[2].to_string();
+    let slot = parts[0].parse::<u16>()?;
+    let addr = parts[1].to_string();
     Ok((slot, addr))
 }
 
-impl<F, I, C> Request<F, I, C>
-where
-    F: Future<Output = (String, RedisResult<I>)> + Unpin,
-    C: ConnectionLike,
-{
-    fn poll_request(
+impl<C> Request<C> {
+    // Decides what to do after an attempt finished with `result`
+    fn handle_result(
         &mut self,
-        cx: &mut task::Context,
-        connections_len: usize,
-    ) -> Poll<Result<Next, RedisError>> {
-        let future = match &mut self.future {
-            RequestState::Future(f) => Pin::new(f),
-            RequestState::Delay(delay) => {
-                return Ok(match ready!(Pin::new(delay).poll(cx)) {
-                    () => Next::TryNewConnection,
-                })
-                .into();
-            }
-            _ => panic!("Request future must be Some"),
-        };
-        match ready!(future.poll(cx)) {
-            (_, Ok(item)) => {
+        addr: String,
+        result: RedisResult<Response>,
+        connections: &Connections<C>,
+        metrics: &Metrics,
+    ) -> Result<Next, RedisError> {
+        match result {
+            Ok(item) => {
                 trace!("Ok");
                 self.respond(Ok(item));
-                Ok(Next::Done).into()
+                Ok(Next::Done)
             }
-            (addr, Err(err)) => {
+            Err(err) => {
                 trace!("{:?} Request error {}", addr, err);
+                self.info.history.record(&addr, &err);
+
+                // Commands sent to a specific node are not rerouted
+                if self.info.node.is_some() {
+                    self.respond(Err(err));
+                    return Ok(Next::Done);
+                }
 
                 match self.max_retries {
                     Some(max_retries) if self.retry == max_retries => {
+                        let err = self.info.history.failed(err);
                         self.respond(Err(err));
-                        return Ok(Next::Done).into();
+                        return Ok(Next::Done);
                     }
                     _ => (),
                 }
                 self.retry = self.retry.saturating_add(1);
+                metrics.observe(|metrics| metrics.retried(self.retry));
 
                 if let Some(error_code) = err.code() {
                     match error_code {
                         "MOVED" => {
-                            if let Ok((slot, parsed_addr)) = parse_ask_or_moved(&format!("{}", err)) {
+                            if let Ok((slot, parsed_addr)) = parse_ask_or_moved(&err) {
+                                metrics.observe(|metrics| {
+                                    metrics.redirected(&addr, &Redirect::Moved { slot, to: parsed_addr.clone() })
+                                });
                                 self.info.excludes.insert(addr);
-                                return Ok(Next::Moved { slot, addr: parsed_addr }).into()
+                                return Ok(Next::Moved { slot, addr: parsed_addr })
                             }
+                            // A redirect we could not parse, refresh the slots and try again.
+                            self.info.excludes.clear();
+                            return Err(err);
                         }
                         "ASK" => {
-                            if let Ok((slot, parsed_addr)) = parse_ask_or_moved(&format!("{}", err)) {
-                                self.info.excludes.insert(addr);
-                                return Ok(Next::Ask { slot, addr: parsed_addr }).into()
+                            if let Ok((slot, parsed_addr)) = parse_ask_or_moved(&err) {
+                                metrics.observe(|metrics| {
+                                    metrics.redirected(&addr, &Redirect::Ask { slot, to: parsed_addr.clone() })
+                                });
+                                return Ok(Next::Ask { slot, addr: format!("redis://{}", parsed_addr) })
                             }
+                            // A redirect we could not parse, refresh the slots and try again.
+                            self.info.excludes.clear();
+                            return Err(err);
                         }
                         "TRYAGAIN" | "CLUSTERDOWN" => {
+                            metrics.observe(|metrics| {
+                                let redirect = if error_code == "TRYAGAIN" {
+                                    Redirect::TryAgain
+                                } else {
+                                    Redirect::ClusterDown
+                                };
+                                metrics.redirected(&addr, &redirect)
+                            });
                             // Sleep and retry.
                             let sleep_duration =
-                                Duration::from_millis(2u64.pow(self.retry.max(7).min(16)) * 10);
+                                Duration::from_millis(2u64.pow(self.retry.clamp(7, 16)) * 10);
                             self.info.excludes.clear();
-                            self.future = RequestState::Delay(tokio::time::delay_for(sleep_duration));
-                            return self.poll_request(cx, connections_len);
+                            return Ok(Next::Delay(sleep_duration));
                         }
 
                         _ => {}
@@ -398,17 +1039,20 @@ where
 
                 self.info.excludes.insert(addr);
 
-                if self.info.excludes.len() >= connections_len {
+                // Nodes which a refresh removed meanwhile do not count
+                let excludes = &self.info.excludes;
+                if connections.keys().all(|addr| excludes.contains(addr)) {
+                    let err = self.info.history.failed(err);
                     self.respond(Err(err));
-                    return Ok(Next::Done).into();
+                    return Ok(Next::Done);
                 }
 
-                Ok(Next::TryNewConnection).into()
+                Ok(Next::TryNewConnection)
             }
         }
     }
 
-    fn respond(&mut self, msg: RedisResult<I>) {
+    fn respond(&mut self, msg: RedisResult<Response>) {
         // If `send` errors the receiver has dropped and thus does not care about the message
         let _ = self
             .sender
@@ -422,24 +1066,46 @@ impl<C> Pipeline<C>
 where
     C: ConnectionLike + Connect + Clone + Send + 'static,
 {
-    async fn new(initial_nodes: &[ConnectionInfo], retries: Option<u32>) -> RedisResult<Self> {
-        let connections = Self::create_initial_connections(initial_nodes).await?;
+    async fn new(initial_nodes: &[ConnectionInfo], params: ClusterParams) -> RedisResult<Self> {
+        let connections = Self::create_initial_connections(initial_nodes, &params.metrics).await?;
         let mut connection = Pipeline {
             connections,
+            reconnects: FuturesUnordered::new(),
             slots: Default::default(),
-            in_flight_requests: Vec::new(),
+            in_flight_requests: FuturesUnordered::new(),
+            queued_requests: Vec::new(),
+            batch: Vec::new(),
+            batch_timer: None,
+            node_queues: HashMap::new(),
+            unanswered: 0,
+            slot_queues: HashMap::new(),
+            next_request_id: 0,
+            refresh_requests: Vec::new(),
+            refresh_needed: false,
+            refresh_waiters: Vec::new(),
+            refresh_failures: 0,
+            recovery: None,
+            topology_requests: Vec::new(),
+            topology_subscribers: Vec::new(),
+            refreshed_at: params.clock.now(),
+            rng: RefCell::new(match params.rng_seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            }),
             state: ConnectionState::PollComplete,
-            retries,
+            params,
         };
-        let (slots, connections) = connection.refresh_slots().await?;
+        let (slots, connections) = connection.refresh_slots("initial").await?;
         connection.slots = slots;
         connection.connections = connections;
+        connection.refreshed_at = connection.params.clock.now();
         Ok(connection)
     }
 
     async fn create_initial_connections(
         initial_nodes: &[ConnectionInfo],
-    ) -> RedisResult<HashMap<String, C>> {
+        metrics: &Metrics,
+    ) -> RedisResult<Connections<C>> {
         stream::iter(initial_nodes)
             .then(|info| {
                 let addr = match *info.addr {
@@ -447,15 +1113,15 @@ where
                     _ => panic!("Unable to reach host {:?}", info),
                 };
 
-                connect_and_check(info.clone()).map(|result| match result {
+                connect_and_check(info.clone(), metrics).map(|result| match result {
                     Ok(conn) => Some((addr, conn)),
                     Err(_) => None,
                 })
             })
             .fold(
                 HashMap::with_capacity(initial_nodes.len()),
-                |mut connections: HashMap<String, C>, conn: Option<(String, C)>| async move {
-                    connections.extend(conn);
+                |mut connections: Connections<C>, conn: Option<(String, C)>| async move {
+                    connections.extend(conn.map(|(addr, conn)| (addr, NodePool::new(conn))));
                     connections
                 },
             )
@@ -471,18 +1137,35 @@ where
             .await
     }
 
-    // Query a node to discover slot-> master mappings.
+    // Refreshes the slot map, and keeps doing so with a growing delay until it succeeds
+    fn recover(&mut self, reason: &'static str) {
+        self.recovery = Some(instrument::recover(reason));
+        let refresh = self.refresh_slots(reason);
+        self.state = ConnectionState::Recover(Box::pin(refresh));
+    }
+
+    // Query a node to discover slot-> master mappings. `reason` tells what triggered the refresh.
     fn refresh_slots(
         &mut self,
-    ) -> impl Future<Output = RedisResult<(SlotMap, HashMap<String, C>)>> {
-        let mut connections = mem::take(&mut self.connections);
-
-        async move {
+        reason: &'static str,
+    ) -> impl Future<Output = RedisResult<(SlotMap, Connections<C>)>> {
+        trace!("Refreshing slots ({})", reason);
+        // The connections stay in use by the requests which are sent during the refresh
+        let connections = self.connections.clone();
+        let metrics = self.params.metrics.clone();
+        let pool_size = self.params.pool_size;
+
+        let refresh = async move {
+            // One connection of each node is enough to ask for the slots
+            let mut nodes = connections
+                .values()
+                .map(|pool| pool.lease(PoolStrategy::LeastLoaded).connection())
+                .collect::<Vec<_>>();
             let mut result = Ok(SlotMap::new());
-            for conn in connections.values_mut() {
-                match get_slots(&mut *conn)
+            for conn in &mut nodes {
+                match get_slots(conn)
                     .await
-                    .and_then(Self::build_slot_map)
+                    .and_then(SlotMap::from_slots)
                 {
                     Ok(s) => {
                         result = Ok(s);
@@ -496,111 +1179,401 @@ where
             // Remove dead connections and connect to new nodes if necessary
             let new_connections = HashMap::with_capacity(connections.len());
 
-            let (_, connections) = stream::iter(slots.values())
+            let (_, connections) = stream::iter(slots.nodes().map(|addrs| &addrs.master))
                 .fold(
                     (connections, new_connections),
-                    move |(mut connections, mut new_connections), addr| async move {
-                        if !new_connections.contains_key(addr) {
-                            let new_connection = if let Some(mut conn) = connections.remove(addr) {
-                                match check_connection(&mut conn).await {
-                                    Ok(_) => Some((addr.to_string(), conn)),
-                                    Err(_) => match connect_and_check(addr.as_ref()).await {
-                                        Ok(conn) => Some((addr.to_string(), conn)),
-                                        Err(_) => None,
-                                    },
+                    |(mut connections, mut new_connections), addr| {
+                        let metrics = metrics.clone();
+                        async move {
+                            if !new_connections.contains_key(addr) {
+                                let old = connections.remove(addr);
+                                let old = old.map(NodePool::into_connections).unwrap_or_default();
+                                let mut checked = Vec::with_capacity(pool_size);
+                                for (mut conn, health) in old.into_iter().take(pool_size) {
+                                    if health.is_broken() {
+                                        continue;
+                                    }
+                                    if check_connection(&mut conn).await.is_ok() {
+                                        checked.push((conn, health));
+                                    }
                                 }
-                            } else {
-                                match connect_and_check(addr.as_ref()).await {
-                                    Ok(conn) => Some((addr.to_string(), conn)),
-                                    Err(_) => None,
+                                // Replaces the connections which failed the check
+                                while checked.len() < pool_size {
+                                    match connect_and_check(addr.as_ref(), &metrics).await {
+                                        Ok(conn) => checked.push((conn, Arc::default())),
+                                        Err(_) => break,
+                                    }
                                 }
-                            };
-                            new_connections.extend(new_connection);
+                                let pool = NodePool::from_connections(checked);
+                                new_connections.extend(pool.map(|pool| (addr.to_string(), pool)));
+                            }
+                            (connections, new_connections)
                         }
-                        (connections, new_connections)
                     },
                 )
                 .await;
             Ok((slots, connections))
-        }
+        };
+        let metrics = self.params.metrics.clone();
+        let span = instrument::refresh_slots(reason, self.recovery.as_ref());
+        let refresh_span = span.clone();
+        let refresh = refresh.inspect(move |result: &RedisResult<(SlotMap, Connections<C>)>| {
+            instrument::record_result(&refresh_span, result);
+            metrics.observe(|metrics| match result {
+                Ok((slots, _)) => {
+                    metrics.slots_refreshed(
+                        slots.nodes().map(|addrs| &addrs.master).collect::<HashSet<_>>().len(),
+                    )
+                }
+                Err(err) => metrics.slots_refresh_failed(err),
+            })
+        });
+        instrument::instrument(refresh, span)
     }
 
-    fn build_slot_map(mut slots_data: Vec<Slot>) -> RedisResult<SlotMap> {
-        slots_data.sort_by_key(|slot_data| slot_data.start);
-        let last_slot = slots_data.iter().try_fold(0, |prev_end, slot_data| {
-            if prev_end != slot_data.start() {
-                return Err(RedisError::from((
-                    ErrorKind::ResponseError,
-                    "Slot refresh error.",
-                    format!(
-                        "Received overlapping slots {} and {}..{}",
-                        prev_end, slot_data.start, slot_data.end
-                    ),
-                )));
+    // Picks a connection, excluding `excludes` unless that leaves none. The candidates are sorted
+    // so that a seeded generator always picks the same node.
+    fn get_random_connection(&self, excludes: Option<&HashSet<String>>) -> (String, Lease<C>) {
+        debug_assert!(!self.connections.is_empty());
+
+        let candidates = self.random_candidates(excludes);
+        let addr = candidates
+            .choose(&mut *self.rng.borrow_mut())
+            .expect("No targets to choose from");
+        let pool = self.connections.get(*addr).unwrap();
+        (addr.to_string(), pool.lease(self.params.pool_strategy))
+    }
+
+    // The nodes which `get_random_connection` picks from, sorted. Those below the limit of
+    // requests to a node are preferred.
+    fn random_candidates(&self, excludes: Option<&HashSet<String>>) -> Vec<&String> {
+        let mut candidates = match excludes {
+            Some(excludes) if excludes.len() < self.connections.len() => self
+                .connections
+                .keys()
+                .filter(|key| !excludes.contains(*key))
+                .collect::<Vec<_>>(),
+            _ => self.connections.keys().collect(),
+        };
+        if let Some(limit) = self.params.max_in_flight_per_node {
+            let below = |addr: &&String| self.connections[*addr].in_flight() < limit.get();
+            if candidates.iter().any(below) {
+                candidates.retain(below);
             }
-            Ok(slot_data.end() + 1)
-        })?;
-
-        if usize::from(last_slot) != SLOT_SIZE {
-            return Err(RedisError::from((
-                ErrorKind::ResponseError,
-                "Slot refresh error.",
-                format!("Lacks the slots >= {}", last_slot),
-            )));
         }
-        let slot_map = slots_data
-            .iter()
-            .map(|slot_data| ((slot_data.start(), slot_data.end()), slot_data.master().to_string()))
-            .collect();
-        Ok(slot_map)
+        candidates.sort();
+        candidates
     }
 
-    fn get_connection(&self, slot: u16) -> impl Future<Output = (String, C)> + 'static {
-        let slot = self.slots.iter().find(|((start, end), _)| slot >= *start && slot < *end);
-        if let Some((_, addr)) = slot {
-            if self.connections.contains_key(addr) {
-                return future::Either::Left(future::ready((
-                    addr.clone(),
-                    self.connections.get(addr).unwrap().clone(),
-                )));
+    fn get_connection(&self, slot: u16) -> impl Future<Output = (String, Lease<C>)> + 'static {
+        if let Some(SlotAddrs { master: addr, .. }) = self.slots.get(slot) {
+            if let Some(pool) = self.connections.get(addr) {
+                let conn = pool.lease(self.params.pool_strategy);
+                return future::Either::Left(future::ready((addr.clone(), conn)));
             }
 
             // Create new connection.
             //
-            let random_conn = get_random_connection(&self.connections, None); // TODO Only do this lookup if the first check fails
+            // TODO Only do this lookup if the first check fails
+            let random_conn = self.get_random_connection(None);
             let addr = addr.clone();
+            let metrics = self.params.metrics.clone();
             future::Either::Right(async move {
-                let result = connect_and_check(addr.as_ref()).await;
+                let result = connect_and_check(addr.as_ref(), &metrics).await;
                 result
-                    .map(|conn| (addr, conn))
+                    .map(|conn| (addr, Lease::unpooled(conn)))
                     .unwrap_or_else(|_| random_conn)
             })
         } else {
             // Return a random connection
-            future::Either::Left(future::ready(get_random_connection(
-                &self.connections,
-                None,
-            )))
+            future::Either::Left(future::ready(self.get_random_connection(None)))
+        }
+    }
+
+    // Called once `request` was answered
+    fn answered(&mut self, request: &Request<C>) {
+        self.unanswered -= 1;
+        if let Some(slot) = request.ordered_slot {
+            self.advance_slot(slot);
+        }
+    }
+
+    // Reopens the connections to `addr` which broke, while the others of its pool go on
+    fn reconnect(&mut self, addr: &str) {
+        let broken = match self.connections.get(addr) {
+            Some(pool) => pool.take_broken(),
+            None => return,
+        };
+        for health in broken {
+            let addr = addr.to_string();
+            let metrics = self.params.metrics.clone();
+            self.reconnects.push(
+                async move {
+                    let result = connect_and_check(addr.as_str(), &metrics).await;
+                    (addr, health, result)
+                }
+                .boxed(),
+            );
+        }
+    }
+
+    // Puts the reopened connections back in their pools
+    fn poll_reconnects(&mut self, cx: &mut task::Context) {
+        while let Poll::Ready(Some((addr, health, result))) = self.reconnects.poll_next_unpin(cx) {
+            match (result, self.connections.get_mut(&addr)) {
+                (Ok(conn), Some(pool)) => pool.replace(&health, conn),
+                (Ok(_), None) => health.reconnect_failed(),
+                // The node may have left the cluster. The connection is tried again once another
+                // attempt fails on it.
+                (Err(_), _) => {
+                    health.reconnect_failed();
+                    if let ConnectionState::PollComplete = self.state {
+                        self.refresh_needed = true;
+                    }
+                }
+            }
+        }
+    }
+
+    // Starts an attempt of `request`, or adds it to the batch if auto-pipelining holds it back
+    fn send_request(&mut self, request: Request<C>) {
+        match self.params.auto_pipeline {
+            Some(window) if request.retry == 0 => {
+                if self.batch.is_empty() && window > Duration::from_secs(0) {
+                    self.batch_timer = Some(self.params.clock.delay(window));
+                }
+                self.batch.push(request);
+            }
+            _ => self.start_request(request),
+        }
+    }
+
+    // Starts the attempts of the batch once its window has passed. Returns `true` if any were
+    // started, in which case they need to be polled.
+    fn poll_batch(&mut self, cx: &mut task::Context) -> bool {
+        if self.batch.is_empty() {
+            return false;
+        }
+        if let Some(timer) = &mut self.batch_timer {
+            if timer.as_mut().poll(cx).is_pending() {
+                return false;
+            }
+        }
+        self.batch_timer = None;
+        let mut nodes: Batches<C> = Vec::new();
+        for request in mem::take(&mut self.batch) {
+            let addr = match self.batch_node(&request.info) {
+                Some(addr) => addr,
+                None => {
+                    // Sent after the commands to its slot which were received before it
+                    if request.ordered_slot.is_some() {
+                        self.send_batches(mem::take(&mut nodes));
+                    }
+                    self.start_request(request);
+                    continue;
+                }
+            };
+            if let Some(addr) = self.saturated_node(&request.info) {
+                self.node_overloaded(addr, request);
+                continue;
+            }
+            let lease = match nodes.iter().find(|(node, _)| *node == addr) {
+                Some((_, requests)) => requests[0].1.clone(),
+                None => {
+                    nodes.push((addr.clone(), Vec::new()));
+                    self.connections[&addr].lease(self.params.pool_strategy)
+                }
+            };
+            let (_, requests) = nodes.iter_mut().find(|(node, _)| *node == addr).unwrap();
+            requests.push((request, lease));
+        }
+        self.send_batches(nodes);
+        true
+    }
+
+    // The node which the first attempt of `info` is sent to with the others of the batch. Only
+    // commands which are routed by their key are batched.
+    fn batch_node(&self, info: &RequestInfo<C>) -> Option<String> {
+        let single = matches!(
+            info.cmd,
+            CmdArg::Cmd { .. } | CmdArg::Packed { single: true, .. }
+        );
+        match (&info.node, &info.redirect, info.slot) {
+            (None, None, Some(slot)) if single && info.excludes.is_empty() => {
+                let addr = &self.slots.get(slot)?.master;
+                self.connections
+                    .get_key_value(addr)
+                    .map(|(addr, _)| addr.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn send_batches(&mut self, nodes: Batches<C>) {
+        for (addr, mut requests) in nodes {
+            if requests.len() > 1 {
+                self.send_batch(addr, requests);
+                continue;
+            }
+            // A single command is sent as usual, which leases the connection again
+            let (request, lease) = requests.pop().unwrap();
+            drop(lease);
+            self.send_attempt(request);
+        }
+    }
+
+    // Sends the first attempts of `requests` to `addr` as one pipeline. The first request sends
+    // it and hands the other requests their replies.
+    fn send_batch(&mut self, addr: String, requests: Vec<(Request<C>, Lease<C>)>) {
+        let cmds = requests
+            .iter()
+            .map(|(request, _)| request.info.cmd.clone())
+            .collect::<Vec<_>>();
+        let (senders, receivers): (Vec<_>, Vec<_>) =
+            requests[1..].iter().map(|_| oneshot::channel()).unzip();
+
+        let mut requests = requests.into_iter();
+        let (first, lease) = requests.next().unwrap();
+        let batch = async move {
+            let mut results = exec_batch(lease, &cmds).await.into_iter();
+            let result = results.next().expect("A reply for every command");
+            for (sender, result) in senders.into_iter().zip(results) {
+                let _ = sender.send(result);
+            }
+            result
+        };
+        self.send_batched(addr.clone(), first, batch.boxed());
+
+        for ((request, lease), receiver) in requests.zip(receivers) {
+            let reply = async move {
+                // Only dropped along with the driver
+                let result = receiver.await.unwrap_or_else(|_| {
+                    Err(RedisError::from(io::Error::from(io::ErrorKind::BrokenPipe)))
+                });
+                drop(lease);
+                result
+            };
+            self.send_batched(addr.clone(), request, reply.boxed());
+        }
+    }
+
+    // Adds the attempt of a batched request, which finishes with `reply`
+    fn send_batched(
+        &mut self,
+        addr: String,
+        request: Request<C>,
+        reply: RedisFuture<'static, Response>,
+    ) {
+        let cmd = request.info.cmd.clone();
+        let metrics = self.params.metrics.clone();
+        let clock = self.params.clock.clone();
+        let attempt = async move {
+            let result = observe_request(&metrics, &clock, &addr, &cmd, reply).await;
+            (addr, result)
+        };
+        let future = self.instrument_attempt(&request, attempt);
+        self.in_flight_requests.push(PendingRequest {
+            request: Some(request),
+            state: RequestState::Future(future),
+        });
+    }
+
+    fn start_request(&mut self, request: Request<C>) {
+        if let Some(addr) = self.saturated_node(&request.info) {
+            return self.node_overloaded(addr, request);
+        }
+        self.send_attempt(request);
+    }
+
+    // A redirect only applies to the attempt it was given for
+    fn send_attempt(&mut self, mut request: Request<C>) {
+        if let (Some(Redirected::Ask(_)), None) = (&request.info.redirect, &request.info.asking) {
+            request.info.asking = Some(request.info.cmd.asking());
         }
+        let future = self.try_request(&request);
+        request.info.redirect = None;
+        self.in_flight_requests.push(PendingRequest {
+            request: Some(request),
+            state: RequestState::Future(future),
+        });
     }
 
-    fn try_request(
+    fn try_request(&self, request: &Request<C>) -> RequestFuture {
+        self.instrument_attempt(request, self.try_attempt(&request.info))
+    }
+
+    fn instrument_attempt(
         &self,
-        info: &RequestInfo<C>,
-    ) -> impl Future<Output = (String, RedisResult<Response>)> {
-        // TODO remove clone by changing the ConnectionLike trait
+        request: &Request<C>,
+        attempt: impl Future<Output = (String, RedisResult<Response>)> + Send + 'static,
+    ) -> RequestFuture {
+        let info = &request.info;
+        let redirect = match info.redirect {
+            Some(Redirected::Moved(_)) => Some("moved"),
+            Some(Redirected::Ask(_)) => Some("ask"),
+            None => None,
+        };
+        let span = instrument::attempt(&info.span, request.retry, redirect);
+        let attempt_span = span.clone();
+        let attempt = attempt.inspect(move |(addr, result)| {
+            instrument::record_node(&attempt_span, addr);
+            instrument::record_result(&attempt_span, result);
+        });
+        instrument::instrument(attempt, span).boxed()
+    }
+
+    fn try_attempt(&self, info: &RequestInfo<C>) -> RequestFuture {
+        // Only clones the `Arc`s of the command
         let cmd = info.cmd.clone();
-        (if !info.excludes.is_empty() || info.slot.is_none() {
-            let conn = get_random_connection(
-                &self.connections,
-                Some(&info.excludes),
-            );
-            future::Either::Left(future::ready(conn))
-        } else {
-            let conn = self.get_connection(info.slot.unwrap());
-            future::Either::Right(conn)
+        let metrics = self.params.metrics.clone();
+        let clock = self.params.clock.clone();
+
+        let target = match (&info.node, &info.redirect) {
+            (Some(addr), _) | (None, Some(Redirected::Moved(addr))) => Some((addr, None)),
+            (None, Some(Redirected::Ask(addr))) => {
+                Some((addr, Some(info.asking.clone().expect("ASKING was built"))))
+            }
+            (None, None) => None,
+        };
+        if let Some((addr, asking)) = target {
+            let strategy = self.params.pool_strategy;
+            let conn = self.connections.get(addr).map(|pool| pool.lease(strategy));
+            let addr = addr.clone();
+            return async move {
+                let conn = match conn {
+                    Some(conn) => conn,
+                    None => match connect_and_check(addr.as_ref(), &metrics).await {
+                        Ok(conn) => Lease::unpooled(conn),
+                        Err(err) => return (addr, Err(err)),
+                    },
+                };
+                let request = match asking {
+                    Some(asking) => asking.exec(conn.connection()),
+                    None => cmd.exec(conn.connection()),
+                };
+                let result = observe_request(&metrics, &clock, &addr, &cmd, request).await;
+                conn.finished(&result);
+                (addr, result)
+            }
+            .boxed();
+        }
+
+        (match info.slot {
+            Some(slot) if info.excludes.is_empty() => {
+                future::Either::Right(self.get_connection(slot))
+            }
+            _ => {
+                let conn = self.get_random_connection(Some(&info.excludes));
+                future::Either::Left(future::ready(conn))
+            }
         })
-        .then(move |(addr, conn)| cmd.exec(conn).map(|result| (addr, result)))
+        .then(move |(addr, conn)| async move {
+            let request = cmd.exec(conn.connection());
+            let result = observe_request(&metrics, &clock, &addr, &cmd, request).await;
+            conn.finished(&result);
+            (addr, result)
+        })
+        .boxed()
     }
 }
 
@@ -610,31 +1583,89 @@ where
 {
     type Error = ();
 
-    fn poll_ready(self: Pin<&mut Self>, _cx: &mut task::Context) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context,
+    ) -> Poll<Result<(), Self::Error>> {
         trace!("Pipeline::poll_ready");
+        if self.params.overload == OverloadPolicy::Fail || !self.is_full() {
+            return Ok(()).into();
+        }
+        // Answering requests is what makes room
+        let _ = self.as_mut().poll_flush(cx);
+        if self.is_full() {
+            return Poll::Pending;
+        }
         Ok(()).into()
     }
 
     fn start_send(mut self: Pin<&mut Self>, msg: Message<C>) -> Result<(), Self::Error> {
         trace!("start_send");
-        let cmd = msg.cmd;
+        let (cmd, sender, route, span, history) = match msg {
+            Message::Request {
+                cmd,
+                sender,
+                route,
+                span,
+                history,
+            } => (cmd, sender, route, span, history),
+            Message::RefreshSlots(sender) => {
+                self.refresh_requests.push(sender);
+                return Ok(());
+            }
+            Message::Topology(request) => {
+                match self.state {
+                    ConnectionState::PollComplete => self.answer_topology(request),
+                    ConnectionState::Recover(_) => self.topology_requests.push(request),
+                }
+                return Ok(());
+            }
+            Message::TopologyEvents(sender) => {
+                let (events, receiver) = mpsc::unbounded();
+                self.topology_subscribers.push(events);
+                let _ = sender.send(Ok(receiver));
+                return Ok(());
+            }
+        };
+
+        if let Some(limit) = self.params.max_in_flight {
+            // Only reached with `OverloadPolicy::Fail`, `poll_ready` waits otherwise
+            if self.unanswered >= limit.get() {
+                let _ = sender.send(Err(overloaded("the limit of requests in flight")));
+                return Ok(());
+            }
+        }
+        self.unanswered += 1;
 
         let excludes = HashSet::new();
-        let slot = cmd.slot();
+        let (slot, node) = match route {
+            Some(Route::Slot(slot)) => (Some(slot), None),
+            Some(Route::Node(addr)) => (None, Some(addr)),
+            Some(Route::Random) => (None, None),
+            None => (cmd.slot(), None),
+        };
 
         let info = RequestInfo {
             cmd,
             slot,
             excludes,
+            node,
+            redirect: None,
+            asking: None,
+            span,
+            history,
         };
+        let id = self.next_request_id;
+        self.next_request_id += 1;
         let request = Request {
-            max_retries: self.retries,
+            id,
+            ordered_slot: slot,
+            max_retries: self.params.retries,
             retry: 0,
-            sender: Some(msg.sender),
-            future: RequestState::None,
+            sender: Some(sender),
             info,
         };
-        self.in_flight_requests.push(request);
+        self.dispatch(request);
         Ok(())
     }
 
@@ -644,96 +1675,167 @@ where
     ) -> Poll<Result<(), Self::Error>> {
         trace!("poll_complete: {:?}", self.state);
         loop {
-            self.state = match mem::replace(&mut self.state, ConnectionState::PollComplete) {
-                ConnectionState::Recover(mut future) => match future.as_mut().poll(cx) {
+            // Requests keep being sent with the old slot map while a refresh is running
+            if let ConnectionState::Recover(future) = &mut self.state {
+                match future.as_mut().poll(cx) {
                     Poll::Ready(Ok((slots, connections))) => {
                         trace!("Recovered with {} connections!", connections.len());
+                        self.slots_changed(&slots);
                         self.slots = slots;
                         self.connections = connections;
-                        ConnectionState::PollComplete
-                    }
-                    Poll::Pending => {
-                        self.state = ConnectionState::Recover(future);
-                        trace!("Recover not ready");
-                        return Poll::Pending;
+                        self.refreshed_at = self.params.clock.now();
+                        self.refresh_failures = 0;
+                        self.recovery = None;
+                        self.state = ConnectionState::PollComplete;
+                        for waiter in self.refresh_waiters.drain(..) {
+                            let _ = waiter.send(Ok(()));
+                        }
+                        for request in mem::take(&mut self.topology_requests) {
+                            self.answer_topology(request);
+                        }
+                        for request in mem::take(&mut self.queued_requests) {
+                            self.send_request(request);
+                        }
+                        self.advance_slots();
+                        self.release_nodes();
                     }
+                    Poll::Pending => trace!("Recover not ready"),
                     Poll::Ready(Err(err)) => {
                         log::trace!("error trying to recover {:?}", err);
-                        ConnectionState::Recover(Box::pin(self.refresh_slots()))
-                    }
-                },
-                ConnectionState::PollComplete => {
-                    let mut error = None;
-                    let mut i = 0;
-
-                    while i < self.in_flight_requests.len() {
-                        if let RequestState::None = self.in_flight_requests[i].future {
-                            let future = self.try_request(&self.in_flight_requests[i].info);
-                            self.in_flight_requests[i].future =
-                                RequestState::Future(Box::pin(future));
+                        self.refresh_failures += 1;
+                        if let Some(recovery) = &self.recovery {
+                            instrument::record_failures(recovery, self.refresh_failures);
                         }
+                        // Subscribers learn of an outage once, `RecoveryFinished` ends it
+                        if self.refresh_failures == 1 {
+                            self.refresh_failed(&err);
+                        }
+                        let refresh_error = || {
+                            RedisError::from((
+                                err.kind(),
+                                "redis_cluster: Unable to refresh slots",
+                                err.to_string(),
+                            ))
+                        };
+                        for waiter in self.refresh_waiters.drain(..) {
+                            let _ = waiter.send(Err(refresh_error()));
+                        }
+                        // They would otherwise wait until a refresh succeeds
+                        for request in self.topology_requests.drain(..) {
+                            request.fail(refresh_error());
+                        }
+                        let delay = self
+                            .params
+                            .clock
+                            .delay(refresh_backoff(self.refresh_failures));
+                        let refresh = self.refresh_slots("retry");
+                        self.state = ConnectionState::Recover(Box::pin(async move {
+                            delay.await;
+                            refresh.await
+                        }));
+                        continue;
+                    }
+                }
+            }
 
-                        let self_ = &mut *self;
-                        match self_.in_flight_requests[i].poll_request(cx, self_.connections.len())
-                        {
-                            Poll::Pending => {
-                                i += 1;
-                            }
-                            Poll::Ready(result) => match result {
-                                Ok(next) => match next {
-                                    Next::Done => {
-                                        self.in_flight_requests.swap_remove(i);
-                                    }
-                                    Next::TryNewConnection => {
-                                        let mut request = self.in_flight_requests.swap_remove(i);
-                                        request.future = RequestState::Future(Box::pin(
-                                            self.try_request(&request.info),
-                                        ));
-                                        self.in_flight_requests.push(request);
-                                    }
-                                    // ASK is intended to ask the directed connection for just this
-                                    // request
-                                    Next::Ask{slot, addr} => {
-                                        trace!("ASK {}, {}", slot, addr);
-                                        let mut request = self.in_flight_requests.swap_remove(i);
-                                        request.info.slot = Some(slot);
-                                        request.future = RequestState::Future(Box::pin(
-                                            self.try_request(&request.info),
-                                        ));
-                                        self.in_flight_requests.push(request);
-                                    }
-
-                                    // MOVED needs to update the slot map
-                                    Next::Moved{slot, addr} => {
-                                        trace!("MOVED {}, {}", slot, addr);
-                                        let mut request = self.in_flight_requests.swap_remove(i);
-                                        request.info.slot = Some(slot);
-                                        request.future = RequestState::Future(Box::pin(
-                                            self.try_request(&request.info),
-                                        ));
-                                        self.in_flight_requests.push(request);
-                                    }
-                                },
-                                Err(err) => {
-                                    log::trace!("error {:?}", err);
-                                    error = Some(err);
+            if let ConnectionState::PollComplete = self.state {
+                if self.refresh_needed || !self.refresh_requests.is_empty() {
+                    let reason = if self.refresh_needed { "moved" } else { "requested" };
+                    self.refresh_needed = false;
+                    self.refresh_waiters = mem::take(&mut self.refresh_requests);
+                    self.recover(reason);
+                    continue;
+                }
+            }
 
-                                    self.in_flight_requests[i].future = RequestState::None;
-                                    i += 1;
-                                }
-                            },
+            let mut error = None;
+            // Only the requests which were woken are polled
+            while let Poll::Ready(Some((mut request, result))) =
+                self.in_flight_requests.poll_next_unpin(cx)
+            {
+                if let Some((addr, Err(err))) = &result {
+                    if pool::is_broken(err) {
+                        self.reconnect(addr);
+                    }
+                }
+                // The node has room for a waiting request once this one is handled
+                let released = match &result {
+                    Some((addr, _)) if !self.node_queues.is_empty() => Some(addr.clone()),
+                    _ => None,
+                };
+                let next = match result {
+                    Some((addr, result)) => request.handle_result(
+                        addr,
+                        result,
+                        &self.connections,
+                        &self.params.metrics,
+                    ),
+                    // The wait after TRYAGAIN or CLUSTERDOWN is over
+                    None => Ok(Next::TryNewConnection),
+                };
+                self.finished_attempt(&request);
+                match next {
+                    Ok(Next::Done) => self.answered(&request),
+                    Ok(Next::TryNewConnection) => self.retry(request, Resend::Now),
+                    Ok(Next::Delay(duration)) => self.retry(request, Resend::After(duration)),
+                    // ASK is intended to ask the directed connection for just this
+                    // request
+                    Ok(Next::Ask { slot, addr }) => {
+                        trace!("ASK {}, {}", slot, addr);
+                        request.info.slot = Some(slot);
+                        request.info.redirect = Some(Redirected::Ask(addr));
+                        self.retry(request, Resend::Now);
+                    }
+                    // MOVED sends the request to the new owner of the slot and
+                    // updates the slot map
+                    Ok(Next::Moved { slot, addr }) => {
+                        trace!("MOVED {}, {}", slot, addr);
+                        request.info.slot = Some(slot);
+                        request.info.redirect =
+                            Some(Redirected::Moved(format!("redis://{}", addr)));
+                        self.retry(request, Resend::Now);
+                        // A refresh which is already running is likely to see the move as well
+                        if let ConnectionState::PollComplete = self.state {
+                            self.refresh_needed = true;
                         }
                     }
+                    // Retried once the slots are refreshed, while the other requests go on
+                    Err(err) => {
+                        log::trace!("error {:?}", err);
+                        error = Some(err);
+                        self.retry(request, Resend::AfterRefresh);
+                    }
+                }
+                if let Some(addr) = released {
+                    self.release_node(&addr);
+                }
+            }
 
+            self.poll_reconnects(cx);
+
+            // The started attempts are polled on the next turn of the loop
+            let batch_started = self.poll_batch(cx);
+
+            match self.state {
+                ConnectionState::PollComplete => {
                     if let Some(err) = error {
                         trace!("Recovering {}", err);
-                        ConnectionState::Recover(Box::pin(self.refresh_slots()))
-                    } else if self.in_flight_requests.is_empty() {
+                        self.refresh_needed = false;
+                        self.recover("error");
+                    } else if self.refresh_needed || batch_started {
+                        // Picked up by the next turn of the loop
+                    } else if self.in_flight_requests.is_empty()
+                        && self.batch.is_empty()
+                        && self.reconnects.is_empty()
+                    {
                         return Ok(()).into();
                     } else {
                         return Poll::Pending;
                     }
                 }
+                // The failed requests wait for the refresh which is running
+                ConnectionState::Recover(_) if batch_started => (),
+                ConnectionState::Recover(_) => return Poll::Pending,
             }
         }
     }
@@ -743,41 +1845,142 @@ where
     }
 }
 
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    async fn send<T>(
+        &mut self,
+        msg: impl FnOnce(oneshot::Sender<RedisResult<T>>) -> Message<C>,
+    ) -> RedisResult<T> {
+        let (sender, receiver) = oneshot::channel();
+        let unable_to_send = || {
+            RedisError::from(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "redis_cluster: Unable to send command",
+            ))
+        };
+        match self.overload {
+            OverloadPolicy::Wait => {
+                self.sender
+                    .send(msg(sender))
+                    .map_err(|_| unable_to_send())
+                    .await?
+            }
+            OverloadPolicy::Fail => self.sender.try_send(msg(sender)).map_err(|err| {
+                if err.is_full() {
+                    overloaded("the queue depth")
+                } else {
+                    unable_to_send()
+                }
+            })?,
+        }
+        receiver.await.unwrap_or_else(|_| {
+            Err(RedisError::from(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "redis_cluster: Unable to receive command",
+            )))
+        })
+    }
+
+    async fn request(&mut self, cmd: CmdArg<C>, route: Option<Route>) -> RedisResult<Response> {
+        let timeout = self.timeout;
+        let clock = self.clock.clone();
+        let span = instrument::request(&cmd, route.as_ref());
+        let request_span = span.clone();
+        let history = History::default();
+        let request_history = history.clone();
+        let request = self.send(|sender| Message::Request {
+            cmd,
+            sender,
+            route,
+            span: request_span,
+            history: request_history,
+        });
+        let request = with_timeout(&clock, timeout, request, || history.timed_out());
+        instrument::instrument(request, span).await
+    }
+
+    // Reloads the slot map. If a refresh is already running another one is started after it.
+    pub(crate) async fn refresh_slots(&mut self) -> RedisResult<()> {
+        self.send(Message::RefreshSlots).await
+    }
+
+    pub(crate) async fn topology(&mut self) -> RedisResult<Topology> {
+        self.send(|sender| Message::Topology(TopologyRequest::Full(sender)))
+            .await
+    }
+
+    // The nodes which serve `slot`, without the rest of the slot map
+    pub(crate) async fn slot_addrs(&mut self, slot: u16) -> RedisResult<Option<SlotAddrs>> {
+        self.send(|sender| Message::Topology(TopologyRequest::Slot(slot, sender)))
+            .await
+    }
+
+    // Sends `cmd` to the master of `slot`, following redirects like any other request
+    pub(crate) async fn query_slot<T: FromRedisValue>(
+        &mut self,
+        slot: u16,
+        cmd: &Cmd,
+    ) -> RedisResult<T> {
+        let cmd = CmdArg::cmd(cmd, self.send_packed);
+        match self.request(cmd, Some(Route::Slot(slot))).await? {
+            Response::Single(value) => T::from_redis_value(&value),
+            Response::Multiple(_) => unreachable!(),
+        }
+    }
+
+    pub(crate) async fn query_slot_pipeline<T: FromRedisValue>(
+        &mut self,
+        slot: u16,
+        pipeline: &redis::Pipeline,
+    ) -> RedisResult<Vec<T>> {
+        let count = pipeline.cmd_iter().count();
+        match self
+            .request(
+                CmdArg::pipeline(pipeline, 0, count, self.send_packed),
+                Some(Route::Slot(slot)),
+            )
+            .await?
+        {
+            Response::Multiple(values) => values.iter().map(T::from_redis_value).collect(),
+            Response::Single(_) => unreachable!(),
+        }
+    }
+
+    // Sends `cmd` to a node picked at random, retrying on another one if it fails
+    pub(crate) async fn query_random<T: FromRedisValue>(&mut self, cmd: &Cmd) -> RedisResult<T> {
+        let cmd = CmdArg::cmd(cmd, self.send_packed);
+        match self.request(cmd, Some(Route::Random)).await? {
+            Response::Single(value) => T::from_redis_value(&value),
+            Response::Multiple(_) => unreachable!(),
+        }
+    }
+
+    /// Send `cmd` to the node at `addr`, given as `redis://host:port`, instead of routing it by
+    /// its key. The node does not have to be a master. Errors, including redirects, are returned
+    /// as is instead of being retried.
+    pub async fn query_node<T: FromRedisValue>(&mut self, addr: &str, cmd: &Cmd) -> RedisResult<T> {
+        let cmd = CmdArg::cmd(cmd, self.send_packed);
+        match self
+            .request(cmd, Some(Route::Node(addr.to_string())))
+            .await?
+        {
+            Response::Single(value) => T::from_redis_value(&value),
+            Response::Multiple(_) => unreachable!(),
+        }
+    }
+}
+
 impl<C> ConnectionLike for Connection<C>
 where
     C: ConnectionLike + Send + 'static,
 {
     fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
         trace!("req_packed_command");
-        let (sender, receiver) = oneshot::channel();
         Box::pin(async move {
-            self.0
-                .send(Message {
-                    cmd: CmdArg::Cmd {
-                        cmd: Arc::new(cmd.clone()), // TODO Remove this clone?
-                        func: |mut conn, cmd| {
-                            Box::pin(async move {
-                                conn.req_packed_command(&cmd).map_ok(Response::Single).await
-                            })
-                        },
-                    },
-                    sender,
-                })
-                .map_err(|_| {
-                    RedisError::from(io::Error::new(
-                        io::ErrorKind::BrokenPipe,
-                        "redis_cluster: Unable to send command",
-                    ))
-                })
-                .await?;
-            receiver
+            self.request(CmdArg::cmd(cmd, self.send_packed), None)
                 .await
-                .unwrap_or_else(|_| {
-                    Err(RedisError::from(io::Error::new(
-                        io::ErrorKind::BrokenPipe,
-                        "redis_cluster: Unable to receive command",
-                    )))
-                })
                 .map(|response| match response {
                     Response::Single(value) => value,
                     Response::Multiple(_) => unreachable!(),
@@ -791,32 +1994,10 @@ where
         offset: usize,
         count: usize,
     ) -> RedisFuture<'a, Vec<Value>> {
-        let (sender, receiver) = oneshot::channel();
         Box::pin(async move {
-            self.0
-                .send(Message {
-                    cmd: CmdArg::Pipeline {
-                        pipeline: Arc::new(pipeline.clone()), // TODO Remove this clone?
-                        offset,
-                        count,
-                        func: |mut conn, pipeline, offset, count| {
-                            Box::pin(async move {
-                                conn.req_packed_commands(&pipeline, offset, count)
-                                    .map_ok(Response::Multiple)
-                                    .await
-                            })
-                        },
-                    },
-                    sender,
-                })
-                .map_err(|_| RedisError::from(io::Error::from(io::ErrorKind::BrokenPipe)))
-                .await?;
-
-            receiver
+            let pipeline = CmdArg::pipeline(pipeline, offset, count, self.send_packed);
+            self.request(pipeline, None)
                 .await
-                .unwrap_or_else(|_| {
-                    Err(RedisError::from(io::Error::from(io::ErrorKind::BrokenPipe)))
-                })
                 .map(|response| match response {
                     Response::Multiple(values) => values,
                     Response::Single(_) => unreachable!(),
@@ -829,9 +2010,27 @@ where
     }
 }
 
-impl Clone for Client {
-    fn clone(&self) -> Client {
-        Client::open(self.initial_nodes.clone()).unwrap()
+// The delay before the slot map is reloaded again after `failures` refreshes failed in a row. It
+// doubles with every failure, from 20ms up to 1.28s.
+fn refresh_backoff(failures: u32) -> Duration {
+    Duration::from_millis(2u64.pow(failures.clamp(1, 7)) * 10)
+}
+
+pub(crate) async fn with_timeout<T>(
+    clock: &Time,
+    timeout: Option<Duration>,
+    future: impl Future<Output = RedisResult<T>>,
+    timed_out: impl FnOnce() -> RedisError,
+) -> RedisResult<T> {
+    match timeout {
+        Some(timeout) => {
+            futures::pin_mut!(future);
+            match future::select(future, clock.delay(timeout)).await {
+                future::Either::Left((result, _)) => result,
+                future::Either::Right(((), _)) => Err(timed_out()),
+            }
+        }
+        None => future.await,
     }
 }
 
@@ -842,6 +2041,26 @@ pub trait Connect: Sized {
     fn connect<'a, T>(info: T) -> RedisFuture<'a, Self>
     where
         T: IntoConnectionInfo + Send + 'a;
+
+    /// Whether requests are sent with `req_packed`. Otherwise they are sent through
+    /// `ConnectionLike`, which packs them again for every attempt.
+    /// Default: false
+    fn sends_packed() -> bool {
+        false
+    }
+
+    /// Writes the bytes of `packed` as they are and returns the `count` replies after the first
+    /// `offset`, like `ConnectionLike::req_packed_commands`. A request is packed once and every
+    /// attempt of it shares the bytes. Only called if `sends_packed` returns true.
+    fn req_packed<'a>(
+        &'a mut self,
+        packed: &'a Packed,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let _ = (packed, offset, count);
+        unimplemented!("`sends_packed` returns true without `req_packed`")
+    }
 }
 
 impl Connect for redis::aio::MultiplexedConnection {
@@ -872,15 +2091,74 @@ impl Connect for redis::aio::Connection {
     }
 }
 
-fn connect_and_check<'a, T, C>(info: T) -> impl ImplRedisFuture<C> + 'a
+fn connect_and_check<'a, T, C>(info: T, metrics: &Metrics) -> impl ImplRedisFuture<C> + 'a
 where
     T: IntoConnectionInfo + Send + 'a,
     C: ConnectionLike + Connect + Send + 'static,
 {
-    C::connect(info).and_then(|mut conn| async move {
-        check_connection(&mut conn).await?;
-        Ok(conn)
-    })
+    let metrics = metrics.clone();
+    async move {
+        let info = info.into_connection_info()?;
+        let addr = match *info.addr {
+            ConnectionAddr::Tcp(ref host, port) => format!("redis://{}:{}", host, port),
+            ref addr => format!("{:?}", addr),
+        };
+        let result = C::connect(info)
+            .and_then(|mut conn| async move {
+                check_connection(&mut conn).await?;
+                Ok(conn)
+            })
+            .await;
+        metrics.observe(|metrics| match &result {
+            Ok(_) => metrics.connection_opened(&addr),
+            Err(err) => metrics.connection_failed(&addr, err),
+        });
+        result
+    }
+}
+
+async fn observe_request<C>(
+    metrics: &Metrics,
+    clock: &Time,
+    addr: &str,
+    cmd: &CmdArg<C>,
+    request: RedisFuture<'static, Response>,
+) -> RedisResult<Response> {
+    let metrics = match metrics.get() {
+        Some(metrics) => metrics,
+        None => return request.await,
+    };
+    let command = cmd.name();
+    metrics.request_started(addr, command);
+    let start = clock.now();
+    let result = request.await;
+    let latency = clock.now() - start;
+    metrics.request_finished(addr, command, latency, result.as_ref().err());
+    result
+}
+
+// Sends `cmds` over `conn` as one pipeline and returns the reply to each of them. A pipeline
+// only returns its first error, so once a command failed each of them is sent again on its own
+// for its own reply.
+async fn exec_batch<C>(conn: Lease<C>, cmds: &[CmdArg<C>]) -> Vec<RedisResult<Response>>
+where
+    C: ConnectionLike + Clone + Send + 'static,
+{
+    let result = CmdArg::batch(cmds).exec(conn.connection()).await;
+    conn.finished(&result);
+    if let Ok(Response::Multiple(values)) = result {
+        return values
+            .into_iter()
+            .map(|value| Ok(Response::Single(value)))
+            .collect();
+    }
+    let mut results = Vec::with_capacity(cmds.len());
+    for cmd in cmds {
+        let result = cmd.exec(conn.connection()).await;
+        conn.finished(&result);
+        results.push(result);
+    }
+    results
 }
 
 async fn check_connection<C>(conn: &mut C) -> RedisResult<()>
@@ -893,54 +2171,6 @@ where
     Ok(())
 }
 
-fn get_random_connection<'a, C>(
-    connections: &'a HashMap<String, C>,
-    excludes: Option<&'a HashSet<String>>,
-) -> (String, C)
-where
-    C: Clone,
-{
-    debug_assert!(!connections.is_empty());
-
-    let mut rng = thread_rng();
-    let sample = match excludes {
-        Some(excludes) if excludes.len() < connections.len() => {
-            let target_keys = connections.keys().filter(|key| !excludes.contains(*key));
-            target_keys.choose(&mut rng)
-        }
-        _ => connections.keys().choose(&mut rng),
-    };
-
-    let addr = sample.expect("No targets to choose from");
-    (addr.to_string(), connections.get(addr).unwrap().clone())
-}
-
-fn slot_for_key(key: &[u8]) -> u16 {
-    let key = sub_key(&key);
-    State::<XMODEM>::calculate(&key) % SLOT_SIZE as u16
-}
-
-// If a key contains `{` and `}`, everything between the first occurence is the only thing that
-// determines the hash slot
-fn sub_key(key: &[u8]) -> &[u8] {
-    key.iter()
-        .position(|b| *b == b'{')
-        .and_then(|open| {
-            let after_open = open + 1;
-            key[after_open..]
-                .iter()
-                .position(|b| *b == b'}')
-                .and_then(|close_offset| {
-                    if close_offset != 0 {
-                        Some(&key[after_open..after_open + close_offset])
-                    } else {
-                        None
-                    }
-                })
-        })
-        .unwrap_or(key)
-}
-
 #[derive(Debug)]
 struct Slot {
     start: u16,
@@ -956,13 +2186,6 @@ impl Slot {
     pub fn end(&self) -> u16 {
         self.end
     }
-    pub fn master(&self) -> &str {
-        &self.master
-    }
-    #[allow(dead_code)]
-    pub fn replicas(&self) -> &Vec<String> {
-        &self.replicas
-    }
 }
 
 // Get slot data from connection.
@@ -1052,10 +2275,7 @@ mod tests {
     use super::*;
 
     fn slot_for_packed_command(cmd: &[u8]) -> Option<u16> {
-        command_key(cmd).map(|key| {
-            let key = sub_key(&key);
-            State::<XMODEM>::calculate(&key) % SLOT_SIZE as u16
-        })
+        command_key(cmd).map(|key| key_slot(&key))
     }
 
     fn command_key(cmd: &[u8]) -> Option<Vec<u8>> {
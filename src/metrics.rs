@@ -0,0 +1,212 @@
+//! Hooks for observing what the cluster connection does.
+//!
+//! Implement `ClusterMetrics` and register it with `Client::set_metrics` to export the events to
+//! a monitoring system. `CountingMetrics` keeps simple counters in memory, which is mostly useful
+//! in tests.
+//!
+//! # Example
+//! ```rust,no_run
+//! use std::sync::Arc;
+//! use redis_cluster_async::{metrics::CountingMetrics, Client};
+//!
+//! #[tokio::main]
+//! async fn main() -> redis::RedisResult<()> {
+//!     let metrics = Arc::new(CountingMetrics::new());
+//!     let mut client = Client::open(vec!["redis://127.0.0.1:7000/"])?;
+//!     client.set_metrics(metrics.clone());
+//!     let mut connection = client.get_connection().await?;
+//!     let _: Option<String> = redis::cmd("GET").arg("key").query_async(&mut connection).await?;
+//!     println!("{:?}", metrics.snapshot());
+//!     Ok(())
+//! }
+//! ```
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use redis::RedisError;
+
+/// Why a node did not answer a request itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redirect {
+    /// The slot is served by the node at `to`, given as `host:port`.
+    Moved { slot: u16, to: String },
+    /// The slot is being migrated and this request should be sent to `to`.
+    Ask { slot: u16, to: String },
+    /// A multi-key request could not be served during a migration and is retried later.
+    TryAgain,
+    /// The cluster is not serving requests and the request is retried later.
+    ClusterDown,
+}
+
+/// Callbacks for the events of a cluster connection. Every method does nothing by default.
+///
+/// The callbacks are called from the task which drives the connection, so they should return
+/// quickly.
+pub trait ClusterMetrics: Send + Sync {
+    /// A request was sent to `node`. `command` is the name of the command, or `PIPELINE`.
+    fn request_started(&self, _node: &str, _command: &str) {}
+
+    /// `node` answered a request, or failed to. Redirects are errors as well.
+    fn request_finished(
+        &self,
+        _node: &str,
+        _command: &str,
+        _latency: Duration,
+        _error: Option<&RedisError>,
+    ) {
+    }
+
+    /// `node` redirected a request.
+    fn redirected(&self, _node: &str, _redirect: &Redirect) {}
+
+    /// A request failed and will be attempted again. `attempt` counts from 1.
+    fn retried(&self, _attempt: u32) {}
+
+    fn connection_opened(&self, _node: &str) {}
+
+    fn connection_failed(&self, _node: &str, _error: &RedisError) {}
+
+    /// The slot map was reloaded and is now served by `masters` masters.
+    fn slots_refreshed(&self, _masters: usize) {}
+
+    fn slots_refresh_failed(&self, _error: &RedisError) {}
+}
+
+/// The counters of a `CountingMetrics`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub requests: u64,
+    pub failed_requests: u64,
+    pub moved: u64,
+    pub ask: u64,
+    pub try_again: u64,
+    pub cluster_down: u64,
+    pub retries: u64,
+    pub connections_opened: u64,
+    pub connection_failures: u64,
+    pub slot_refreshes: u64,
+    pub slot_refresh_failures: u64,
+    /// Finished requests for each node.
+    pub requests_by_node: HashMap<String, u64>,
+    /// The summed latency of the finished requests for each node.
+    pub latency_by_node: HashMap<String, Duration>,
+    /// Finished requests for each command name.
+    pub requests_by_command: HashMap<String, u64>,
+}
+
+/// A `ClusterMetrics` which counts the events in memory.
+#[derive(Default)]
+pub struct CountingMetrics {
+    counters: Mutex<MetricsSnapshot>,
+}
+
+impl CountingMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of the current counters.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.counters.lock().unwrap().clone()
+    }
+
+    /// Sets every counter back to zero.
+    pub fn reset(&self) {
+        *self.counters.lock().unwrap() = MetricsSnapshot::default();
+    }
+
+    fn update(&self, f: impl FnOnce(&mut MetricsSnapshot)) {
+        f(&mut self.counters.lock().unwrap())
+    }
+}
+
+impl fmt::Debug for CountingMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CountingMetrics")
+            .field(&self.snapshot())
+            .finish()
+    }
+}
+
+impl ClusterMetrics for CountingMetrics {
+    fn request_finished(
+        &self,
+        node: &str,
+        command: &str,
+        latency: Duration,
+        error: Option<&RedisError>,
+    ) {
+        self.update(|counters| {
+            counters.requests += 1;
+            if error.is_some() {
+                counters.failed_requests += 1;
+            }
+            *counters
+                .requests_by_node
+                .entry(node.to_string())
+                .or_default() += 1;
+            *counters
+                .latency_by_node
+                .entry(node.to_string())
+                .or_default() += latency;
+            *counters
+                .requests_by_command
+                .entry(command.to_string())
+                .or_default() += 1;
+        })
+    }
+
+    fn redirected(&self, _node: &str, redirect: &Redirect) {
+        self.update(|counters| match redirect {
+            Redirect::Moved { .. } => counters.moved += 1,
+            Redirect::Ask { .. } => counters.ask += 1,
+            Redirect::TryAgain => counters.try_again += 1,
+            Redirect::ClusterDown => counters.cluster_down += 1,
+        })
+    }
+
+    fn retried(&self, _attempt: u32) {
+        self.update(|counters| counters.retries += 1)
+    }
+
+    fn connection_opened(&self, _node: &str) {
+        self.update(|counters| counters.connections_opened += 1)
+    }
+
+    fn connection_failed(&self, _node: &str, _error: &RedisError) {
+        self.update(|counters| counters.connection_failures += 1)
+    }
+
+    fn slots_refreshed(&self, _masters: usize) {
+        self.update(|counters| counters.slot_refreshes += 1)
+    }
+
+    fn slots_refresh_failed(&self, _error: &RedisError) {
+        self.update(|counters| counters.slot_refresh_failures += 1)
+    }
+}
+
+// The observer registered on a `Client`, if any.
+#[derive(Clone, Default)]
+pub(crate) struct Metrics(Option<Arc<dyn ClusterMetrics>>);
+
+impl Metrics {
+    pub(crate) fn new(metrics: Arc<dyn ClusterMetrics>) -> Self {
+        Metrics(Some(metrics))
+    }
+
+    pub(crate) fn get(&self) -> Option<&dyn ClusterMetrics> {
+        self.0.as_deref()
+    }
+
+    pub(crate) fn observe(&self, f: impl FnOnce(&dyn ClusterMetrics)) {
+        if let Some(metrics) = self.get() {
+            f(metrics)
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Keeps the requests sent to a slot in the order they were received.
+//!
+//! Requests to a slot are sent as they arrive, so a node receives them in order. Once one of them
+//! has to be retried the slot is serialized: the requests which are still in flight are waited
+//! for, then the ones which failed or arrived meanwhile are sent one at a time, oldest first.
+//! Redirects, `TRYAGAIN` and refreshes can therefore not let a request overtake an older one to
+//! the same slot. Requests sent after the first failure which the node applied anyway, such as a
+//! request for another key during a migration, can not be undone and keep their place.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use redis::aio::ConnectionLike;
+
+use crate::{Connect, PendingRequest, Pipeline, Request, RequestState};
+
+// The requests of a slot which are not yet answered
+pub(crate) struct SlotQueue<C> {
+    in_flight: usize,
+    // Set once a request has to be retried, until every request of the slot is answered
+    serialized: bool,
+    // Sent one at a time once `in_flight` is 0, keyed by the order they were received in
+    waiting: BTreeMap<u64, (Request<C>, Resend)>,
+}
+
+impl<C> Default for SlotQueue<C> {
+    fn default() -> Self {
+        SlotQueue {
+            in_flight: 0,
+            serialized: false,
+            waiting: BTreeMap::new(),
+        }
+    }
+}
+
+// When a request is sent again
+pub(crate) enum Resend {
+    Now,
+    After(Duration),
+    // Once the slot map has been refreshed
+    AfterRefresh,
+}
+
+impl<C> Pipeline<C>
+where
+    C: ConnectionLike + Connect + Clone + Send + 'static,
+{
+    // Sends a request which was just received, unless its slot is serialized
+    pub(crate) fn dispatch(&mut self, request: Request<C>) {
+        let slot = match request.ordered_slot {
+            Some(slot) => slot,
+            None => return self.send_request(request),
+        };
+        let queue = self.slot_queues.entry(slot).or_default();
+        if !queue.serialized {
+            queue.in_flight += 1;
+            self.send_request(request);
+        } else {
+            queue.waiting.insert(request.id, (request, Resend::Now));
+        }
+    }
+
+    // Called with every request whose attempt finished, before it is retried or dropped
+    pub(crate) fn finished_attempt(&mut self, request: &Request<C>) {
+        if let Some(slot) = request.ordered_slot {
+            if let Some(queue) = self.slot_queues.get_mut(&slot) {
+                queue.in_flight -= 1;
+            }
+        }
+    }
+
+    // Sends the next waiting request of `slot` if nothing else of the slot is in flight
+    pub(crate) fn advance_slot(&mut self, slot: u16) {
+        let queue = match self.slot_queues.get_mut(&slot) {
+            Some(queue) => queue,
+            None => return,
+        };
+        if queue.in_flight > 0 {
+            return;
+        }
+        let id = match queue.waiting.iter().next() {
+            Some((_, (_, Resend::AfterRefresh))) => return,
+            Some((&id, _)) => id,
+            None => {
+                self.slot_queues.remove(&slot);
+                return;
+            }
+        };
+        let (request, resend) = queue.waiting.remove(&id).unwrap();
+        queue.in_flight = 1;
+        self.resend(request, resend);
+    }
+
+    // Sends the requests which waited for a refresh to finish
+    pub(crate) fn advance_slots(&mut self) {
+        for queue in self.slot_queues.values_mut() {
+            for (_, resend) in queue.waiting.values_mut() {
+                if let Resend::AfterRefresh = resend {
+                    *resend = Resend::Now;
+                }
+            }
+        }
+        let slots = self.slot_queues.keys().copied().collect::<Vec<_>>();
+        for slot in slots {
+            self.advance_slot(slot);
+        }
+    }
+
+    pub(crate) fn retry(&mut self, request: Request<C>, resend: Resend) {
+        match request.ordered_slot {
+            Some(slot) => {
+                let queue = self.slot_queues.entry(slot).or_default();
+                queue.serialized = true;
+                queue.waiting.insert(request.id, (request, resend));
+                self.advance_slot(slot);
+            }
+            None => self.resend(request, resend),
+        }
+    }
+
+    fn resend(&mut self, request: Request<C>, resend: Resend) {
+        match resend {
+            Resend::Now => self.send_request(request),
+            Resend::After(duration) => {
+                let delay = self.params.clock.delay(duration);
+                self.in_flight_requests.push(PendingRequest {
+                    request: Some(request),
+                    state: RequestState::Delay(delay),
+                });
+            }
+            Resend::AfterRefresh => self.queued_requests.push(request),
+        }
+    }
+}
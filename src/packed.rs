@@ -0,0 +1,73 @@
+//! Commands which are packed once for every attempt of a request.
+
+use std::{str, sync::Arc};
+
+/// Commands packed into the bytes which redis receives, see `Connect::req_packed`.
+///
+/// A request is packed once when it is sent. Its attempts share the bytes, including the
+/// attempts which follow `ASKING` and the ones sent together with other requests.
+#[derive(Clone, Debug)]
+pub struct Packed {
+    chunks: Vec<Arc<Vec<u8>>>,
+}
+
+impl Packed {
+    pub(crate) fn new(bytes: Vec<u8>) -> Self {
+        Packed {
+            chunks: vec![Arc::new(bytes)],
+        }
+    }
+
+    /// The bytes to write, in order.
+    pub fn chunks(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        self.chunks.iter().map(|chunk| &chunk[..])
+    }
+
+    // The commands of every one of `packed`, in order
+    pub(crate) fn join<'a>(packed: impl IntoIterator<Item = &'a Packed>) -> Self {
+        Packed {
+            chunks: packed
+                .into_iter()
+                .flat_map(|packed| packed.chunks.iter().cloned())
+                .collect(),
+        }
+    }
+
+    // `ASKING` followed by the commands
+    pub(crate) fn asking(&self) -> Self {
+        let asking = Packed::new(b"*1\r\n$6\r\nASKING\r\n".to_vec());
+        Packed::join(vec![&asking, self])
+    }
+
+    // The name of the first command, read from `*<args>\r\n$<length>\r\n<name>\r\n`
+    pub(crate) fn name(&self) -> Option<&str> {
+        let mut lines = self.chunks.first()?.splitn(3, |&byte| byte == b'\n');
+        lines.next()?;
+        let length = lines.next()?.strip_prefix(b"$")?.strip_suffix(b"\r")?;
+        let length = str::from_utf8(length).ok()?.parse::<usize>().ok()?;
+        str::from_utf8(lines.next()?.get(..length)?).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn asking_shares_the_command() {
+        let packed = Packed::new(redis::cmd("GET").arg("b").get_packed_command());
+        assert_eq!(packed.name(), Some("GET"));
+
+        let asking = packed.asking();
+        assert_eq!(asking.name(), Some("ASKING"));
+        assert!(Arc::ptr_eq(&asking.chunks[1], &packed.chunks[0]));
+        assert_eq!(
+            asking.chunks().collect::<Vec<_>>().concat(),
+            redis::pipe()
+                .cmd("ASKING")
+                .cmd("GET")
+                .arg("b")
+                .get_packed_pipeline()
+        );
+    }
+}
@@ -0,0 +1,275 @@
+//! The connections to a node.
+//!
+//! A node can be served by several connections so that a slow reply on one of them does not hold
+//! up the requests sent over the others. Each attempt leases a connection, which counts it as in
+//! flight until the attempt finishes. A connection whose attempt failed with an I/O error is no
+//! longer picked and is reconnected on its own, while the others of the node go on.
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
+use redis::{RedisError, RedisResult};
+
+/// How a node connection is picked for each request when `Client::set_pool_size` opens more
+/// than one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PoolStrategy {
+    /// The connection with the fewest requests in flight, the first one on a tie.
+    #[default]
+    LeastLoaded,
+    /// Each connection in turn.
+    RoundRobin,
+}
+
+// Shared by a pooled connection and the attempts sent over it
+#[derive(Debug, Default)]
+pub(crate) struct Health {
+    in_flight: AtomicUsize,
+    broken: AtomicBool,
+    reconnecting: AtomicBool,
+}
+
+impl Health {
+    pub(crate) fn is_broken(&self) -> bool {
+        self.broken.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn reconnect_failed(&self) {
+        self.reconnecting.store(false, Ordering::Relaxed);
+    }
+}
+
+// A connection picked for an attempt, counted as in flight until it is dropped
+pub(crate) struct Lease<C> {
+    conn: C,
+    health: Arc<Health>,
+}
+
+impl<C: Clone> Lease<C> {
+    // A connection which is not part of a pool
+    pub(crate) fn unpooled(conn: C) -> Self {
+        Self::new(conn, Arc::default())
+    }
+
+    fn new(conn: C, health: Arc<Health>) -> Self {
+        health.in_flight.fetch_add(1, Ordering::Relaxed);
+        Lease { conn, health }
+    }
+
+    pub(crate) fn connection(&self) -> C {
+        self.conn.clone()
+    }
+
+    // Records the result of the attempt sent over the connection
+    pub(crate) fn finished<T>(&self, result: &RedisResult<T>) {
+        if let Err(err) = result {
+            if is_broken(err) {
+                self.health.broken.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// Another lease of the same connection, for a request which is sent together with the others
+impl<C: Clone> Clone for Lease<C> {
+    fn clone(&self) -> Self {
+        Self::new(self.conn.clone(), self.health.clone())
+    }
+}
+
+impl<C> Drop for Lease<C> {
+    fn drop(&mut self) {
+        self.health.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Errors after which a connection is not used again
+pub(crate) fn is_broken(err: &RedisError) -> bool {
+    err.is_io_error() || err.is_connection_dropped() || err.is_connection_refusal()
+}
+
+struct Pooled<C> {
+    conn: C,
+    health: Arc<Health>,
+}
+
+// Clones share the health of the connections, so that the attempts leased from either are counted
+pub(crate) struct NodePool<C> {
+    connections: Vec<Pooled<C>>,
+    // The connection which `RoundRobin` tries first
+    next: AtomicUsize,
+}
+
+impl<C: Clone> Clone for NodePool<C> {
+    fn clone(&self) -> Self {
+        NodePool {
+            connections: self
+                .connections
+                .iter()
+                .map(|pooled| Pooled {
+                    conn: pooled.conn.clone(),
+                    health: pooled.health.clone(),
+                })
+                .collect(),
+            next: AtomicUsize::new(self.next.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl<C: Clone> NodePool<C> {
+    pub(crate) fn new(conn: C) -> Self {
+        Self::from_connections(vec![(conn, Arc::default())]).unwrap()
+    }
+
+    // `None` if there are no connections
+    pub(crate) fn from_connections(connections: Vec<(C, Arc<Health>)>) -> Option<Self> {
+        if connections.is_empty() {
+            return None;
+        }
+        Some(NodePool {
+            connections: connections
+                .into_iter()
+                .map(|(conn, health)| Pooled { conn, health })
+                .collect(),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    // Takes the connections out to check them during a refresh
+    pub(crate) fn into_connections(self) -> Vec<(C, Arc<Health>)> {
+        self.connections
+            .into_iter()
+            .map(|pooled| (pooled.conn, pooled.health))
+            .collect()
+    }
+
+    // The attempts in flight over every connection of the node
+    pub(crate) fn in_flight(&self) -> usize {
+        self.connections
+            .iter()
+            .map(|pooled| pooled.health.in_flight.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    // Picks a connection which is not broken, or any connection if they all are
+    pub(crate) fn lease(&self, strategy: PoolStrategy) -> Lease<C> {
+        let len = self.connections.len();
+        let start = match strategy {
+            PoolStrategy::LeastLoaded => 0,
+            PoolStrategy::RoundRobin => self.next.load(Ordering::Relaxed) % len,
+        };
+        let mut candidates = (start..start + len).map(|i| &self.connections[i % len]);
+        let picked = match strategy {
+            PoolStrategy::LeastLoaded => candidates
+                .filter(|pooled| !pooled.health.is_broken())
+                .min_by_key(|pooled| pooled.health.in_flight.load(Ordering::Relaxed)),
+            PoolStrategy::RoundRobin => candidates.find(|pooled| !pooled.health.is_broken()),
+        };
+        let picked = picked.unwrap_or(&self.connections[start]);
+        if let PoolStrategy::RoundRobin = strategy {
+            let index = self
+                .connections
+                .iter()
+                .position(|pooled| Arc::ptr_eq(&pooled.health, &picked.health))
+                .unwrap();
+            self.next.store(index + 1, Ordering::Relaxed);
+        }
+        Lease::new(picked.conn.clone(), picked.health.clone())
+    }
+
+    // The broken connections which are not yet being reconnected. They are marked as being
+    // reconnected, until they are given to `replace` or `Health::reconnect_failed`.
+    pub(crate) fn take_broken(&self) -> Vec<Arc<Health>> {
+        self.connections
+            .iter()
+            .filter(|pooled| {
+                pooled.health.is_broken()
+                    && !pooled.health.reconnecting.swap(true, Ordering::Relaxed)
+            })
+            .map(|pooled| pooled.health.clone())
+            .collect()
+    }
+
+    // Puts `conn` in place of the connection which `health` belonged to
+    pub(crate) fn replace(&mut self, health: &Arc<Health>, conn: C) {
+        health.reconnecting.store(false, Ordering::Relaxed);
+        if let Some(pooled) = self
+            .connections
+            .iter_mut()
+            .find(|pooled| Arc::ptr_eq(&pooled.health, health))
+        {
+            *pooled = Pooled {
+                conn,
+                health: Arc::default(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+
+    fn pool(size: usize) -> NodePool<usize> {
+        NodePool::from_connections((0..size).map(|conn| (conn, Arc::default())).collect()).unwrap()
+    }
+
+    fn broken() -> RedisResult<()> {
+        Err(io::Error::from(io::ErrorKind::ConnectionReset).into())
+    }
+
+    #[test]
+    fn least_loaded() {
+        let pool = pool(3);
+        let first = pool.lease(PoolStrategy::LeastLoaded);
+        let second = pool.lease(PoolStrategy::LeastLoaded);
+        assert_eq!((first.connection(), second.connection()), (0, 1));
+        drop(first);
+        assert_eq!(pool.lease(PoolStrategy::LeastLoaded).connection(), 0);
+
+        second.finished(&broken());
+        let leases = (0..4)
+            .map(|_| pool.lease(PoolStrategy::LeastLoaded))
+            .collect::<Vec<_>>();
+        let picked = leases.iter().map(Lease::connection).collect::<Vec<_>>();
+        assert_eq!(picked, vec![0, 2, 0, 2]);
+    }
+
+    #[test]
+    fn round_robin() {
+        let mut pool = pool(3);
+        let picked = (0..4)
+            .map(|_| pool.lease(PoolStrategy::RoundRobin).connection())
+            .collect::<Vec<_>>();
+        assert_eq!(picked, vec![0, 1, 2, 0]);
+
+        pool.lease(PoolStrategy::RoundRobin).finished(&broken());
+        let picked = (0..3)
+            .map(|_| pool.lease(PoolStrategy::RoundRobin).connection())
+            .collect::<Vec<_>>();
+        assert_eq!(picked, vec![2, 0, 2]);
+
+        let broken = pool.take_broken();
+        assert_eq!(broken.len(), 1);
+        assert!(pool.take_broken().is_empty());
+        pool.replace(&broken[0], 3);
+        let picked = (0..3)
+            .map(|_| pool.lease(PoolStrategy::RoundRobin).connection())
+            .collect::<Vec<_>>();
+        assert_eq!(picked, vec![0, 3, 2]);
+    }
+
+    #[test]
+    fn all_broken() {
+        let pool = pool(2);
+        for _ in 0..2 {
+            pool.lease(PoolStrategy::LeastLoaded).finished(&broken());
+        }
+        assert_eq!(pool.lease(PoolStrategy::LeastLoaded).connection(), 0);
+        assert_eq!(pool.lease(PoolStrategy::RoundRobin).connection(), 0);
+    }
+}
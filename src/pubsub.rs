@@ -0,0 +1,324 @@
+//! Pattern subscriptions which span every master of the cluster.
+//!
+//! Keyspace notifications are only published on the node which owns the key, so watching them
+//! for the whole cluster requires a `PSUBSCRIBE` on every master.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    marker::PhantomData,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::{
+    channel::mpsc,
+    future::{self, AbortHandle},
+    prelude::*,
+    task::{self, Poll},
+};
+use log::trace;
+use redis::{
+    aio::ConnectionLike, Cmd, ConnectionInfo, FromRedisValue, IntoConnectionInfo, RedisResult,
+    Value,
+};
+
+use crate::{with_timeout, ClusterParams, Connect, Pipeline};
+
+// How often the slot map is checked for masters which were added or removed.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+// Delay before resubscribing after a subscription connection was lost.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_millis(100);
+
+/// A message published on one of the cluster's masters.
+#[derive(Debug, Clone)]
+pub struct KeyspaceMessage {
+    node: String,
+    pattern: String,
+    channel: String,
+    payload: Value,
+}
+
+impl KeyspaceMessage {
+    fn from_value(node: &str, value: Value) -> Option<Self> {
+        let mut items = match value {
+            Value::Bulk(items) if items.len() == 4 => items.into_iter(),
+            _ => return None,
+        };
+        let kind = items.next()?;
+        if !matches!(&kind, Value::Data(kind) if kind == b"pmessage") {
+            return None;
+        }
+        let pattern = String::from_redis_value(&items.next()?).ok()?;
+        let channel = String::from_redis_value(&items.next()?).ok()?;
+        Some(KeyspaceMessage {
+            node: node.to_string(),
+            pattern,
+            channel,
+            payload: items.next()?,
+        })
+    }
+
+    /// The address of the master which published the message.
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// The pattern which matched the channel.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// The channel the message was published on, for instance `__keyspace@0__:mykey`.
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    /// Converts the payload into the requested type.
+    pub fn payload<T: FromRedisValue>(&self) -> RedisResult<T> {
+        T::from_redis_value(&self.payload)
+    }
+
+    /// Returns the raw bytes of the payload.
+    pub fn payload_bytes(&self) -> &[u8] {
+        match &self.payload {
+            Value::Data(bytes) => bytes,
+            _ => b"",
+        }
+    }
+}
+
+/// A stream of the messages received from every master in the cluster.
+///
+/// The stream is subscribed on every reachable master once it is returned. Subscriptions are then
+/// added and removed in the background as masters join or leave the cluster. Dropping the stream
+/// unsubscribes from all nodes.
+pub struct KeyspaceEvents {
+    receiver: mpsc::Receiver<KeyspaceMessage>,
+    abort: AbortHandle,
+}
+
+impl Stream for KeyspaceEvents {
+    type Item = KeyspaceMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+impl Drop for KeyspaceEvents {
+    fn drop(&mut self) {
+        self.abort.abort();
+    }
+}
+
+// `C` connects the slot map and `S` the subscriptions, which need a connection of their own
+struct Subscriptions<C, S> {
+    initial_nodes: Vec<ConnectionInfo>,
+    params: ClusterParams,
+    pipeline: Pipeline<C>,
+    patterns: Arc<Vec<String>>,
+    nodes: HashMap<String, AbortHandle>,
+    messages: mpsc::Sender<KeyspaceMessage>,
+    closed: mpsc::UnboundedSender<String>,
+    subscriber: PhantomData<fn() -> S>,
+}
+
+impl<C, S> Drop for Subscriptions<C, S> {
+    fn drop(&mut self) {
+        for (_, handle) in self.nodes.drain() {
+            handle.abort();
+        }
+    }
+}
+
+impl<C, S> Subscriptions<C, S>
+where
+    C: ConnectionLike + Connect + Clone + Send + 'static,
+    S: ConnectionLike + Connect + Send + 'static,
+{
+    fn masters(&self) -> HashSet<String> {
+        self.pipeline
+            .slots
+            .nodes()
+            .map(|addrs| addrs.master.clone())
+            .collect()
+    }
+
+    // Subscribe to masters which are new and unsubscribe from those which are gone.
+    async fn reconcile(&mut self) {
+        let masters = self.masters();
+
+        let patterns = &self.patterns;
+        self.nodes.retain(|addr, handle| {
+            let keep = masters.contains(addr);
+            if !keep {
+                trace!("Unsubscribing from {} ({:?})", addr, patterns);
+                handle.abort();
+            }
+            keep
+        });
+
+        let subscribed = future::join_all(
+            masters
+                .into_iter()
+                .filter(|addr| !self.nodes.contains_key(addr))
+                .map(|addr| {
+                    let patterns = self.patterns.clone();
+                    let info = node_info(&addr, self.initial_nodes.first());
+                    let params = &self.params;
+                    let subscribe = async move { subscribe_node::<S>(info?, &patterns).await };
+                    let subscribe = with_timeout(&params.clock, params.timeout, subscribe, || {
+                        io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "redis_cluster: Subscribing timed out",
+                        )
+                        .into()
+                    });
+                    subscribe.map(|result| (addr, result))
+                }),
+        )
+        .await;
+
+        for (addr, result) in subscribed {
+            let conn = match result {
+                Ok(conn) => conn,
+                Err(err) => {
+                    // Retried on the next refresh
+                    trace!("Unable to subscribe to {}: {}", addr, err);
+                    continue;
+                }
+            };
+            trace!("Subscribed to {} ({:?})", addr, self.patterns);
+            let (listen, handle) =
+                future::abortable(listen_node::<S>(addr.clone(), conn, self.messages.clone()));
+            let closed = self.closed.clone();
+            let node = addr.clone();
+            tokio::spawn(async move {
+                if let Ok(result) = listen.await {
+                    if let Err(err) = result {
+                        trace!("Subscription to {} failed: {}", node, err);
+                    }
+                    let _ = closed.unbounded_send(node);
+                }
+            });
+            self.nodes.insert(addr, handle);
+        }
+    }
+
+    async fn refresh(&mut self) {
+        let result = match self.pipeline.refresh_slots("subscriptions").await {
+            Ok(result) => Ok(result),
+            // None of the known nodes may be left, so start over from the initial nodes.
+            Err(_) => match Pipeline::new(&self.initial_nodes, self.params.clone()).await {
+                Ok(pipeline) => Ok((pipeline.slots, pipeline.connections)),
+                Err(err) => Err(err),
+            },
+        };
+        match result {
+            Ok((slots, connections)) => {
+                self.pipeline.slots = slots;
+                self.pipeline.connections = connections;
+                self.reconcile().await;
+            }
+            Err(err) => trace!("Unable to refresh the subscribed masters: {}", err),
+        }
+    }
+
+    async fn run(mut self, mut closed: mpsc::UnboundedReceiver<String>) {
+        loop {
+            let delay = self.params.clock.delay(REFRESH_INTERVAL);
+            match future::select(closed.next(), delay).await {
+                future::Either::Left((Some(addr), _)) => {
+                    self.nodes.remove(&addr);
+                    self.params.clock.delay(RESUBSCRIBE_DELAY).await;
+                }
+                future::Either::Left((None, _)) => return,
+                future::Either::Right(_) => (),
+            }
+            if self.messages.is_closed() {
+                return;
+            }
+            self.refresh().await;
+        }
+    }
+}
+
+pub(crate) async fn subscribe<C, S>(
+    initial_nodes: &[ConnectionInfo],
+    params: ClusterParams,
+    patterns: Vec<String>,
+) -> RedisResult<KeyspaceEvents>
+where
+    C: ConnectionLike + Connect + Clone + Send + 'static,
+    S: ConnectionLike + Connect + Send + 'static,
+{
+    let pipeline = Pipeline::<C>::new(initial_nodes, params.clone()).await?;
+    let (messages, receiver) = mpsc::channel(100);
+    let (closed, closed_receiver) = mpsc::unbounded();
+
+    let mut subscriptions = Subscriptions::<C, S> {
+        initial_nodes: initial_nodes.to_vec(),
+        params,
+        pipeline,
+        patterns: Arc::new(patterns),
+        nodes: HashMap::new(),
+        messages,
+        closed,
+        subscriber: PhantomData,
+    };
+    subscriptions.reconcile().await;
+
+    let (run, abort) = future::abortable(subscriptions.run(closed_receiver));
+    tokio::spawn(run.map(|_| ()));
+
+    Ok(KeyspaceEvents { receiver, abort })
+}
+
+// The settings of the initial node, such as the password and database, for the node at `addr`
+fn node_info(addr: &str, initial: Option<&ConnectionInfo>) -> RedisResult<ConnectionInfo> {
+    let mut info = addr.into_connection_info()?;
+    if let Some(initial) = initial {
+        info.db = initial.db;
+        info.passwd = initial.passwd.clone();
+    }
+    Ok(info)
+}
+
+async fn subscribe_node<S>(info: ConnectionInfo, patterns: &[String]) -> RedisResult<S>
+where
+    S: ConnectionLike + Connect,
+{
+    let mut conn = S::connect(info).await?;
+
+    let mut cmd = Cmd::new();
+    cmd.arg("PSUBSCRIBE").arg(patterns);
+    // Each pattern is confirmed with its own reply
+    let mut subscribe = redis::pipe();
+    subscribe.add_command(cmd);
+    conn.req_packed_commands(&subscribe, 0, patterns.len())
+        .await?;
+    Ok(conn)
+}
+
+async fn listen_node<S: ConnectionLike>(
+    addr: String,
+    mut conn: S,
+    mut messages: mpsc::Sender<KeyspaceMessage>,
+) -> RedisResult<()> {
+    // An empty pipeline writes nothing and just reads the next message pushed by the server
+    let read = redis::pipe();
+    loop {
+        let value = conn
+            .req_packed_commands(&read, 0, 1)
+            .await?
+            .pop()
+            .unwrap_or(Value::Nil);
+        if let Some(msg) = KeyspaceMessage::from_value(&addr, value) {
+            if messages.send(msg).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
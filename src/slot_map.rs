@@ -0,0 +1,193 @@
+//! The nodes which serve each hash slot.
+//!
+//! The ranges returned by `CLUSTER SLOTS` are kept for the topology views, while requests are
+//! routed with a table holding the index of the nodes of every slot.
+
+use std::iter::FromIterator;
+
+use redis::{ErrorKind, RedisError, RedisResult};
+
+use crate::{Slot, SLOT_SIZE};
+
+// The entry of the slots which no range covers
+const NO_NODE: u16 = u16::MAX;
+
+// The nodes which serve a range of slots
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SlotAddrs {
+    pub(crate) master: String,
+    pub(crate) replicas: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct SlotMap {
+    // `(start, end, node)` ordered by `start`, where `node` indexes `nodes`
+    ranges: Vec<(u16, u16, u16)>,
+    // Every distinct set of nodes, in the order they first appear in `ranges`
+    nodes: Vec<SlotAddrs>,
+    // The index in `nodes` of each slot. Empty if there are no ranges.
+    table: Vec<u16>,
+}
+
+impl SlotMap {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    // Builds the map of `CLUSTER SLOTS`, which has to cover every slot exactly once
+    pub(crate) fn from_slots(mut slots_data: Vec<Slot>) -> RedisResult<Self> {
+        slots_data.sort_by_key(|slot_data| slot_data.start);
+        let last_slot = slots_data.iter().try_fold(0, |prev_end, slot_data| {
+            if prev_end != slot_data.start() {
+                return Err(RedisError::from((
+                    ErrorKind::ResponseError,
+                    "Slot refresh error.",
+                    format!(
+                        "Received overlapping slots {} and {}..{}",
+                        prev_end, slot_data.start, slot_data.end
+                    ),
+                )));
+            }
+            Ok(slot_data.end() + 1)
+        })?;
+
+        if usize::from(last_slot) != SLOT_SIZE {
+            return Err(RedisError::from((
+                ErrorKind::ResponseError,
+                "Slot refresh error.",
+                format!("Lacks the slots >= {}", last_slot),
+            )));
+        }
+        Ok(slots_data
+            .into_iter()
+            .map(|slot_data| {
+                let addrs = SlotAddrs {
+                    master: slot_data.master,
+                    replicas: slot_data.replicas,
+                };
+                ((slot_data.start, slot_data.end), addrs)
+            })
+            .collect())
+    }
+
+    pub(crate) fn get(&self, slot: u16) -> Option<&SlotAddrs> {
+        match self.table.get(usize::from(slot)) {
+            Some(&node) if node != NO_NODE => Some(&self.nodes[usize::from(node)]),
+            _ => None,
+        }
+    }
+
+    // The ranges `start..=end` and their nodes, ordered by `start`
+    pub(crate) fn ranges(&self) -> impl Iterator<Item = (u16, u16, &SlotAddrs)> {
+        self.ranges
+            .iter()
+            .map(move |&(start, end, node)| (start, end, &self.nodes[usize::from(node)]))
+    }
+
+    // Every distinct set of nodes which serves a range
+    pub(crate) fn nodes(&self) -> impl Iterator<Item = &SlotAddrs> {
+        self.nodes.iter()
+    }
+}
+
+// Ranges which overlap are served by the one which comes last
+impl FromIterator<((u16, u16), SlotAddrs)> for SlotMap {
+    fn from_iter<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = ((u16, u16), SlotAddrs)>,
+    {
+        let mut map = SlotMap::new();
+        for ((start, end), addrs) in iter {
+            let node = match map.nodes.iter().position(|node| *node == addrs) {
+                Some(node) => node as u16,
+                None => {
+                    map.nodes.push(addrs);
+                    (map.nodes.len() - 1) as u16
+                }
+            };
+            if map.table.is_empty() {
+                map.table = vec![NO_NODE; SLOT_SIZE];
+            }
+            for entry in &mut map.table[usize::from(start)..=usize::from(end)] {
+                *entry = node;
+            }
+            map.ranges.push((start, end, node));
+        }
+        map.ranges.sort_by_key(|&(start, ..)| start);
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::{prelude::*, proptest};
+
+    // Splits the slots into ranges at `cuts`, each served by one of three masters
+    fn cluster_slots(cuts: &[u16], masters: &[usize]) -> Vec<Slot> {
+        let mut bounds = cuts.to_vec();
+        bounds.sort();
+        bounds.dedup();
+        let starts = Some(0).into_iter().chain(bounds.iter().map(|cut| cut + 1));
+        let ends = bounds.iter().copied().chain(Some(SLOT_SIZE as u16 - 1));
+        starts
+            .zip(ends)
+            .zip(masters.iter().cycle())
+            .map(|((start, end), master)| Slot {
+                start,
+                end,
+                master: format!("redis://node:{}", 6379 + master),
+                replicas: vec![format!("redis://node:{}", 7379 + master)],
+            })
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn table_matches_cluster_slots(
+            cuts in proptest::collection::vec(0..SLOT_SIZE as u16 - 1, 0..20),
+            masters in proptest::collection::vec(0..3usize, 1..21),
+        ) {
+            let mut slots = cluster_slots(&cuts, &masters);
+            let expected = slots
+                .iter()
+                .map(|slot| (slot.start, slot.end, slot.master.clone(), slot.replicas.clone()))
+                .collect::<Vec<_>>();
+            // `CLUSTER SLOTS` does not order the ranges
+            slots.reverse();
+            let map = SlotMap::from_slots(slots).unwrap();
+
+            for slot in 0..SLOT_SIZE as u16 {
+                let range = expected
+                    .iter()
+                    .find(|(start, end, ..)| (*start..=*end).contains(&slot))
+                    .unwrap();
+                let addrs = map.get(slot).unwrap();
+                prop_assert_eq!(&addrs.master, &range.2);
+                prop_assert_eq!(&addrs.replicas, &range.3);
+            }
+            let ranges = map
+                .ranges()
+                .map(|(start, end, addrs)| {
+                    (start, end, addrs.master.clone(), addrs.replicas.clone())
+                })
+                .collect::<Vec<_>>();
+            prop_assert_eq!(ranges, expected);
+            prop_assert!(map.nodes().count() <= 3);
+        }
+    }
+
+    #[test]
+    fn incomplete_cluster_slots() {
+        let mut slots = cluster_slots(&[99, 199], &[0, 1, 2]);
+        slots.remove(1);
+        assert!(SlotMap::from_slots(slots).is_err());
+
+        let mut slots = cluster_slots(&[], &[0]);
+        slots[0].end = 100;
+        assert!(SlotMap::from_slots(slots).is_err());
+
+        assert_eq!(SlotMap::new().get(0), None);
+    }
+}
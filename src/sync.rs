@@ -0,0 +1,171 @@
+//! A blocking interface to a Redis cluster.
+//!
+//! `ClusterConnection` drives the same request pipeline as the async `Connection` on a runtime
+//! of its own, so it can be used from code which does not run inside tokio. It implements
+//! `redis::ConnectionLike` and therefore `redis::Commands`.
+//!
+//! # Example
+//! ```rust,no_run
+//! use redis_cluster_async::{Client, redis::Commands};
+//!
+//! let nodes = vec!["redis://127.0.0.1:7000/", "redis://127.0.0.1:7001/", "redis://127.0.0.1:7002/"];
+//! let client = Client::open(nodes).unwrap();
+//! let mut connection = client.get_sync_connection().unwrap();
+//! let () = connection.set("test", "test_data").unwrap();
+//! let res: String = connection.get("test").unwrap();
+//! assert_eq!(res, "test_data");
+//! ```
+
+use std::io;
+
+use redis::{aio, Cmd, ConnectionInfo, ErrorKind, RedisError, RedisResult, Value};
+use tokio::runtime::Runtime;
+
+use crate::{ClusterParams, Connect, Connection};
+
+/// This is a blocking connection of Redis cluster.
+pub struct ClusterConnection<C = redis::aio::MultiplexedConnection> {
+    runtime: Runtime,
+    connection: Connection<C>,
+}
+
+impl<C> ClusterConnection<C>
+where
+    C: aio::ConnectionLike + Connect + Clone + Send + Unpin + 'static,
+{
+    pub(crate) fn new(
+        initial_nodes: &[ConnectionInfo],
+        params: ClusterParams,
+    ) -> RedisResult<ClusterConnection<C>> {
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_io()
+            .enable_time()
+            .build()?;
+        let connection = runtime.block_on(Connection::new(initial_nodes, params))?;
+        Ok(ClusterConnection {
+            runtime,
+            connection,
+        })
+    }
+}
+
+impl<C> redis::ConnectionLike for ClusterConnection<C>
+where
+    C: aio::ConnectionLike + Send + 'static,
+{
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        let cmd = parse_commands(cmd)?
+            .pop()
+            .ok_or_else(|| invalid_command("Expected a command"))?;
+        let connection = &mut self.connection;
+        self.runtime
+            .block_on(aio::ConnectionLike::req_packed_command(connection, &cmd))
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        let mut cmds = parse_commands(cmd)?;
+
+        let mut pipeline = redis::pipe();
+        // Transactions arrive wrapped in MULTI/EXEC which the async pipeline adds by itself
+        if is_named(cmds.first(), b"MULTI") && is_named(cmds.last(), b"EXEC") {
+            cmds.pop();
+            cmds.remove(0);
+            pipeline.atomic();
+        }
+        for cmd in cmds {
+            pipeline.add_command(cmd);
+        }
+
+        let connection = &mut self.connection;
+        self.runtime
+            .block_on(aio::ConnectionLike::req_packed_commands(
+                connection, &pipeline, offset, count,
+            ))
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+
+    fn check_connection(&mut self) -> bool {
+        redis::cmd("PING").query::<String>(self).is_ok()
+    }
+
+    fn is_open(&self) -> bool {
+        !self.connection.sender.is_closed()
+    }
+}
+
+fn invalid_command(detail: &str) -> RedisError {
+    RedisError::from((
+        ErrorKind::ClientError,
+        "redis_cluster: Invalid packed command",
+        detail.to_string(),
+    ))
+}
+
+fn is_named(cmd: Option<&Cmd>, name: &[u8]) -> bool {
+    match cmd.and_then(|cmd| cmd.args_iter().next()) {
+        Some(redis::Arg::Simple(arg)) => arg.eq_ignore_ascii_case(name),
+        _ => false,
+    }
+}
+
+// The sync `ConnectionLike` trait only gives us the encoded commands so they are decoded back into
+// `Cmd`s, which is what the cluster routing works with.
+fn parse_commands(bytes: &[u8]) -> RedisResult<Vec<Cmd>> {
+    let mut reader = io::Cursor::new(bytes);
+    let mut cmds = Vec::new();
+    while (reader.position() as usize) < bytes.len() {
+        let args = match redis::Parser::new(&mut reader).parse_value()? {
+            Value::Bulk(args) => args,
+            _ => return Err(invalid_command("Expected an array of arguments")),
+        };
+        let mut cmd = Cmd::new();
+        for arg in args {
+            match arg {
+                Value::Data(arg) => {
+                    cmd.arg(&arg[..]);
+                }
+                _ => return Err(invalid_command("Expected a bulk string argument")),
+            }
+        }
+        cmds.push(cmd);
+    }
+    Ok(cmds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_packed_pipeline() {
+        let mut pipeline = redis::pipe();
+        pipeline
+            .cmd("SET")
+            .arg("key")
+            .arg(&b"\r\nbinary"[..])
+            .cmd("GET")
+            .arg("key");
+        let cmds = parse_commands(&pipeline.get_packed_pipeline()).unwrap();
+        assert_eq!(cmds.len(), 2);
+        assert_eq!(
+            cmds[0].get_packed_command(),
+            redis::cmd("SET")
+                .arg("key")
+                .arg(&b"\r\nbinary"[..])
+                .get_packed_command()
+        );
+        assert_eq!(
+            cmds[1].get_packed_command(),
+            redis::cmd("GET").arg("key").get_packed_command()
+        );
+    }
+}
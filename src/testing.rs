@@ -0,0 +1,311 @@
+//! An in-process mock cluster for testing code which uses a cluster connection, enabled with the
+//! `testing` feature.
+//!
+//! Mock nodes are addressed as `redis://<name>:<port>`. Every node of a cluster shares the
+//! handler registered under `<name>`, which receives each packed command together with the port
+//! of the node it was sent to. A handler answers a command by returning `Err(response)`, which
+//! lets helpers such as `MockSlots::respond` be chained with `?`.
+//!
+//! `Scenario` scripts the failures of each node of a mock cluster and records which node received
+//! each request, and `VirtualClock` skips the waits of the retries. `SimCluster` instead runs
+//! nodes which listen on localhost, to test the real connections.
+//!
+//! # Example
+//! ```rust
+//! use redis_cluster_async::{
+//!     redis::{cmd, Value},
+//!     testing::{moved, MockEnv, MockSlots},
+//! };
+//!
+//! let mut slots = MockSlots::new("example");
+//! slots.add(0, 8191, 6379, &[]).add(8192, 16383, 6380, &[]);
+//! let MockEnv { mut runtime, mut connection, .. } = MockEnv::new("example", move |cmd, port| {
+//!     slots.respond(cmd)?;
+//!     match port {
+//!         6379 => Err(Ok(Value::Data(b"bar".to_vec()))),
+//!         _ => Err(moved(0, "example", 6379)),
+//!     }
+//! });
+//! let value: String = runtime
+//!     .block_on(cmd("GET").arg("foo").query_async(&mut connection))
+//!     .unwrap();
+//! assert_eq!(value, "bar");
+//! ```
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use futures::future;
+use redis::{
+    aio::ConnectionLike, parse_redis_value, ConnectionAddr, IntoConnectionInfo, RedisFuture,
+    RedisResult, Value,
+};
+use tokio::runtime::Runtime;
+
+use crate::{Client, Connect, Connection};
+
+pub use self::{
+    clock::VirtualClock,
+    scenario::{Action, NodeScript, Scenario},
+    simulator::SimCluster,
+};
+
+mod clock;
+mod scenario;
+mod simulator;
+
+/// Answers the commands sent to a mock cluster. Called with the packed command and the port of
+/// the node. Returns `Err(response)` to answer and `Ok(())` if it does not know the command.
+pub type Handler = Arc<dyn Fn(&[u8], u16) -> Result<(), RedisResult<Value>> + Send + Sync>;
+
+lazy_static::lazy_static! {
+    static ref HANDLERS: RwLock<HashMap<String, Handler>> = Default::default();
+}
+
+/// A connection to a node of a mock cluster.
+#[derive(Clone)]
+pub struct MockConnection {
+    handler: Handler,
+    port: u16,
+}
+
+impl Connect for MockConnection {
+    fn connect<'a, T>(info: T) -> RedisFuture<'a, Self>
+    where
+        T: IntoConnectionInfo + Send + 'a,
+    {
+        let info = info.into_connection_info().unwrap();
+
+        let (name, port) = match &*info.addr {
+            ConnectionAddr::Tcp(addr, port) => (addr, *port),
+            _ => unreachable!(),
+        };
+        Box::pin(future::ok(MockConnection {
+            handler: HANDLERS
+                .read()
+                .unwrap()
+                .get(name)
+                .unwrap_or_else(|| panic!("Handler `{}` were not installed", name))
+                .clone(),
+            port,
+        }))
+    }
+}
+
+impl ConnectionLike for MockConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a redis::Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(future::ready(self.respond(&cmd.get_packed_command())))
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        // An atomic pipeline is wrapped in MULTI/EXEC, and only EXEC returns the replies
+        let atomic = pipeline
+            .get_packed_pipeline()
+            .starts_with(b"*1\r\n$5\r\nMULTI\r\n");
+        // Every command runs, like on a node, even though only the first error is returned
+        let results = pipeline
+            .cmd_iter()
+            .map(|cmd| self.respond(&cmd.get_packed_command()))
+            .collect::<Vec<_>>();
+        let values = results
+            .into_iter()
+            .collect::<RedisResult<Vec<_>>>()
+            .map(|values| {
+                if atomic {
+                    let queued = Value::Status("QUEUED".to_string());
+                    let mut transaction = vec![Value::Okay];
+                    transaction.extend(values.iter().map(|_| queued.clone()));
+                    transaction.push(Value::Bulk(values));
+                    transaction
+                } else {
+                    values
+                }
+            })
+            .map(|values| values.into_iter().skip(offset).take(count).collect());
+        Box::pin(future::ready(values))
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+impl MockConnection {
+    fn respond(&self, cmd: &[u8]) -> RedisResult<Value> {
+        (self.handler)(cmd, self.port).expect_err("Handler did not specify a response")
+    }
+}
+
+/// Removes the handler of a mock cluster when dropped.
+pub struct HandlerGuard(String);
+
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        HANDLERS.write().unwrap().remove(&self.0);
+    }
+}
+
+/// Installs `handler` for the nodes `redis://<name>:<port>`, replacing any previous handler with
+/// the same name.
+pub fn register_handler(
+    name: &str,
+    handler: impl Fn(&[u8], u16) -> Result<(), RedisResult<Value>> + Send + Sync + 'static,
+) -> HandlerGuard {
+    HANDLERS
+        .write()
+        .unwrap()
+        .insert(name.to_string(), Arc::new(handler));
+    HandlerGuard(name.to_string())
+}
+
+/// Combines a handler for each port into the handler of a cluster. Commands sent to any other
+/// port panic.
+pub fn per_node<F>(
+    handlers: impl IntoIterator<Item = (u16, F)>,
+) -> impl Fn(&[u8], u16) -> Result<(), RedisResult<Value>> + Send + Sync + 'static
+where
+    F: Fn(&[u8]) -> Result<(), RedisResult<Value>> + Send + Sync + 'static,
+{
+    let handlers = handlers.into_iter().collect::<HashMap<_, _>>();
+    move |cmd, port| match handlers.get(&port) {
+        Some(handler) => handler(cmd),
+        None => panic!("No mock node on port {}", port),
+    }
+}
+
+/// A runtime and a connection to a mock cluster. The cluster is reached through
+/// `redis://<name>:6379`.
+pub struct MockEnv {
+    pub runtime: Runtime,
+    pub client: Client,
+    pub connection: Connection<MockConnection>,
+    pub handler: HandlerGuard,
+}
+
+impl MockEnv {
+    /// Panics if the connection can not be created, for instance if `handler` does not answer
+    /// `CLUSTER SLOTS`.
+    pub fn new(
+        name: &str,
+        handler: impl Fn(&[u8], u16) -> Result<(), RedisResult<Value>> + Send + Sync + 'static,
+    ) -> Self {
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_io()
+            .enable_time()
+            .build()
+            .unwrap();
+
+        let handler = register_handler(name, handler);
+
+        let client = Client::open(vec![&*format!("redis://{}", name)]).unwrap();
+        let connection = runtime.block_on(client.get_generic_connection()).unwrap();
+        MockEnv {
+            runtime,
+            client,
+            connection,
+            handler,
+        }
+    }
+}
+
+/// Builds the slot map of a mock cluster.
+#[derive(Clone, Debug)]
+pub struct MockSlots {
+    name: String,
+    ranges: Vec<(u16, u16, u16, Vec<u16>)>,
+}
+
+impl MockSlots {
+    /// A cluster without any slots. Add them with `add`.
+    pub fn new(name: &str) -> Self {
+        MockSlots {
+            name: name.to_string(),
+            ranges: Vec::new(),
+        }
+    }
+
+    /// A cluster where the node on `port` serves every slot.
+    pub fn single(name: &str, port: u16) -> Self {
+        let mut slots = Self::new(name);
+        slots.add(0, 16383, port, &[]);
+        slots
+    }
+
+    /// Assigns the slots `start..=end` to the master on port `master`.
+    pub fn add(&mut self, start: u16, end: u16, master: u16, replicas: &[u16]) -> &mut Self {
+        self.ranges.push((start, end, master, replicas.to_vec()));
+        self
+    }
+
+    /// The response to `CLUSTER SLOTS`.
+    pub fn value(&self) -> Value {
+        let node = |port: u16| {
+            Value::Bulk(vec![
+                Value::Data(self.name.as_bytes().to_vec()),
+                Value::Int(port.into()),
+            ])
+        };
+        Value::Bulk(
+            self.ranges
+                .iter()
+                .map(|(start, end, master, replicas)| {
+                    let mut range = vec![
+                        Value::Int((*start).into()),
+                        Value::Int((*end).into()),
+                        node(*master),
+                    ];
+                    range.extend(replicas.iter().map(|&replica| node(replica)));
+                    Value::Bulk(range)
+                })
+                .collect(),
+        )
+    }
+
+    /// Answers `PING` and `CLUSTER SLOTS`, which every connection sends when it is created.
+    pub fn respond(&self, cmd: &[u8]) -> Result<(), RedisResult<Value>> {
+        if contains_slice(cmd, b"PING") {
+            Err(Ok(Value::Status("OK".into())))
+        } else if contains_slice(cmd, b"CLUSTER") && contains_slice(cmd, b"SLOTS") {
+            Err(Ok(self.value()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Answers `PING` and `CLUSTER SLOTS` for a cluster where `redis://<name>:6379` serves every
+/// slot.
+pub fn respond_startup(name: &str, cmd: &[u8]) -> Result<(), RedisResult<Value>> {
+    MockSlots::single(name, 6379).respond(cmd)
+}
+
+/// Returns true if `cmd` contains `part`.
+pub fn contains_slice(cmd: &[u8], part: &[u8]) -> bool {
+    part.is_empty() || cmd.windows(part.len()).any(|window| window == part)
+}
+
+/// A `MOVED` error redirecting `slot` to `redis://<name>:<port>`.
+pub fn moved(slot: u16, name: &str, port: u16) -> RedisResult<Value> {
+    parse_redis_value(format!("-MOVED {} {}:{}\r\n", slot, name, port).as_bytes())
+}
+
+/// An `ASK` error redirecting `slot` to `redis://<name>:<port>`.
+pub fn ask(slot: u16, name: &str, port: u16) -> RedisResult<Value> {
+    parse_redis_value(format!("-ASK {} {}:{}\r\n", slot, name, port).as_bytes())
+}
+
+pub fn try_again() -> RedisResult<Value> {
+    parse_redis_value(b"-TRYAGAIN mock\r\n")
+}
+
+pub fn cluster_down() -> RedisResult<Value> {
+    parse_redis_value(b"-CLUSTERDOWN mock\r\n")
+}
@@ -0,0 +1,185 @@
+//! A clock which only moves when told to.
+
+use std::{
+    sync::{Arc, Mutex},
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use futures::{
+    channel::oneshot,
+    future::{self, BoxFuture, FutureExt},
+};
+
+use crate::clock::Clock;
+
+/// A `Clock` whose time only passes with `advance` and `advance_to_next`, for running retries
+/// and timeouts without waiting.
+///
+/// # Example
+/// ```rust
+/// use std::{sync::Arc, time::Duration};
+/// use redis_cluster_async::{
+///     redis::Value,
+///     testing::{MockConnection, Scenario, VirtualClock},
+/// };
+///
+/// let clock = VirtualClock::new();
+/// let mut scenario = Scenario::new("virtual_clock");
+/// scenario.slots(0, 16383, 6379);
+/// scenario.node(6379).cluster_down().reply(Value::Int(1));
+/// let mut env = scenario.env();
+/// env.client.set_clock(Arc::new(clock.clone()));
+/// let mut connection = env
+///     .runtime
+///     .block_on(env.client.get_generic_connection::<MockConnection>())
+///     .unwrap();
+///
+/// let mut get = redis::cmd("GET");
+/// get.arg("b");
+/// let value: i32 = env
+///     .runtime
+///     .block_on(clock.run(get.query_async(&mut connection)))
+///     .unwrap();
+/// assert_eq!(value, 1);
+/// // The backoff after CLUSTERDOWN
+/// assert_eq!(clock.elapsed(), Duration::from_millis(1280));
+/// ```
+#[derive(Clone)]
+pub struct VirtualClock(Arc<Mutex<State>>);
+
+struct State {
+    start: Instant,
+    elapsed: Duration,
+    // The pending delays, with the time they finish at
+    timers: Vec<(Duration, oneshot::Sender<()>)>,
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        VirtualClock(Arc::new(Mutex::new(State {
+            start: Instant::now(),
+            elapsed: Duration::from_secs(0),
+            timers: Vec::new(),
+        })))
+    }
+
+    /// How much time has passed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        self.0.lock().unwrap().elapsed
+    }
+
+    /// Moves the time forward by `duration`, finishing the delays which end until then.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.0.lock().unwrap();
+        state.elapsed += duration;
+        let now = state.elapsed;
+        let (due, pending) = state
+            .timers
+            .drain(..)
+            .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+        state.timers = pending;
+        for (_, timer) in due {
+            let _ = timer.send(());
+        }
+    }
+
+    /// Moves the time forward to the end of the first pending delay and finishes it. Returns
+    /// `false` if there are no pending delays.
+    pub fn advance_to_next(&self) -> bool {
+        let next = {
+            let mut state = self.0.lock().unwrap();
+            state.timers.retain(|(_, timer)| !timer.is_canceled());
+            state.timers.iter().map(|(deadline, _)| *deadline).min()
+        };
+        match next {
+            Some(deadline) => {
+                self.advance(deadline - self.elapsed());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The delays which were started and are not yet finished or dropped.
+    pub fn pending_timers(&self) -> usize {
+        let state = self.0.lock().unwrap();
+        state
+            .timers
+            .iter()
+            .filter(|(_, timer)| !timer.is_canceled())
+            .count()
+    }
+
+    /// Runs `future`, advancing the time to the next delay whenever everything else is waiting.
+    ///
+    /// Panics if `future` is still waiting after the delays run out, since it would never
+    /// finish.
+    pub async fn run<F: std::future::Future>(&self, future: F) -> F::Output {
+        // Every task on the runtime gets a chance to run between two advances
+        const YIELDS: usize = 100;
+        let clock = self.clone();
+        let driver = async move {
+            loop {
+                for _ in 0..YIELDS {
+                    yield_now().await;
+                }
+                assert!(
+                    clock.advance_to_next(),
+                    "The future is waiting but there are no delays left"
+                );
+            }
+        };
+        futures::pin_mut!(future);
+        futures::pin_mut!(driver);
+        match future::select(future, driver).await {
+            future::Either::Left((output, _)) => output,
+            future::Either::Right(_) => unreachable!(),
+        }
+    }
+}
+
+// Lets the other tasks of the runtime run before continuing
+async fn yield_now() {
+    let mut yielded = false;
+    future::poll_fn(|cx| {
+        if yielded {
+            Poll::Ready(())
+        } else {
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        let state = self.0.lock().unwrap();
+        state.start + state.elapsed
+    }
+
+    fn delay(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let mut state = self.0.lock().unwrap();
+        if duration == Duration::from_secs(0) {
+            return future::ready(()).boxed();
+        }
+        let (sender, receiver) = oneshot::channel();
+        let deadline = state.elapsed + duration;
+        state.timers.push((deadline, sender));
+        // A dropped clock never finishes its delays
+        receiver
+            .then(|result| match result {
+                Ok(()) => future::ready(()).left_future(),
+                Err(_) => future::pending().right_future(),
+            })
+            .boxed()
+    }
+}
@@ -0,0 +1,308 @@
+//! Scripted fault injection for the mock cluster.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{Arc, Mutex},
+};
+
+use redis::{parse_redis_value, RedisResult, Value};
+
+use super::{MockEnv, MockSlots};
+use crate::key_slot;
+
+/// What a node does with a request it receives.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Answers `MOVED slot <name>:to`.
+    Moved {
+        slot: u16,
+        to: u16,
+    },
+    /// Answers `ASK slot <name>:to`.
+    Ask {
+        slot: u16,
+        to: u16,
+    },
+    TryAgain,
+    ClusterDown,
+    /// Fails with an `io::ErrorKind::TimedOut` error, as if the node did not answer in time.
+    Timeout,
+    /// Answers with an error, given without the leading `-`, for instance `ERR boom`.
+    Error(String),
+    Reply(Value),
+    /// Assigns the slots `start..=end` to the node on port `to`, then handles the request like a
+    /// node which has run out of actions.
+    MoveSlots {
+        start: u16,
+        end: u16,
+        to: u16,
+    },
+}
+
+/// A mock cluster whose nodes follow a script, and which records the requests they receive.
+///
+/// Each node runs through its actions in order, one per request. A node which has run out of
+/// actions behaves like a healthy node: it answers `MOVED` for the slots it does not serve and
+/// the default reply for the others. Nodes are told apart by their port and reached as
+/// `redis://<name>:<port>`.
+///
+/// # Example
+/// ```rust
+/// use redis_cluster_async::{redis::{cmd, Value}, testing::Scenario};
+///
+/// let mut scenario = Scenario::new("scenario_example");
+/// scenario.slots(0, 8191, 6379).slots(8192, 16383, 6380);
+/// // "b" is in slot 3300, which 6379 says has moved to 6380
+/// scenario.node(6379).moved(3300, 6380);
+/// scenario.node(6380).reply(Value::Int(1));
+///
+/// let mut env = scenario.env();
+/// let value: i32 = env
+///     .runtime
+///     .block_on(cmd("GET").arg("b").query_async(&mut env.connection))
+///     .unwrap();
+/// assert_eq!(value, 1);
+/// assert_eq!(
+///     scenario.requests(),
+///     vec![
+///         (6379, "GET b".to_string()),
+///         (6380, "GET b".to_string()),
+///     ]
+/// );
+/// ```
+#[derive(Clone)]
+pub struct Scenario {
+    name: String,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    slots: Vec<(u16, u16, u16)>,
+    actions: HashMap<u16, VecDeque<Action>>,
+    default_reply: Value,
+    // Ports which received `ASKING` for their next request
+    asking: HashMap<u16, bool>,
+    requests: Vec<(u16, String)>,
+    refreshes: usize,
+}
+
+impl Scenario {
+    /// A cluster without any slots. Assign them with `slots`.
+    pub fn new(name: &str) -> Self {
+        Scenario {
+            name: name.to_string(),
+            state: Arc::new(Mutex::new(State {
+                slots: Vec::new(),
+                actions: HashMap::new(),
+                default_reply: Value::Okay,
+                asking: HashMap::new(),
+                requests: Vec::new(),
+                refreshes: 0,
+            })),
+        }
+    }
+
+    /// Assigns the slots `start..=end` to the node on `port`.
+    pub fn slots(&mut self, start: u16, end: u16, port: u16) -> &mut Self {
+        self.state.lock().unwrap().assign(start, end, port);
+        self
+    }
+
+    /// What nodes answer when they serve the slot of the request and have no action left.
+    /// `Value::Okay` by default.
+    pub fn set_default_reply(&mut self, reply: Value) -> &mut Self {
+        self.state.lock().unwrap().default_reply = reply;
+        self
+    }
+
+    /// Returns a builder which appends actions to the script of the node on `port`.
+    pub fn node(&mut self, port: u16) -> NodeScript<'_> {
+        NodeScript {
+            scenario: self,
+            port,
+        }
+    }
+
+    /// Every request the nodes received except `PING` and `CLUSTER SLOTS`, as the port of the
+    /// node and the arguments separated by spaces, for instance `(6379, "GET foo")`.
+    pub fn requests(&self) -> Vec<(u16, String)> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// How many times the slot map was requested, including when connecting.
+    pub fn refreshes(&self) -> usize {
+        self.state.lock().unwrap().refreshes
+    }
+
+    /// The handler which runs the scenario, for `MockEnv::new` or `register_handler`.
+    pub fn handler(&self) -> impl Fn(&[u8], u16) -> Result<(), RedisResult<Value>> + Send + Sync {
+        let name = self.name.clone();
+        let state = self.state.clone();
+        move |cmd, port| Err(state.lock().unwrap().handle(&name, cmd, port))
+    }
+
+    /// Connects to the scenario through the node on port 6379.
+    pub fn env(&self) -> MockEnv {
+        MockEnv::new(&self.name, self.handler())
+    }
+}
+
+/// Appends actions to the script of a node. Created by `Scenario::node`.
+pub struct NodeScript<'a> {
+    scenario: &'a mut Scenario,
+    port: u16,
+}
+
+impl NodeScript<'_> {
+    pub fn then(&mut self, action: Action) -> &mut Self {
+        let mut state = self.scenario.state.lock().unwrap();
+        state
+            .actions
+            .entry(self.port)
+            .or_default()
+            .push_back(action);
+        drop(state);
+        self
+    }
+
+    /// Repeats the last action until it has been added `count` times in a row.
+    pub fn times(&mut self, count: usize) -> &mut Self {
+        let mut state = self.scenario.state.lock().unwrap();
+        let actions = state.actions.entry(self.port).or_default();
+        let last = actions
+            .back()
+            .cloned()
+            .expect("`times` must follow an action");
+        for _ in 1..count {
+            actions.push_back(last.clone());
+        }
+        drop(state);
+        self
+    }
+
+    pub fn moved(&mut self, slot: u16, to: u16) -> &mut Self {
+        self.then(Action::Moved { slot, to })
+    }
+
+    pub fn ask(&mut self, slot: u16, to: u16) -> &mut Self {
+        self.then(Action::Ask { slot, to })
+    }
+
+    pub fn try_again(&mut self) -> &mut Self {
+        self.then(Action::TryAgain)
+    }
+
+    pub fn cluster_down(&mut self) -> &mut Self {
+        self.then(Action::ClusterDown)
+    }
+
+    pub fn timeout(&mut self) -> &mut Self {
+        self.then(Action::Timeout)
+    }
+
+    pub fn error(&mut self, error: &str) -> &mut Self {
+        self.then(Action::Error(error.to_string()))
+    }
+
+    pub fn reply(&mut self, value: Value) -> &mut Self {
+        self.then(Action::Reply(value))
+    }
+
+    pub fn move_slots(&mut self, start: u16, end: u16, to: u16) -> &mut Self {
+        self.then(Action::MoveSlots { start, end, to })
+    }
+}
+
+impl State {
+    fn assign(&mut self, start: u16, end: u16, port: u16) {
+        let mut slots = Vec::with_capacity(self.slots.len() + 2);
+        for &(s, e, p) in &self.slots {
+            if s < start {
+                slots.push((s, e.min(start - 1), p));
+            }
+            if e > end {
+                slots.push((s.max(end + 1), e, p));
+            }
+        }
+        slots.push((start, end, port));
+        slots.sort();
+        self.slots = slots;
+    }
+
+    fn owner(&self, slot: u16) -> Option<u16> {
+        self.slots
+            .iter()
+            .find(|(start, end, _)| (*start..=*end).contains(&slot))
+            .map(|&(_, _, port)| port)
+    }
+
+    fn handle(&mut self, name: &str, cmd: &[u8], port: u16) -> RedisResult<Value> {
+        let args = match parse_redis_value(cmd)? {
+            Value::Bulk(args) => args
+                .into_iter()
+                .map(|arg| match arg {
+                    Value::Data(arg) => arg,
+                    _ => Vec::new(),
+                })
+                .collect::<Vec<_>>(),
+            _ => Vec::new(),
+        };
+        let text = args
+            .iter()
+            .map(|arg| String::from_utf8_lossy(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        match &text.to_uppercase()[..] {
+            "PING" => return Ok(Value::Status("PONG".into())),
+            "CLUSTER SLOTS" => {
+                self.refreshes += 1;
+                let mut slots = MockSlots::new(name);
+                for &(start, end, port) in &self.slots {
+                    slots.add(start, end, port, &[]);
+                }
+                return Ok(slots.value());
+            }
+            _ => self.requests.push((port, text.clone())),
+        }
+        if text.eq_ignore_ascii_case("ASKING") {
+            self.asking.insert(port, true);
+            return Ok(Value::Okay);
+        }
+        let asking = self.asking.remove(&port).unwrap_or(false);
+
+        let action = self.actions.get_mut(&port).and_then(VecDeque::pop_front);
+        let error = |text: String| parse_redis_value(format!("-{}\r\n", text).as_bytes());
+        match action {
+            Some(Action::Moved { slot, to }) => error(format!("MOVED {} {}:{}", slot, name, to)),
+            Some(Action::Ask { slot, to }) => error(format!("ASK {} {}:{}", slot, name, to)),
+            Some(Action::TryAgain) => error("TRYAGAIN scenario".into()),
+            Some(Action::ClusterDown) => error("CLUSTERDOWN scenario".into()),
+            Some(Action::Timeout) => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "scenario").into())
+            }
+            Some(Action::Error(text)) => error(text),
+            Some(Action::Reply(value)) => Ok(value),
+            Some(Action::MoveSlots { start, end, to }) => {
+                self.assign(start, end, to);
+                self.default(name, &args, port, asking)
+            }
+            None => self.default(name, &args, port, asking),
+        }
+    }
+
+    // What a healthy node answers
+    fn default(&self, name: &str, args: &[Vec<u8>], port: u16, asking: bool) -> RedisResult<Value> {
+        if let Some(key) = args.get(1) {
+            let slot = key_slot(key);
+            match self.owner(slot) {
+                Some(owner) if owner != port && !asking => {
+                    let moved = format!("-MOVED {} {}:{}\r\n", slot, name, owner);
+                    return parse_redis_value(moved.as_bytes());
+                }
+                _ => (),
+            }
+        }
+        Ok(self.default_reply.clone())
+    }
+}
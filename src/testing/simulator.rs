@@ -0,0 +1,512 @@
+//! A cluster of simulated nodes which speak RESP over TCP on localhost.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::future::{self, AbortHandle};
+use redis::{RedisResult, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{key_slot, Client, SLOT_SIZE};
+
+/// A cluster of simulated masters listening on `127.0.0.1`, for testing the real network
+/// connections without a redis server.
+///
+/// Each node keeps its own keyspace of strings and supports `GET`, `SET`, `DEL`, `EXISTS`,
+/// `INCR`, `MGET`, `PING`, `ASKING`, `CLUSTER SLOTS`, `CLUSTER NODES` and `CLUSTER INFO`. Keys
+/// sent to a node which does not serve their slot are answered with `MOVED`, and keys missing
+/// from a slot being migrated with `ASK`. Migrations, slot reassignments and node failures are
+/// scripted with the methods of `SimCluster`.
+///
+/// The nodes run on the tokio runtime which `start` is called from and stop when the
+/// `SimCluster` is dropped.
+///
+/// # Example
+/// ```rust
+/// use redis_cluster_async::{redis::cmd, testing::SimCluster};
+///
+/// #[tokio::main]
+/// async fn main() -> redis::RedisResult<()> {
+///     let cluster = SimCluster::start(3).await?;
+///     let mut connection = cluster.client()?.get_connection().await?;
+///     let () = cmd("SET").arg("foo").arg("bar").query_async(&mut connection).await?;
+///
+///     // Move the slot of "foo" to another node along with its keys
+///     let owner = cluster.owner(12182);
+///     cluster.migrate_slot(12182, (owner + 1) % 3);
+///     let value: String = cmd("GET").arg("foo").query_async(&mut connection).await?;
+///     assert_eq!(value, "bar");
+///     Ok(())
+/// }
+/// ```
+pub struct SimCluster {
+    state: Arc<Mutex<State>>,
+    listeners: Vec<AbortHandle>,
+}
+
+struct State {
+    nodes: Vec<Node>,
+    // The index of the node which serves each slot
+    owners: Vec<usize>,
+    // Slots being migrated and the node they are migrated to
+    migrating: HashMap<u16, usize>,
+}
+
+struct Node {
+    id: String,
+    addr: SocketAddr,
+    alive: bool,
+    keys: HashMap<Vec<u8>, Vec<u8>>,
+    connections: Vec<AbortHandle>,
+}
+
+impl SimCluster {
+    /// Starts `masters` nodes, each serving an even share of the slots.
+    pub async fn start(masters: usize) -> RedisResult<SimCluster> {
+        assert!(masters > 0, "A cluster needs at least one master");
+        let mut nodes = Vec::with_capacity(masters);
+        let mut listeners = Vec::with_capacity(masters);
+        for i in 0..masters {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            nodes.push(Node {
+                id: format!("{:040x}", i + 1),
+                addr: listener.local_addr()?,
+                alive: true,
+                keys: HashMap::new(),
+                connections: Vec::new(),
+            });
+            listeners.push(listener);
+        }
+        let owners = (0..SLOT_SIZE)
+            .map(|slot| slot * masters / SLOT_SIZE)
+            .collect();
+        let state = Arc::new(Mutex::new(State {
+            nodes,
+            owners,
+            migrating: HashMap::new(),
+        }));
+
+        let listeners = listeners
+            .into_iter()
+            .enumerate()
+            .map(|(i, listener)| {
+                let (accept, handle) = future::abortable(accept(state.clone(), i, listener));
+                tokio::spawn(accept);
+                handle
+            })
+            .collect();
+        Ok(SimCluster { state, listeners })
+    }
+
+    /// The addresses of the nodes, as `redis://127.0.0.1:port`.
+    pub fn nodes(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .nodes
+            .iter()
+            .map(|node| format!("redis://{}", node.addr))
+            .collect()
+    }
+
+    /// A client which uses every node as an initial node.
+    pub fn client(&self) -> RedisResult<Client> {
+        Client::open(self.nodes())
+    }
+
+    /// The index of the node which serves `slot`.
+    pub fn owner(&self, slot: u16) -> usize {
+        self.state.lock().unwrap().owners[usize::from(slot)]
+    }
+
+    /// Starts migrating `slot` to the node `to`. Until `finish_migration` is called the current
+    /// owner answers `ASK` for the keys of the slot which it does not have.
+    pub fn start_migration(&self, slot: u16, to: usize) {
+        let mut state = self.state.lock().unwrap();
+        assert!(to < state.nodes.len(), "No node {}", to);
+        state.migrating.insert(slot, to);
+    }
+
+    /// Moves the keys of `slot` which are left on its owner to the node it is migrated to, and
+    /// makes that node the owner.
+    pub fn finish_migration(&self, slot: u16) {
+        let mut state = self.state.lock().unwrap();
+        let to = state
+            .migrating
+            .remove(&slot)
+            .unwrap_or_else(|| panic!("Slot {} is not being migrated", slot));
+        let from = state.owners[usize::from(slot)];
+        let keys = state.nodes[from]
+            .keys
+            .keys()
+            .filter(|key| key_slot(key) == slot)
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in keys {
+            let value = state.nodes[from].keys.remove(&key).unwrap();
+            state.nodes[to].keys.insert(key, value);
+        }
+        state.owners[usize::from(slot)] = to;
+    }
+
+    /// Migrates `slot` and its keys to the node `to` at once.
+    pub fn migrate_slot(&self, slot: u16, to: usize) {
+        self.start_migration(slot, to);
+        self.finish_migration(slot);
+    }
+
+    /// Makes the node `to` the owner of `slots` without moving any keys, as after a failover to
+    /// a replica which lost them.
+    pub fn assign_slots(&self, slots: RangeInclusive<u16>, to: usize) {
+        let mut state = self.state.lock().unwrap();
+        assert!(to < state.nodes.len(), "No node {}", to);
+        for slot in slots {
+            state.owners[usize::from(slot)] = to;
+        }
+    }
+
+    /// Closes every connection to the node and refuses new ones until `restart` is called. The
+    /// node keeps its slots and keys.
+    pub fn kill(&self, node: usize) {
+        let mut state = self.state.lock().unwrap();
+        let node = &mut state.nodes[node];
+        node.alive = false;
+        for connection in node.connections.drain(..) {
+            connection.abort();
+        }
+    }
+
+    pub fn restart(&self, node: usize) {
+        self.state.lock().unwrap().nodes[node].alive = true;
+    }
+
+    /// The value of `key` in the keyspace of `node`, whether or not it serves the slot of `key`.
+    pub fn get(&self, node: usize, key: &[u8]) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().nodes[node]
+            .keys
+            .get(key)
+            .cloned()
+    }
+}
+
+impl Drop for SimCluster {
+    fn drop(&mut self) {
+        for listener in &self.listeners {
+            listener.abort();
+        }
+        for node in &mut self.state.lock().unwrap().nodes {
+            for connection in node.connections.drain(..) {
+                connection.abort();
+            }
+        }
+    }
+}
+
+async fn accept(state: Arc<Mutex<State>>, node: usize, mut listener: TcpListener) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_) => continue,
+        };
+        // Reset the connection when it is dropped, like a node which crashed. A graceful close
+        // would leave the requests sent to it waiting for an answer.
+        let _ = stream.set_linger(Some(Duration::from_secs(0)));
+        let mut guard = state.lock().unwrap();
+        if !guard.nodes[node].alive {
+            continue;
+        }
+        let (serve, handle) = future::abortable(serve(state.clone(), node, stream));
+        guard.nodes[node].connections.push(handle);
+        tokio::spawn(serve);
+    }
+}
+
+async fn serve(state: Arc<Mutex<State>>, node: usize, stream: TcpStream) {
+    let mut stream = BufReader::new(stream);
+    let mut asking = false;
+    while let Ok(Some(args)) = read_command(&mut stream).await {
+        let reply = {
+            let mut state = state.lock().unwrap();
+            if !state.nodes[node].alive {
+                return;
+            }
+            state.execute(node, &args, &mut asking)
+        };
+        let mut out = Vec::new();
+        encode(&reply, &mut out);
+        if stream.get_mut().write_all(&out).await.is_err() {
+            return;
+        }
+    }
+}
+
+// The reply to a command. Errors are sent as `-<message>`.
+enum Reply {
+    Value(Value),
+    Error(String),
+}
+
+impl State {
+    fn execute(&mut self, node: usize, args: &[Vec<u8>], asking: &mut bool) -> Reply {
+        let name = match args.first() {
+            Some(name) => String::from_utf8_lossy(name).to_uppercase(),
+            None => return Reply::Error("ERR empty command".into()),
+        };
+        let was_asking = std::mem::replace(asking, false);
+        match &name[..] {
+            "PING" => return Reply::Value(Value::Status("PONG".into())),
+            "ASKING" => {
+                *asking = true;
+                return Reply::Value(Value::Okay);
+            }
+            "CLUSTER" => return self.cluster(node, args),
+            _ => (),
+        }
+
+        let keys = match &name[..] {
+            "GET" | "SET" | "INCR" => args.get(1..2),
+            "DEL" | "EXISTS" | "MGET" => args.get(1..),
+            _ => return Reply::Error(format!("ERR unknown command `{}`", name)),
+        };
+        let keys = match keys {
+            Some(keys) if !keys.is_empty() => keys,
+            _ => return Reply::Error(format!("ERR wrong number of arguments for `{}`", name)),
+        };
+        let slot = key_slot(&keys[0]);
+        if keys.iter().any(|key| key_slot(key) != slot) {
+            return Reply::Error("CROSSSLOT Keys in request don't hash to the same slot".into());
+        }
+        if let Some(redirect) = self.redirect(node, slot, keys, was_asking) {
+            return Reply::Error(redirect);
+        }
+
+        let store = &mut self.nodes[node].keys;
+        match (&name[..], args.len()) {
+            ("GET", 2) => Reply::Value(match store.get(&args[1]) {
+                Some(value) => Value::Data(value.clone()),
+                None => Value::Nil,
+            }),
+            ("SET", 3) => {
+                store.insert(args[1].clone(), args[2].clone());
+                Reply::Value(Value::Okay)
+            }
+            ("INCR", 2) => {
+                let value = store
+                    .get(&args[1])
+                    .map_or(Ok(0), |value| String::from_utf8_lossy(value).parse::<i64>());
+                match value {
+                    Ok(value) => {
+                        store.insert(args[1].clone(), (value + 1).to_string().into_bytes());
+                        Reply::Value(Value::Int(value + 1))
+                    }
+                    Err(_) => Reply::Error("ERR value is not an integer or out of range".into()),
+                }
+            }
+            ("DEL", _) => Reply::Value(Value::Int(
+                keys.iter()
+                    .filter(|key| store.remove(*key).is_some())
+                    .count() as i64,
+            )),
+            ("EXISTS", _) => Reply::Value(Value::Int(
+                keys.iter().filter(|key| store.contains_key(*key)).count() as i64,
+            )),
+            ("MGET", _) => Reply::Value(Value::Bulk(
+                keys.iter()
+                    .map(|key| store.get(key).cloned().map_or(Value::Nil, Value::Data))
+                    .collect(),
+            )),
+            _ => Reply::Error(format!("ERR wrong number of arguments for `{}`", name)),
+        }
+    }
+
+    // The MOVED or ASK error for a command on `keys` sent to `node`, if it should not serve it
+    fn redirect(&self, node: usize, slot: u16, keys: &[Vec<u8>], asking: bool) -> Option<String> {
+        let owner = self.owners[usize::from(slot)];
+        let migrating = self.migrating.get(&slot).copied();
+        if owner == node {
+            let local = &self.nodes[node].keys;
+            match migrating {
+                Some(to) if keys.iter().any(|key| !local.contains_key(key)) => {
+                    Some(format!("ASK {} {}", slot, self.nodes[to].addr))
+                }
+                _ => None,
+            }
+        } else if asking && migrating == Some(node) {
+            None
+        } else {
+            Some(format!("MOVED {} {}", slot, self.nodes[owner].addr))
+        }
+    }
+
+    fn cluster(&self, node: usize, args: &[Vec<u8>]) -> Reply {
+        let subcommand = args
+            .get(1)
+            .map(|arg| String::from_utf8_lossy(arg).to_uppercase());
+        match subcommand.as_deref() {
+            Some("SLOTS") => Reply::Value(Value::Bulk(
+                self.ranges()
+                    .into_iter()
+                    .map(|(start, end, owner)| {
+                        let node = &self.nodes[owner];
+                        Value::Bulk(vec![
+                            Value::Int(start.into()),
+                            Value::Int(end.into()),
+                            Value::Bulk(vec![
+                                Value::Data(node.addr.ip().to_string().into_bytes()),
+                                Value::Int(node.addr.port().into()),
+                                Value::Data(node.id.clone().into_bytes()),
+                            ]),
+                        ])
+                    })
+                    .collect(),
+            )),
+            Some("NODES") => Reply::Value(Value::Data(self.nodes_text(node).into_bytes())),
+            Some("INFO") => Reply::Value(Value::Data(
+                format!(
+                    "cluster_state:ok\r\ncluster_slots_assigned:{}\r\ncluster_slots_ok:{}\r\n\
+                     cluster_known_nodes:{}\r\ncluster_size:{}\r\n",
+                    SLOT_SIZE,
+                    SLOT_SIZE,
+                    self.nodes.len(),
+                    self.nodes.len(),
+                )
+                .into_bytes(),
+            )),
+            Some("MYID") => Reply::Value(Value::Data(self.nodes[node].id.clone().into_bytes())),
+            _ => Reply::Error("ERR unknown CLUSTER subcommand".into()),
+        }
+    }
+
+    // The contiguous slot ranges and the node which serves each
+    fn ranges(&self) -> Vec<(u16, u16, usize)> {
+        let mut ranges: Vec<(u16, u16, usize)> = Vec::new();
+        for (slot, &owner) in self.owners.iter().enumerate() {
+            match ranges.last_mut() {
+                Some((_, end, last)) if *last == owner => *end = slot as u16,
+                _ => ranges.push((slot as u16, slot as u16, owner)),
+            }
+        }
+        ranges
+    }
+
+    fn nodes_text(&self, myself: usize) -> String {
+        let ranges = self.ranges();
+        let mut text = String::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let mut flags = if i == myself {
+                "myself,master"
+            } else {
+                "master"
+            }
+            .to_string();
+            if !node.alive {
+                flags.push_str(",fail");
+            }
+            let link = if node.alive {
+                "connected"
+            } else {
+                "disconnected"
+            };
+            text.push_str(&format!(
+                "{} {}@{} {} - 0 0 {} {}",
+                node.id,
+                node.addr,
+                node.addr.port() + 10000,
+                flags,
+                i + 1,
+                link
+            ));
+            for &(start, end, _) in ranges.iter().filter(|range| range.2 == i) {
+                if start == end {
+                    text.push_str(&format!(" {}", start));
+                } else {
+                    text.push_str(&format!(" {}-{}", start, end));
+                }
+            }
+            for (slot, &to) in &self.migrating {
+                if self.owners[usize::from(*slot)] == i {
+                    text.push_str(&format!(" [{}->-{}]", slot, self.nodes[to].id));
+                } else if to == i {
+                    let from = self.owners[usize::from(*slot)];
+                    text.push_str(&format!(" [{}-<-{}]", slot, self.nodes[from].id));
+                }
+            }
+            text.push('\n');
+        }
+        text
+    }
+}
+
+// Reads a command sent as an array of bulk strings. Returns `None` when the connection is closed.
+async fn read_command(stream: &mut BufReader<TcpStream>) -> RedisResult<Option<Vec<Vec<u8>>>> {
+    let count = match read_line(stream).await? {
+        Some(line) => parse_header(&line, b'*')?,
+        None => return Ok(None),
+    };
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(stream).await?.ok_or_else(protocol_error)?;
+        let len = parse_header(&line, b'$')?;
+        let mut arg = vec![0; len + 2];
+        stream.read_exact(&mut arg).await?;
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+async fn read_line(stream: &mut BufReader<TcpStream>) -> RedisResult<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if stream.read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line))
+}
+
+fn parse_header(line: &[u8], prefix: u8) -> RedisResult<usize> {
+    if line.first() != Some(&prefix) {
+        return Err(protocol_error());
+    }
+    String::from_utf8_lossy(&line[1..])
+        .trim()
+        .parse()
+        .map_err(|_| protocol_error())
+}
+
+fn protocol_error() -> redis::RedisError {
+    redis::RedisError::from((redis::ErrorKind::ResponseError, "Invalid command"))
+}
+
+fn encode(reply: &Reply, out: &mut Vec<u8>) {
+    match reply {
+        Reply::Value(value) => encode_value(value, out),
+        Reply::Error(message) => out.extend_from_slice(format!("-{}\r\n", message).as_bytes()),
+    }
+}
+
+fn encode_value(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Nil => out.extend_from_slice(b"$-1\r\n"),
+        Value::Int(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+        Value::Data(data) => {
+            out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+            out.extend_from_slice(data);
+            out.extend_from_slice(b"\r\n");
+        }
+        Value::Bulk(values) => {
+            out.extend_from_slice(format!("*{}\r\n", values.len()).as_bytes());
+            for value in values {
+                encode_value(value, out);
+            }
+        }
+        Value::Status(status) => out.extend_from_slice(format!("+{}\r\n", status).as_bytes()),
+        Value::Okay => out.extend_from_slice(b"+OK\r\n"),
+    }
+}
@@ -0,0 +1,303 @@
+//! Read-only views of the slot map and connections which the driver routes requests with, and
+//! the events emitted when they change.
+
+use std::{collections::BTreeSet, time::Instant};
+
+use futures::{
+    channel::oneshot,
+    stream::{BoxStream, StreamExt},
+};
+use redis::{aio::ConnectionLike, ErrorKind, RedisError, RedisResult};
+
+use crate::{Connection, Message, Pipeline, SlotAddrs, SlotMap, SLOT_SIZE};
+
+/// The master and replicas which serve the slots `start..=end`. Addresses are given as
+/// `redis://host:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotRange {
+    pub start: u16,
+    pub end: u16,
+    pub master: String,
+    pub replicas: Vec<String>,
+}
+
+/// The slot ranges of the cluster, as returned by `Connection::slots`.
+#[derive(Debug, Clone)]
+pub struct SlotsSnapshot {
+    /// Every slot range, ordered by their first slot.
+    pub ranges: Vec<SlotRange>,
+    /// When the slot map was last refreshed.
+    pub refreshed_at: Instant,
+}
+
+/// The nodes the connection is connected to, as returned by `Connection::nodes`.
+#[derive(Debug, Clone)]
+pub struct NodesSnapshot {
+    /// The address of every connected node, sorted.
+    pub nodes: Vec<String>,
+    /// When the slot map was last refreshed.
+    pub refreshed_at: Instant,
+}
+
+/// A change of the cluster topology, as returned by `Connection::topology_events`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TopologyEvent {
+    /// The slots `start..=end` are now served by the master `to`. `from` is the master which
+    /// served them before, if any.
+    SlotsReassigned {
+        start: u16,
+        end: u16,
+        from: Option<String>,
+        to: String,
+    },
+    /// A master or replica appeared in the slot map.
+    NodeAdded(String),
+    /// A master or replica is no longer part of the slot map.
+    NodeRemoved(String),
+    /// Reloading the slot map failed. It is retried with a growing delay until it succeeds, and
+    /// only the first failure is reported until `RecoveryFinished`.
+    RefreshFailed { kind: ErrorKind, message: String },
+    /// The slot map was reloaded and requests are served again. Emitted after the other events
+    /// of the refresh.
+    RecoveryFinished,
+}
+
+// The state of the driver sent back for `Message::Topology`
+#[derive(Clone)]
+pub(crate) struct Topology {
+    pub(crate) slots: SlotMap,
+    pub(crate) nodes: Vec<String>,
+    pub(crate) refreshed_at: Instant,
+}
+
+// A request for the state of the driver, which waits while the slots are refreshed
+pub(crate) enum TopologyRequest {
+    Full(oneshot::Sender<RedisResult<Topology>>),
+    // The nodes of a single slot, to not clone the whole slot map
+    Slot(u16, oneshot::Sender<RedisResult<Option<SlotAddrs>>>),
+}
+
+impl TopologyRequest {
+    pub(crate) fn fail(self, err: RedisError) {
+        match self {
+            TopologyRequest::Full(sender) => {
+                let _ = sender.send(Err(err));
+            }
+            TopologyRequest::Slot(_, sender) => {
+                let _ = sender.send(Err(err));
+            }
+        }
+    }
+}
+
+impl<C> Pipeline<C> {
+    // Emits the changes from the current slot map to `slots`, which is about to replace it
+    pub(crate) fn slots_changed(&mut self, slots: &SlotMap) {
+        for event in diff(&self.slots, slots) {
+            self.emit(event);
+        }
+        self.emit(TopologyEvent::RecoveryFinished);
+    }
+
+    pub(crate) fn refresh_failed(&mut self, err: &RedisError) {
+        self.emit(TopologyEvent::RefreshFailed {
+            kind: err.kind(),
+            message: err.to_string(),
+        });
+    }
+
+    // Sends `event` to every subscriber and forgets those which were dropped
+    fn emit(&mut self, event: TopologyEvent) {
+        self.topology_subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+
+    pub(crate) fn answer_topology(&self, request: TopologyRequest) {
+        match request {
+            TopologyRequest::Full(sender) => {
+                let _ = sender.send(Ok(self.topology()));
+            }
+            TopologyRequest::Slot(slot, sender) => {
+                let _ = sender.send(Ok(self.slots.get(slot).cloned()));
+            }
+        }
+    }
+
+    fn topology(&self) -> Topology {
+        let mut nodes = self.connections.keys().cloned().collect::<Vec<_>>();
+        nodes.sort();
+        Topology {
+            slots: self.slots.clone(),
+            nodes,
+            refreshed_at: self.refreshed_at,
+        }
+    }
+}
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// Returns the slot map which requests are currently routed with.
+    ///
+    /// If the slot map is being refreshed this waits for the refresh to finish, and fails if the
+    /// refresh fails.
+    pub async fn slots(&mut self) -> RedisResult<SlotsSnapshot> {
+        let topology = self.topology().await?;
+        let ranges = topology
+            .slots
+            .ranges()
+            .map(|(start, end, addrs)| SlotRange {
+                start,
+                end,
+                master: addrs.master.clone(),
+                replicas: addrs.replicas.clone(),
+            })
+            .collect();
+        Ok(SlotsSnapshot {
+            ranges,
+            refreshed_at: topology.refreshed_at,
+        })
+    }
+
+    /// Returns the nodes which the connection currently holds a connection to.
+    ///
+    /// If the slot map is being refreshed this waits for the refresh to finish.
+    pub async fn nodes(&mut self) -> RedisResult<NodesSnapshot> {
+        let topology = self.topology().await?;
+        Ok(NodesSnapshot {
+            nodes: topology.nodes,
+            refreshed_at: topology.refreshed_at,
+        })
+    }
+
+    /// Returns a stream of the changes to the slot map, starting with the next refresh.
+    ///
+    /// The events of a refresh are emitted before the requests which waited for it are retried.
+    /// Events are buffered without limit until the stream is read. The stream ends when the
+    /// connection is dropped.
+    pub async fn topology_events(&mut self) -> RedisResult<BoxStream<'static, TopologyEvent>> {
+        let events = self.send(Message::TopologyEvents).await?;
+        Ok(events.boxed())
+    }
+}
+
+// The events which turn `old` into `new`
+fn diff(old: &SlotMap, new: &SlotMap) -> Vec<TopologyEvent> {
+    let old_nodes = nodes(old);
+    let new_nodes = nodes(new);
+    let mut events = new_nodes
+        .difference(&old_nodes)
+        .map(|node| TopologyEvent::NodeAdded(node.to_string()))
+        .collect::<Vec<_>>();
+
+    let old_masters = masters(old);
+    let new_masters = masters(new);
+    let mut slot = 0;
+    while slot < SLOT_SIZE {
+        let (from, to) = (old_masters[slot], new_masters[slot]);
+        let end = (slot..SLOT_SIZE)
+            .take_while(|&i| old_masters[i] == from && new_masters[i] == to)
+            .last()
+            .unwrap();
+        if let Some(to) = to {
+            if from != Some(to) {
+                events.push(TopologyEvent::SlotsReassigned {
+                    start: slot as u16,
+                    end: end as u16,
+                    from: from.map(String::from),
+                    to: to.to_string(),
+                });
+            }
+        }
+        slot = end + 1;
+    }
+
+    events.extend(
+        old_nodes
+            .difference(&new_nodes)
+            .map(|node| TopologyEvent::NodeRemoved(node.to_string())),
+    );
+    events
+}
+
+fn nodes(slots: &SlotMap) -> BTreeSet<&str> {
+    slots
+        .nodes()
+        .flat_map(|addrs| Some(&addrs.master).into_iter().chain(&addrs.replicas))
+        .map(|addr| &addr[..])
+        .collect()
+}
+
+// The master of each slot
+fn masters(slots: &SlotMap) -> Vec<Option<&str>> {
+    (0..SLOT_SIZE)
+        .map(|slot| slots.get(slot as u16).map(|addrs| &addrs.master[..]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SlotAddrs;
+
+    fn slot_map(ranges: &[(u16, u16, &str, &[&str])]) -> SlotMap {
+        ranges
+            .iter()
+            .map(|&(start, end, master, replicas)| {
+                let addrs = SlotAddrs {
+                    master: master.to_string(),
+                    replicas: replicas.iter().map(|r| r.to_string()).collect(),
+                };
+                ((start, end), addrs)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diff_slot_maps() {
+        let old = slot_map(&[(0, 8191, "a", &["c"]), (8192, 16383, "b", &["d"])]);
+        let new = slot_map(&[
+            (0, 99, "a", &["c"]),
+            (100, 8191, "e", &[]),
+            (8192, 16383, "d", &["b"]),
+        ]);
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                TopologyEvent::NodeAdded("e".into()),
+                TopologyEvent::SlotsReassigned {
+                    start: 100,
+                    end: 8191,
+                    from: Some("a".into()),
+                    to: "e".into(),
+                },
+                TopologyEvent::SlotsReassigned {
+                    start: 8192,
+                    end: 16383,
+                    from: Some("b".into()),
+                    to: "d".into(),
+                },
+            ]
+        );
+        assert_eq!(diff(&new, &new), vec![]);
+
+        let events = diff(&SlotMap::new(), &slot_map(&[(0, 16383, "a", &[])]));
+        assert_eq!(
+            events,
+            vec![
+                TopologyEvent::NodeAdded("a".into()),
+                TopologyEvent::SlotsReassigned {
+                    start: 0,
+                    end: 16383,
+                    from: None,
+                    to: "a".into(),
+                },
+            ]
+        );
+        assert_eq!(
+            diff(&old, &slot_map(&[(0, 16383, "a", &["c"])])).last(),
+            Some(&TopologyEvent::NodeRemoved("d".into()))
+        );
+    }
+}
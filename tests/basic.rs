@@ -11,14 +11,15 @@ use {
 };
 
 use redis_cluster_async::{
-    redis::{cmd, RedisError, RedisResult, Script},
+    admin::{ClusterNode, MigrationProgress},
+    redis::{cmd, Commands, RedisError, RedisResult, Script},
     Client,
 };
 
 const REDIS_URL: &str = "redis://127.0.0.1:7000/";
 
 pub struct RedisProcess;
-pub struct RedisLock(MutexGuard<'static, RedisProcess>);
+pub struct RedisLock(#[allow(dead_code)] MutexGuard<'static, RedisProcess>);
 
 impl RedisProcess {
     // Blocks until we have sole access.
@@ -44,6 +45,12 @@ pub struct RedisEnv {
     nodes: Vec<redis::aio::MultiplexedConnection>,
 }
 
+impl Default for RedisEnv {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RedisEnv {
     pub fn new() -> Self {
         let _ = env_logger::try_init();
@@ -117,23 +124,13 @@ impl RedisEnv {
         redis::cmd("CLUSTER")
             .arg("NODES")
             .query_async(redis_client)
-            .map_ok(|s: String| {
-                s.lines()
-                    .map(|line| {
-                        let mut iter = line.split(' ');
-                        let port = iter
-                            .by_ref()
-                            .nth(1)
-                            .expect("Node ip")
-                            .splitn(2, '@')
-                            .next()
-                            .unwrap()
-                            .splitn(2, ':')
-                            .nth(1)
-                            .unwrap();
+            .map_ok(|nodes: Vec<ClusterNode>| {
+                nodes
+                    .into_iter()
+                    .map(|node| {
                         (
-                            format!("redis://localhost:{}", port),
-                            iter.next().expect("master").contains("master"),
+                            format!("redis://localhost:{}", node.port),
+                            node.is_master(),
                         )
                     })
                     .collect::<Vec<_>>()
@@ -166,6 +163,23 @@ fn basic_cmd() {
         .unwrap()
 }
 
+#[test]
+fn basic_sync_cmd() {
+    let env = RedisEnv::new();
+    let mut connection = env.client.get_sync_connection().unwrap();
+    let () = connection.set("test", "test_data").unwrap();
+    let res: String = connection.get("test").unwrap();
+    assert_eq!(res, "test_data");
+
+    let (first, second): (i32, i32) = redis::pipe()
+        .atomic()
+        .incr("{test}counter", 1)
+        .incr("{test}counter", 1)
+        .query(&mut connection)
+        .unwrap();
+    assert_eq!((first, second), (1, 2));
+}
+
 #[test]
 fn basic_eval() {
     let mut env = RedisEnv::new();
@@ -251,7 +265,7 @@ fn xadd_cmd() {
                 .arg("value1")
                 .arg("field2")
                 .arg("value2")
-                .query_async(&mut connection)
+                .query_async::<_, ()>(&mut connection)
                 .await?;
             Ok(())
         })
@@ -274,7 +288,7 @@ fn xread_streams_cmd() {
                 .arg("*") // ask redis to generate a key for us
                 .arg("identifier")
                 .arg("message")
-                .query_async(&mut connection)
+                .query_async::<_, ()>(&mut connection)
                 .await?;
 
             redis::cmd("XREAD")
@@ -283,8 +297,146 @@ fn xread_streams_cmd() {
                 .arg("STREAMS")
                 .arg("mystream")
                 .arg("0-0")
+                .query_async::<_, ()>(&mut connection)
+                .await?;
+            Ok(())
+        })
+        .map_err(|err: RedisError| err)
+        .unwrap()
+}
+
+#[test]
+fn cluster_topology() {
+    let mut env = RedisEnv::new();
+    let client = env.client;
+    env.runtime
+        .block_on(async {
+            let mut connection = client.get_connection().await?;
+
+            let info = connection.cluster_info().await?;
+            assert!(info.state_ok);
+            assert_eq!(info.slots_assigned, 16384);
+            assert_eq!(info.size, 3);
+
+            let nodes = connection.cluster_nodes().await?;
+            assert_eq!(nodes.len(), info.known_nodes as usize);
+            let masters = nodes.iter().filter(|node| node.is_master()).collect::<Vec<_>>();
+            assert_eq!(masters.len(), 3);
+            let slots: u32 = masters
+                .iter()
+                .flat_map(|node| &node.slots)
+                .map(|(start, end)| u32::from(end - start) + 1)
+                .sum();
+            assert_eq!(slots, 16384);
+            for replica in nodes.iter().filter(|node| node.is_replica()) {
+                let master_id = replica.master_id.as_ref().expect("Replica without a master");
+                assert!(masters.iter().any(|master| &master.id == master_id));
+            }
+            Ok(())
+        })
+        .map_err(|err: RedisError| err)
+        .unwrap()
+}
+
+#[test]
+fn migrate_slot() {
+    let mut env = RedisEnv::new();
+    let client = env.client;
+    env.runtime
+        .block_on(async {
+            let mut connection = client.get_connection().await?;
+            let () = cmd("SET")
+                .arg("migrate")
+                .arg(1)
+                .query_async(&mut connection)
+                .await?;
+            let slot: u16 = cmd("CLUSTER")
+                .arg("KEYSLOT")
+                .arg("migrate")
+                .query_async(&mut connection)
+                .await?;
+
+            let nodes = connection.cluster_nodes().await?;
+            let masters = nodes.iter().filter(|node| node.is_master());
+            let (owner, other): (Vec<_>, Vec<_>) = masters.partition(|node| {
+                node.slots
+                    .iter()
+                    .any(|&(start, end)| start <= slot && slot <= end)
+            });
+            let from = owner[0].url();
+            let to = other[0].url();
+
+            let progress = connection
+                .migrate_slot(slot, &from, &to)
+                .try_collect::<Vec<_>>()
+                .await?;
+            assert_eq!(
+                progress.first(),
+                Some(&MigrationProgress::Started {
+                    slot,
+                    from: from.clone(),
+                    to: to.clone(),
+                })
+            );
+            assert_eq!(
+                progress.last(),
+                Some(&MigrationProgress::Finished { slot, keys: 1 })
+            );
+
+            let value: i32 = cmd("GET")
+                .arg("migrate")
                 .query_async(&mut connection)
                 .await?;
+            assert_eq!(value, 1);
+            Ok(())
+        })
+        .map_err(|err: RedisError| err)
+        .unwrap()
+}
+
+#[test]
+fn keyspace_events() {
+    let mut env = RedisEnv::new();
+    let client = env.client;
+    let mut nodes = env.nodes;
+    env.runtime
+        .block_on(async {
+            for node in &mut nodes {
+                let () = cmd("CONFIG")
+                    .arg("SET")
+                    .arg("notify-keyspace-events")
+                    .arg("KA")
+                    .query_async(node)
+                    .await?;
+            }
+
+            let mut events = client
+                .subscribe_keyspace_events(vec!["__keyspace@0__:*"])
+                .await?;
+            let mut connection = client.get_connection().await?;
+
+            let keys = ["a", "b", "c", "d", "e", "f"];
+            for key in &keys {
+                let () = cmd("SET")
+                    .arg(*key)
+                    .arg("value")
+                    .query_async(&mut connection)
+                    .await?;
+            }
+
+            let mut received = Vec::new();
+            while received.len() < keys.len() {
+                let msg = tokio::time::timeout(std::time::Duration::from_secs(3), events.next())
+                    .await
+                    .expect("Timed out waiting for keyspace events")
+                    .expect("Keyspace event stream ended");
+                assert_eq!(msg.pattern(), "__keyspace@0__:*");
+                assert_eq!(msg.payload::<String>()?, "set");
+                assert!(msg.node().starts_with("redis://"));
+                received.push(msg.channel().trim_start_matches("__keyspace@0__:").to_string());
+            }
+            received.sort();
+            assert_eq!(received, keys);
             Ok(())
         })
         .map_err(|err: RedisError| err)
@@ -297,7 +449,7 @@ fn proptests() {
 
     proptest!(
         proptest::prelude::ProptestConfig { cases: 30, failure_persistence: None, .. Default::default() },
-        |(requests in 0..15, value in 0..i32::max_value())| {
+        |(requests in 0..15, value in 0..i32::MAX)| {
             test_failover(&mut env.borrow_mut(), requests, value)
         }
     );
@@ -348,13 +500,13 @@ fn test_failover(env: &mut FailoverEnv, requests: i32, value: i32) {
                 async move {
                     if i == requests / 2 {
                         // Failover all the nodes, error only if all the failover requests error
-                        nodes.iter_mut().map(|node| do_failover(node))
+                        nodes.iter_mut().map(do_failover)
                             .collect::<stream::FuturesUnordered<_>>()
                             .fold(
                                 Err(Box::<dyn Error + Send + Sync>::from("None".to_string())),
                                 |acc: Result<(), Box<dyn Error + Send + Sync>>,
                                  result: Result<(), Box<dyn Error + Send + Sync>>| async move {
-                                    acc.or_else(|_| result)
+                                    acc.or(result)
                                 },
                             )
                             .await
@@ -378,7 +530,7 @@ fn test_failover(env: &mut FailoverEnv, requests: i32, value: i32) {
                 }
             })
             .collect::<stream::FuturesUnordered<_>>()
-            .try_collect()
+            .try_collect::<()>()
             .await
     };
     env.runtime
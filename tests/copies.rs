@@ -0,0 +1,165 @@
+//! Counts the bytes allocated while a large command is redirected, to check that its attempts
+//! share one packed copy of it. The allocator counts for the whole binary, so this is the only
+//! test in it.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use {
+    futures::prelude::*,
+    redis_cluster_async::{
+        redis::{
+            aio::ConnectionLike, cmd, parse_redis_value, Cmd, ConnectionAddr, IntoConnectionInfo,
+            Pipeline, RedisFuture, RedisResult, Value,
+        },
+        testing::MockSlots,
+        Client, Connect, Packed,
+    },
+};
+
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const NAME: &str = "copies";
+
+// How many times the `SET` was sent
+static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+
+// A node which writes packed commands as they are. It packs the commands it is given through
+// `ConnectionLike` like `MultiplexedConnection` does.
+#[derive(Clone)]
+struct Node;
+
+impl Connect for Node {
+    fn connect<'a, T>(info: T) -> RedisFuture<'a, Self>
+    where
+        T: IntoConnectionInfo + Send + 'a,
+    {
+        let info = info.into_connection_info();
+        async move {
+            match *info?.addr {
+                ConnectionAddr::Tcp(..) => Ok(Node),
+                _ => panic!("Expected a TCP address"),
+            }
+        }
+        .boxed()
+    }
+
+    fn sends_packed() -> bool {
+        true
+    }
+
+    fn req_packed<'a>(
+        &'a mut self,
+        packed: &'a Packed,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let names = packed.chunks().map(name).collect::<Vec<_>>();
+        let replies = match &names[..] {
+            [b"SET"] => {
+                assert_eq!((offset, count), (0, 1));
+                match ATTEMPTS.fetch_add(1, Ordering::SeqCst) {
+                    // "b" is in slot 3300
+                    0 => parse_redis_value(format!("-MOVED 3300 {}:6380\r\n", NAME).as_bytes()),
+                    attempt => ask_then_reply(attempt),
+                }
+            }
+            [b"ASKING", b"SET"] => {
+                assert_eq!((offset, count), (1, 1));
+                ask_then_reply(ATTEMPTS.fetch_add(1, Ordering::SeqCst))
+            }
+            _ => panic!("Unexpected commands {:?}", names),
+        };
+        future::ready(replies.map(|value| vec![value])).boxed()
+    }
+}
+
+// The name of a packed command, `*<args>\r\n$<length>\r\n<name>\r\n...`
+fn name(packed: &[u8]) -> &[u8] {
+    let name = packed
+        .splitn(4, |&byte| byte == b'\n')
+        .nth(2)
+        .unwrap_or_default();
+    name.strip_suffix(b"\r").unwrap_or(name)
+}
+
+// Redirects the attempts after the MOVED with ASK until the fifth one
+fn ask_then_reply(attempt: usize) -> RedisResult<Value> {
+    match attempt {
+        0..=3 => parse_redis_value(format!("-ASK 3300 {}:6381\r\n", NAME).as_bytes()),
+        _ => Ok(Value::Okay),
+    }
+}
+
+impl ConnectionLike for Node {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        let response = match name(&cmd.get_packed_command()) {
+            b"PING" => Ok(Value::Status("PONG".into())),
+            b"CLUSTER" => Ok(MockSlots::single(NAME, 6379).value()),
+            name => panic!("Unexpected command {}", String::from_utf8_lossy(name)),
+        };
+        future::ready(response).boxed()
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        _pipeline: &'a Pipeline,
+        _offset: usize,
+        _count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        panic!("Pipelines are sent packed")
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+#[test]
+fn large_command_is_copied_once() {
+    const SIZE: usize = 1 << 23;
+
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_time()
+        .build()
+        .unwrap();
+    let client = Client::open(vec![&*format!("redis://{}:6379", NAME)]).unwrap();
+    let mut connection = runtime
+        .block_on(client.get_generic_connection::<Node>())
+        .unwrap();
+
+    let mut set = cmd("SET");
+    set.arg("b").arg(vec![0u8; SIZE]);
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    let () = runtime.block_on(set.query_async(&mut connection)).unwrap();
+    let allocated = ALLOCATED.load(Ordering::SeqCst) - before;
+
+    assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 5);
+    // Packed once and shared by every attempt, including the ones after ASKING. The refresh after
+    // the MOVED allocates less than a megabyte whatever the size of the command.
+    assert!(
+        allocated < SIZE + SIZE / 8,
+        "Allocated {} bytes for a {} byte command",
+        allocated,
+        SIZE
+    );
+}
@@ -1,148 +1,30 @@
 use std::{
     collections::HashMap,
-    sync::{atomic, Arc, RwLock},
+    num::NonZeroUsize,
+    sync::{atomic, Arc, Mutex},
+    time::Duration,
 };
 
 use {
-    futures::future,
+    futures::prelude::*,
+    proptest::{prelude::*, proptest},
     redis_cluster_async::{
+        admin::{ClusterSetup, FailoverMode, MigrationProgress, RebalancePlan},
+        clock::Clock,
+        metrics::CountingMetrics,
         redis::{
-            aio::ConnectionLike, cmd, parse_redis_value, IntoConnectionInfo, RedisFuture,
-            RedisResult, Value,
+            aio::ConnectionLike, cmd, parse_redis_value, Commands, ConnectionAddr,
+            IntoConnectionInfo, RedisError, RedisFuture, Value,
         },
-        Client, Connect,
+        testing::{
+            contains_slice, moved, per_node, register_handler, respond_startup, Action,
+            HandlerGuard, MockConnection, MockEnv, MockSlots, Scenario, VirtualClock,
+        },
+        is_overloaded, Client, ClusterError, Connect, OverloadPolicy, PoolStrategy, SlotRange,
+        TopologyEvent,
     },
-    tokio::runtime::Runtime,
 };
 
-type Handler = Arc<dyn Fn(&redis::Cmd, u16) -> Result<(), RedisResult<Value>> + Send + Sync>;
-
-lazy_static::lazy_static! {
-    static ref HANDLERS: RwLock<HashMap<String, Handler>>
-        = Default::default();
-}
-
-#[derive(Clone)]
-pub struct MockConnection {
-    handler: Handler,
-    port: u16,
-}
-
-impl Connect for MockConnection {
-    fn connect<'a, T>(info: T) -> RedisFuture<'a, Self>
-    where
-        T: IntoConnectionInfo + Send + 'a,
-    {
-        let info = info.into_connection_info().unwrap();
-
-        let (name, port) = match &*info.addr {
-            redis::ConnectionAddr::Tcp(addr, port) => (addr, *port),
-            _ => unreachable!(),
-        };
-        Box::pin(future::ok(MockConnection {
-            handler: HANDLERS
-                .read()
-                .unwrap()
-                .get(name)
-                .unwrap_or_else(|| panic!("Handler `{}` were not installed", name))
-                .clone(),
-            port,
-        }))
-    }
-}
-
-fn contains_slice(xs: &[u8], ys: &[u8]) -> bool {
-    for i in 0..xs.len() {
-        if xs[i..].starts_with(ys) {
-            return true;
-        }
-    }
-    false
-}
-
-fn respond_startup(name: &str, cmd: &[u8]) -> Result<(), RedisResult<Value>> {
-    if contains_slice(&cmd, b"PING") {
-        Err(Ok(Value::Status("OK".into())))
-    } else if contains_slice(&cmd, b"CLUSTER") && contains_slice(&cmd, b"SLOTS") {
-        Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
-            Value::Int(0),
-            Value::Int(16383),
-            Value::Bulk(vec![
-                Value::Data(name.as_bytes().to_vec()),
-                Value::Int(6379),
-            ]),
-        ])])))
-    } else {
-        Ok(())
-    }
-}
-
-impl ConnectionLike for MockConnection {
-    fn req_packed_command<'a>(&'a mut self, cmd: &'a redis::Cmd) -> RedisFuture<'a, Value> {
-        Box::pin(future::ready(
-            (self.handler)(&cmd, self.port).expect_err("Handler did not specify a response"),
-        ))
-    }
-
-    fn req_packed_commands<'a>(
-        &'a mut self,
-        _pipeline: &'a redis::Pipeline,
-        _offset: usize,
-        _count: usize,
-    ) -> RedisFuture<'a, Vec<Value>> {
-        Box::pin(future::ok(vec![]))
-    }
-
-    fn get_db(&self) -> i64 {
-        0
-    }
-}
-
-pub struct MockEnv {
-    runtime: Runtime,
-    client: redis_cluster_async::Client,
-    connection: redis_cluster_async::Connection<MockConnection>,
-    #[allow(unused)]
-    handler: RemoveHandler,
-}
-
-struct RemoveHandler(String);
-
-impl Drop for RemoveHandler {
-    fn drop(&mut self) {
-        HANDLERS.write().unwrap().remove(&self.0);
-    }
-}
-
-impl MockEnv {
-    fn new(
-        id: &str,
-        handler: impl Fn(&[u8], u16) -> Result<(), RedisResult<Value>> + Send + Sync + 'static,
-    ) -> Self {
-        let mut runtime = tokio::runtime::Builder::new()
-            .basic_scheduler()
-            .enable_io()
-            .enable_time()
-            .build()
-            .unwrap();
-
-        let id = id.to_string();
-        HANDLERS.write().unwrap().insert(
-            id.clone(),
-            Arc::new(move |cmd, port| handler(&cmd.get_packed_command(), port)),
-        );
-
-        let client = Client::open(vec![&*format!("redis://{}", id)]).unwrap();
-        let connection = runtime.block_on(client.get_generic_connection()).unwrap();
-        MockEnv {
-            runtime,
-            client,
-            connection,
-            handler: RemoveHandler(id),
-        }
-    }
-}
-
 #[test]
 fn tryagain_simple() {
     let _ = env_logger::try_init();
@@ -207,10 +89,27 @@ fn tryagain_exhaust_retries() {
             .query_async::<_, Option<i32>>(&mut connection),
     );
 
+    let err = result.unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::TryAgain);
+    assert_eq!(err.code(), Some("TRYAGAIN"));
+    let node = format!(
+        "redis://{}:6379 TRYAGAIN \"An error was signalled by the server: mock\"",
+        name
+    );
     assert_eq!(
-        result.map_err(|err| err.to_string()),
-        Err("An error was signalled by the server: mock".to_string())
+        err.to_string(),
+        format!(
+            "An error was signalled by the server: mock (redis_cluster: 3 failed attempts: \
+             {0}, {0}, {0})",
+            node
+        )
     );
+    let cluster_error = ClusterError::from_redis_error(&err).unwrap();
+    assert_eq!(cluster_error.attempts().len(), 3);
+    assert!(cluster_error
+        .attempts()
+        .iter()
+        .all(|attempt| attempt.kind == redis::ErrorKind::TryAgain));
     assert_eq!(requests.load(atomic::Ordering::SeqCst), 3);
 }
 
@@ -232,7 +131,7 @@ fn rebuild_with_extra_nodes() {
         }
         started.store(true, atomic::Ordering::SeqCst);
 
-        if contains_slice(&cmd, b"PING") {
+        if contains_slice(cmd, b"PING") {
             return Err(Ok(Value::Status("OK".into())));
         }
 
@@ -278,3 +177,2064 @@ fn rebuild_with_extra_nodes() {
 
     assert_eq!(value, Ok(Some(123)));
 }
+
+#[test]
+fn tryagain_timeout() {
+    let _ = env_logger::try_init();
+    let name = "tryagain_timeout";
+
+    let MockEnv {
+        mut runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], _| {
+        respond_startup(name, cmd)?;
+        Err(parse_redis_value(b"-TRYAGAIN mock\r\n"))
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_timeout(Some(Duration::from_millis(50)))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    let result = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, Option<i32>>(&mut connection),
+    );
+
+    let err = result.unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::IoError);
+    assert!(err.is_timeout());
+    assert!(ClusterError::from_redis_error(&err).unwrap().timed_out());
+}
+
+#[test]
+fn sync_connection() {
+    let _ = env_logger::try_init();
+    let name = "sync_connection";
+
+    let requests = atomic::AtomicUsize::new(0);
+    let MockEnv {
+        client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], _| {
+        respond_startup(name, cmd)?;
+
+        match requests.fetch_add(1, atomic::Ordering::SeqCst) {
+            0 => Err(parse_redis_value(b"-TRYAGAIN mock\r\n")),
+            _ => Err(Ok(Value::Data(b"123".to_vec()))),
+        }
+    });
+
+    let mut connection = client
+        .get_generic_sync_connection::<MockConnection>()
+        .unwrap();
+    let value: Option<i32> = connection.get("test").unwrap();
+
+    assert_eq!(value, Some(123));
+    assert!(redis::ConnectionLike::check_connection(&mut connection));
+}
+
+#[test]
+fn ask_redirect() {
+    let _ = env_logger::try_init();
+    let name = "ask_redirect";
+
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        mut runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let requests = requests.clone();
+        move |cmd: &[u8], port| {
+            respond_startup(name, cmd)?;
+            let cmd = String::from_utf8_lossy(cmd);
+            let cmd = cmd.split("\r\n").nth(2).unwrap().to_string();
+            requests.lock().unwrap().push((port, cmd.clone()));
+
+            match (port, &cmd[..]) {
+                (6379, "GET") => Err(parse_redis_value(
+                    format!("-ASK 123 {}:6380\r\n", name).as_bytes(),
+                )),
+                (6380, "ASKING") => Err(Ok(Value::Okay)),
+                (6380, "GET") => Err(Ok(Value::Data(b"123".to_vec()))),
+                _ => panic!("Unexpected request {} to {}", cmd, port),
+            }
+        }
+    });
+
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, Option<i32>>(&mut connection),
+    );
+
+    assert_eq!(value, Ok(Some(123)));
+    assert_eq!(
+        *requests.lock().unwrap(),
+        vec![
+            (6379, "GET".to_string()),
+            (6380, "ASKING".to_string()),
+            (6380, "GET".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn query_node() {
+    let _ = env_logger::try_init();
+    let name = "query_node";
+
+    let MockEnv {
+        mut runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup(name, cmd)?;
+        match port {
+            6381 => Err(Ok(Value::Data(b"replica".to_vec()))),
+            _ => Err(parse_redis_value(b"-ERR wrong node\r\n")),
+        }
+    });
+
+    let value: String = runtime
+        .block_on(connection.query_node(&format!("redis://{}:6381", name), &cmd("ROLE")))
+        .unwrap();
+    assert_eq!(value, "replica");
+
+    let result = runtime.block_on(
+        connection.query_node::<String>(&format!("redis://{}:6379", name), &cmd("ROLE")),
+    );
+    assert_eq!(
+        result.map_err(|err| err.code().map(String::from)),
+        Err(Some("ERR".to_string()))
+    );
+}
+
+#[test]
+fn failover() {
+    let _ = env_logger::try_init();
+    let name = "failover";
+
+    let promoted = Arc::new(atomic::AtomicBool::new(false));
+    let gets = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        mut runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let promoted = promoted.clone();
+        let gets = gets.clone();
+        move |cmd: &[u8], port| {
+            let master_port: u16 = if promoted.load(atomic::Ordering::SeqCst) {
+                6380
+            } else {
+                6379
+            };
+            if contains_slice(cmd, b"SLOTS") {
+                return Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
+                    Value::Int(0),
+                    Value::Int(16383),
+                    Value::Bulk(vec![
+                        Value::Data(name.as_bytes().to_vec()),
+                        Value::Int(master_port.into()),
+                    ]),
+                ])])));
+            }
+            respond_startup(name, cmd)?;
+
+            if contains_slice(cmd, b"NODES") {
+                let role = |node_port| match (node_port == port, node_port == master_port) {
+                    (true, true) => "myself,master -",
+                    (false, true) => "master -",
+                    (true, false) if node_port == 6379 => "myself,slave id6380",
+                    (true, false) => "myself,slave id6379",
+                    (false, false) if node_port == 6379 => "slave id6380",
+                    (false, false) => "slave id6379",
+                };
+                let nodes = [6379u16, 6380]
+                    .iter()
+                    .map(|&node_port| {
+                        let slots = if node_port == master_port {
+                            " 0-16383"
+                        } else {
+                            ""
+                        };
+                        format!(
+                            "id{} {}:{}@1{} {} 0 0 1 connected{}\n",
+                            node_port,
+                            name,
+                            node_port,
+                            node_port,
+                            role(node_port),
+                            slots
+                        )
+                    })
+                    .collect::<String>();
+                Err(Ok(Value::Data(nodes.into_bytes())))
+            } else if contains_slice(cmd, b"FAILOVER") {
+                assert_eq!(port, 6380);
+                assert!(contains_slice(cmd, b"FORCE"));
+                promoted.store(true, atomic::Ordering::SeqCst);
+                Err(Ok(Value::Okay))
+            } else if contains_slice(cmd, b"GET") {
+                gets.lock().unwrap().push(port);
+                if port == master_port {
+                    Err(Ok(Value::Data(b"123".to_vec())))
+                } else {
+                    Err(parse_redis_value(
+                        format!("-MOVED 123 {}:{}\r\n", name, master_port).as_bytes(),
+                    ))
+                }
+            } else {
+                panic!("Unexpected command {}", String::from_utf8_lossy(cmd))
+            }
+        }
+    });
+
+    let report = runtime
+        .block_on(connection.failover(&format!("redis://{}:6380", name), FailoverMode::Force))
+        .unwrap();
+    assert_eq!(report.new_master, format!("redis://{}:6380", name));
+    assert_eq!(report.old_master, format!("redis://{}:6379", name));
+
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, Option<i32>>(&mut connection),
+    );
+    assert_eq!(value, Ok(Some(123)));
+    // The slot map already points to the new master, so the request was not redirected
+    assert_eq!(*gets.lock().unwrap(), vec![6380]);
+}
+
+type Received = Arc<Mutex<Vec<(u16, String)>>>;
+
+// Standalone nodes on 127.0.0.1:7000-7003 which only see each other once they were all met if
+// `meet`, and which reject `CLUSTER ADDSLOTSRANGE` like redis 6 unless `addslotsrange`. Returns
+// the commands they received, apart from `CLUSTER INFO` and `CLUSTER NODES`.
+fn standalone_nodes(meet: bool, addslotsrange: bool) -> (HandlerGuard, Received) {
+    let commands = Arc::new(Mutex::new(Vec::new()));
+    let state = Mutex::new((0, Vec::new()));
+    let guard = register_handler("127.0.0.1", {
+        let commands = commands.clone();
+        move |cmd: &[u8], port| {
+            let cmd = String::from_utf8_lossy(cmd);
+            let args = cmd.split("\r\n").skip(2).step_by(2).collect::<Vec<_>>();
+            let mut state = state.lock().unwrap();
+            let (meets, replicas) = &mut *state;
+            let formed = *meets == 3;
+            let reply = match &args[..] {
+                ["CLUSTER", "INFO"] => {
+                    let info = format!(
+                        "cluster_state:{}\r\ncluster_slots_assigned:{}\r\ncluster_slots_ok:0\r\n\
+                         cluster_slots_pfail:0\r\ncluster_slots_fail:0\r\n\
+                         cluster_known_nodes:{}\r\ncluster_size:2\r\n\
+                         cluster_current_epoch:0\r\ncluster_my_epoch:0\r\n",
+                        if replicas.len() == 2 { "ok" } else { "fail" },
+                        if formed { 16384 } else { 0 },
+                        if formed { 4 } else { 1 },
+                    );
+                    return Err(Ok(Value::Data(info.into_bytes())));
+                }
+                ["CLUSTER", "NODES"] => {
+                    let node = |node_port: u16| {
+                        let role = match replicas.iter().find(|(port, _)| *port == node_port) {
+                            Some((_, master)) => format!("slave {}", master),
+                            None => "master -".to_string(),
+                        };
+                        let myself = if node_port == port { "myself," } else { "" };
+                        format!(
+                            "id{0} 127.0.0.1:{0}@1{0} {1}{2} 0 0 1 connected\n",
+                            node_port, myself, role
+                        )
+                    };
+                    let nodes = if formed {
+                        (7000..7004).map(node).collect()
+                    } else {
+                        node(port)
+                    };
+                    return Err(Ok(Value::Data(nodes.into_bytes())));
+                }
+                ["CLUSTER", "MYID"] => Value::Data(format!("id{}", port).into_bytes()),
+                ["CLUSTER", "MEET", ..] if meet => {
+                    *meets += 1;
+                    Value::Okay
+                }
+                ["CLUSTER", "REPLICATE", master] => {
+                    replicas.push((port, master.to_string()));
+                    Value::Okay
+                }
+                ["CLUSTER", "ADDSLOTSRANGE", ..] if !addslotsrange => {
+                    let unknown = b"-ERR Unknown subcommand 'ADDSLOTSRANGE'\r\n";
+                    return Err(parse_redis_value(unknown));
+                }
+                _ => Value::Okay,
+            };
+            commands.lock().unwrap().push((port, args.join(" ")));
+            Err(Ok(reply))
+        }
+    });
+    (guard, commands)
+}
+
+#[test]
+fn cluster_setup() {
+    let _ = env_logger::try_init();
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let nodes = (7000..7004)
+        .map(|port| format!("redis://127.0.0.1:{}", port))
+        .collect::<Vec<_>>();
+    let mut setup = ClusterSetup::open(nodes).unwrap();
+    setup.set_replicas(1).set_timeout(Duration::from_secs(5));
+
+    let (handler, commands) = standalone_nodes(true, true);
+    runtime
+        .block_on(setup.create_generic::<MockConnection>())
+        .unwrap();
+    drop(handler);
+    let expected = [
+        (7000, "CLUSTER MYID"),
+        (7001, "CLUSTER MYID"),
+        (7002, "CLUSTER MYID"),
+        (7003, "CLUSTER MYID"),
+        (7000, "CLUSTER ADDSLOTSRANGE 0 8191"),
+        (7001, "CLUSTER ADDSLOTSRANGE 8192 16383"),
+        (7000, "CLUSTER MEET 127.0.0.1 7001"),
+        (7000, "CLUSTER MEET 127.0.0.1 7002"),
+        (7000, "CLUSTER MEET 127.0.0.1 7003"),
+        (7002, "CLUSTER REPLICATE id7000"),
+        (7003, "CLUSTER REPLICATE id7001"),
+    ];
+    let expected = expected
+        .iter()
+        .map(|&(port, command)| (port, command.to_string()))
+        .collect::<Vec<_>>();
+    assert_eq!(*commands.lock().unwrap(), expected);
+
+    // Nodes older than redis 7 are given every slot
+    let (handler, commands) = standalone_nodes(true, false);
+    runtime
+        .block_on(setup.create_generic::<MockConnection>())
+        .unwrap();
+    drop(handler);
+    let addslots = |port: u16, slots: std::ops::RangeInclusive<u16>| {
+        let slots = slots.map(|slot| slot.to_string()).collect::<Vec<_>>();
+        (port, format!("CLUSTER ADDSLOTS {}", slots.join(" ")))
+    };
+    let commands = commands.lock().unwrap();
+    assert_eq!(commands[4], addslots(7000, 0..=8191));
+    assert_eq!(commands[5], addslots(7001, 8192..=16383));
+    drop(commands);
+
+    // The nodes never see each other
+    let (_handler, _) = standalone_nodes(false, true);
+    let clock = VirtualClock::new();
+    setup.set_clock(Arc::new(clock.clone()));
+    let result = runtime.block_on(clock.run(setup.create_generic::<MockConnection>()));
+    let message = result.err().unwrap().to_string();
+    assert!(
+        message.contains("Timed out waiting for the cluster"),
+        "{}",
+        message
+    );
+    assert_eq!(clock.elapsed(), Duration::from_secs(5));
+}
+
+#[test]
+fn failover_timeout() {
+    let _ = env_logger::try_init();
+    let name = "failover_timeout";
+
+    let polls = Arc::new(atomic::AtomicUsize::new(0));
+    let pings = Arc::new(Mutex::new(HashMap::new()));
+    let mut env = MockEnv::new(name, {
+        let polls = polls.clone();
+        let pings = pings.clone();
+        move |cmd: &[u8], port| {
+            // Every connection is checked with a PING when it is opened
+            if contains_slice(cmd, b"PING") {
+                *pings.lock().unwrap().entry(port).or_insert(0) += 1;
+            }
+            respond_startup(name, cmd)?;
+            if contains_slice(cmd, b"NODES") {
+                polls.fetch_add(1, atomic::Ordering::SeqCst);
+                // The replica is never promoted
+                let nodes = format!(
+                    "id6380 {0}:6380@16380 myself,slave id6379 0 0 1 connected\n\
+                     id6379 {0}:6379@16379 master - 0 0 1 connected 0-16383\n",
+                    name
+                );
+                Err(Ok(Value::Data(nodes.into_bytes())))
+            } else if contains_slice(cmd, b"FAILOVER") {
+                Err(Ok(Value::Okay))
+            } else {
+                panic!("Unexpected command {}", String::from_utf8_lossy(cmd))
+            }
+        }
+    });
+    let clock = VirtualClock::new();
+    env.client.set_clock(Arc::new(clock.clone()));
+    let mut connection = env
+        .runtime
+        .block_on(env.client.get_generic_connection::<MockConnection>())
+        .unwrap();
+
+    let replica = format!("redis://{}:6380", name);
+    let result = env
+        .runtime
+        .block_on(clock.run(connection.failover(&replica, FailoverMode::Default)));
+    let err = result.unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::IoError, "{}", err);
+    let message = err.to_string();
+    assert!(message.contains("Timed out waiting for the failover"), "{}", message);
+    assert_eq!(clock.elapsed(), Duration::from_secs(60));
+    // Polled every 100ms until the timeout
+    assert!(polls.load(atomic::Ordering::SeqCst) > 600);
+    // The replica is outside the pool, so reading its view and failing it over open a connection
+    // each, while polling it reuses a single one
+    assert_eq!(pings.lock().unwrap().get(&6380), Some(&3));
+}
+
+#[test]
+fn keys_in_slot() {
+    let _ = env_logger::try_init();
+    let name = "keys_in_slot";
+
+    let counts = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        mut runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let counts = counts.clone();
+        move |cmd: &[u8], _| {
+            respond_startup(name, cmd)?;
+            let cmd = String::from_utf8_lossy(cmd);
+            let args = cmd.split("\r\n").skip(2).step_by(2).collect::<Vec<_>>();
+            match &args[..] {
+                ["CLUSTER", "GETKEYSINSLOT", slot, count] => {
+                    let count = count.parse::<usize>().unwrap();
+                    // Only slot 5 holds any keys
+                    let stored = if *slot == "5" { 250 } else { 0 };
+                    counts.lock().unwrap().push(count);
+                    Err(Ok(Value::Bulk(
+                        (0..count.min(stored))
+                            .map(|i| Value::Data(format!("key{}", i).into_bytes()))
+                            .collect(),
+                    )))
+                }
+                ["CLUSTER", "COUNTKEYSINSLOT", slot] => {
+                    Err(Ok(Value::Int(slot.parse::<i64>().unwrap() % 3)))
+                }
+                _ => panic!("Unexpected command {:?}", args),
+            }
+        }
+    });
+
+    let keys = runtime
+        .block_on(connection.keys_in_slot::<String>(5).try_collect::<Vec<_>>())
+        .unwrap();
+    assert_eq!(
+        keys,
+        (0..250).map(|i| format!("key{}", i)).collect::<Vec<_>>()
+    );
+    // Each page asks for the keys already read and 100 more
+    assert_eq!(*counts.lock().unwrap(), vec![100, 200, 300]);
+
+    let keys = runtime
+        .block_on(connection.keys_in_slot::<String>(3).try_collect::<Vec<_>>())
+        .unwrap();
+    assert!(keys.is_empty());
+    assert_eq!(*counts.lock().unwrap(), vec![100, 200, 300, 100]);
+
+    let counts = runtime.block_on(connection.slot_key_counts()).unwrap();
+    assert_eq!(counts.len(), 16384);
+    assert_eq!(&counts[..4], &[0, 1, 2, 0]);
+    assert_eq!(counts.iter().sum::<u64>(), 16383);
+}
+
+#[test]
+fn moved_to_redirect_target() {
+    let _ = env_logger::try_init();
+    let name = "moved_to_redirect_target";
+
+    let ports = Arc::new(Mutex::new(Vec::new()));
+    let slot_refreshes = Arc::new(atomic::AtomicUsize::new(0));
+    let MockEnv {
+        mut runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let ports = ports.clone();
+        let slot_refreshes = slot_refreshes.clone();
+        move |cmd: &[u8], port| {
+            if contains_slice(cmd, b"SLOTS") {
+                slot_refreshes.fetch_add(1, atomic::Ordering::SeqCst);
+            }
+            respond_startup(name, cmd)?;
+            ports.lock().unwrap().push(port);
+            match port {
+                6379 => Err(parse_redis_value(
+                    format!("-MOVED 123 {}:6381\r\n", name).as_bytes(),
+                )),
+                _ => Err(Ok(Value::Data(b"123".to_vec()))),
+            }
+        }
+    });
+    let initial_refreshes = slot_refreshes.load(atomic::Ordering::SeqCst);
+
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, Option<i32>>(&mut connection),
+    );
+    assert_eq!(value, Ok(Some(123)));
+
+    // The retry goes to the node named by MOVED rather than a random one
+    assert_eq!(*ports.lock().unwrap(), vec![6379, 6381]);
+    assert!(slot_refreshes.load(atomic::Ordering::SeqCst) > initial_refreshes);
+}
+
+#[test]
+fn metrics() {
+    let _ = env_logger::try_init();
+    let name = "metrics";
+
+    let MockEnv {
+        mut runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup(name, cmd)?;
+        match port {
+            6379 => Err(parse_redis_value(
+                format!("-MOVED 123 {}:6380\r\n", name).as_bytes(),
+            )),
+            _ => Err(Ok(Value::Data(b"123".to_vec()))),
+        }
+    });
+
+    let metrics = Arc::new(CountingMetrics::new());
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_metrics(metrics.clone())
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    assert_eq!(metrics.snapshot().slot_refreshes, 1);
+    metrics.reset();
+
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, Option<i32>>(&mut connection),
+    );
+    assert_eq!(value, Ok(Some(123)));
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.requests, 2);
+    assert_eq!(snapshot.failed_requests, 1);
+    assert_eq!(snapshot.moved, 1);
+    assert_eq!(snapshot.retries, 1);
+    assert_eq!(snapshot.connections_opened, 1);
+    // MOVED means that the slot map is outdated
+    assert_eq!(snapshot.slot_refreshes, 1);
+    assert_eq!(snapshot.requests_by_command["GET"], 2);
+    assert_eq!(snapshot.requests_by_node[&format!("redis://{}:6379", name)], 1);
+    assert_eq!(snapshot.requests_by_node[&format!("redis://{}:6380", name)], 1);
+}
+
+#[test]
+fn topology() {
+    let _ = env_logger::try_init();
+    let name = "topology";
+
+    let moved = atomic::AtomicBool::new(false);
+    let MockEnv {
+        mut runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        if contains_slice(cmd, b"PING") {
+            return Err(Ok(Value::Status("OK".into())));
+        }
+        let node = |port: i64| {
+            Value::Bulk(vec![
+                Value::Data(name.as_bytes().to_vec()),
+                Value::Int(port),
+            ])
+        };
+        if contains_slice(cmd, b"CLUSTER") && contains_slice(cmd, b"SLOTS") {
+            // After the MOVED the second master has taken over every slot
+            let split = if moved.load(atomic::Ordering::SeqCst) {
+                0
+            } else {
+                8000
+            };
+            let mut ranges = Vec::new();
+            if split > 0 {
+                ranges.push(Value::Bulk(vec![
+                    Value::Int(0),
+                    Value::Int(split - 1),
+                    node(6379),
+                    node(6381),
+                ]));
+            }
+            ranges.push(Value::Bulk(vec![
+                Value::Int(split),
+                Value::Int(16383),
+                node(6380),
+                node(6382),
+            ]));
+            return Err(Ok(Value::Bulk(ranges)));
+        }
+        match port {
+            6379 => {
+                moved.store(true, atomic::Ordering::SeqCst);
+                Err(parse_redis_value(
+                    format!("-MOVED 5 {}:6380\r\n", name).as_bytes(),
+                ))
+            }
+            _ => Err(Ok(Value::Data(b"123".to_vec()))),
+        }
+    });
+
+    let addr = |port: u16| format!("redis://{}:{}", name, port);
+
+    let slots = runtime.block_on(connection.slots()).unwrap();
+    assert_eq!(
+        slots.ranges,
+        vec![
+            SlotRange {
+                start: 0,
+                end: 7999,
+                master: addr(6379),
+                replicas: vec![addr(6381)],
+            },
+            SlotRange {
+                start: 8000,
+                end: 16383,
+                master: addr(6380),
+                replicas: vec![addr(6382)],
+            },
+        ]
+    );
+    let nodes = runtime.block_on(connection.nodes()).unwrap();
+    assert_eq!(nodes.nodes, vec![addr(6379), addr(6380)]);
+    assert_eq!(nodes.refreshed_at, slots.refreshed_at);
+    let node = runtime.block_on(connection.node_for_key(b"b")).unwrap();
+    assert_eq!(node, addr(6379));
+
+    let events = runtime.block_on(connection.topology_events()).unwrap();
+
+    // "b" is in slot 3300
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("b")
+            .query_async::<_, Option<i32>>(&mut connection),
+    );
+    assert_eq!(value, Ok(Some(123)));
+
+    // The refresh triggered by the MOVED is waited for
+    let refreshed = runtime.block_on(connection.slots()).unwrap();
+    assert_eq!(
+        refreshed.ranges,
+        vec![SlotRange {
+            start: 0,
+            end: 16383,
+            master: addr(6380),
+            replicas: vec![addr(6382)],
+        }]
+    );
+    assert!(refreshed.refreshed_at > slots.refreshed_at);
+    let nodes = runtime.block_on(connection.nodes()).unwrap();
+    assert_eq!(nodes.nodes, vec![addr(6380)]);
+    let node = runtime.block_on(connection.node_for_key(b"b")).unwrap();
+    assert_eq!(node, addr(6380));
+
+    drop(connection);
+    let events = runtime.block_on(events.collect::<Vec<_>>());
+    assert_eq!(
+        events,
+        vec![
+            TopologyEvent::SlotsReassigned {
+                start: 0,
+                end: 7999,
+                from: Some(addr(6379)),
+                to: addr(6380),
+            },
+            TopologyEvent::NodeRemoved(addr(6379)),
+            TopologyEvent::NodeRemoved(addr(6381)),
+            TopologyEvent::RecoveryFinished,
+        ]
+    );
+}
+
+#[test]
+fn refresh_backoff() {
+    let _ = env_logger::try_init();
+    let name = "refresh_backoff";
+
+    let slots_requests = Arc::new(atomic::AtomicU32::new(0));
+    let moved = atomic::AtomicBool::new(false);
+    let mut env = MockEnv::new(name, {
+        let slots_requests = slots_requests.clone();
+        move |cmd: &[u8], port| {
+            if contains_slice(cmd, b"PING") {
+                return Err(Ok(Value::Status("OK".into())));
+            }
+            if contains_slice(cmd, b"SLOTS") {
+                // The first two requests come from the connections, the next three refreshes fail
+                let requests = slots_requests.fetch_add(1, atomic::Ordering::SeqCst);
+                if (2..5).contains(&requests) {
+                    return Err(parse_redis_value(b"-ERR no slots\r\n"));
+                }
+                return Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
+                    Value::Int(0),
+                    Value::Int(16383),
+                    Value::Bulk(vec![
+                        Value::Data(name.as_bytes().to_vec()),
+                        Value::Int(6379),
+                    ]),
+                ])])));
+            }
+            if !moved.swap(true, atomic::Ordering::SeqCst) {
+                return Err(parse_redis_value(
+                    format!("-MOVED 5 {}:{}\r\n", name, port).as_bytes(),
+                ));
+            }
+            Err(Ok(Value::Data(b"123".to_vec())))
+        }
+    });
+    let clock = VirtualClock::new();
+    env.client.set_clock(Arc::new(clock.clone()));
+    let mut connection = env
+        .runtime
+        .block_on(env.client.get_generic_connection::<MockConnection>())
+        .unwrap();
+    let events = env.runtime.block_on(connection.topology_events()).unwrap();
+
+    let value = env.runtime.block_on(
+        cmd("GET")
+            .arg("b")
+            .query_async::<_, Option<i32>>(&mut connection),
+    );
+    assert_eq!(value, Ok(Some(123)));
+    // Waits for the refresh triggered by the MOVED. The slot map is not answered while the slots
+    // are refreshed, so each failed refresh fails the requests waiting for it. The first refresh
+    // failed before the GET was answered.
+    let mut failures = Vec::new();
+    while let Err(err) = env.runtime.block_on(clock.run(connection.slots())) {
+        failures.push(err.to_string());
+    }
+    let failure = "redis_cluster: Unable to refresh slots: \
+                   An error was signalled by the server: no slots";
+    assert_eq!(failures, vec![failure; 2]);
+
+    assert_eq!(slots_requests.load(atomic::Ordering::SeqCst), 6);
+    // Each retry waits twice as long as the one before
+    assert_eq!(clock.elapsed(), Duration::from_millis(20 + 40 + 80));
+
+    drop(connection);
+    let events = env.runtime.block_on(events.collect::<Vec<_>>());
+    assert_eq!(
+        events,
+        vec![
+            TopologyEvent::RefreshFailed {
+                kind: redis::ErrorKind::ResponseError,
+                message: "An error was signalled by the server: no slots".to_string(),
+            },
+            TopologyEvent::RecoveryFinished,
+        ]
+    );
+}
+
+#[test]
+fn pipeline_per_node() {
+    let _ = env_logger::try_init();
+    let name = "pipeline_per_node";
+
+    let mut slots = MockSlots::new(name);
+    slots.add(0, 8191, 6379, &[]).add(8192, 16383, 6380, &[]);
+    let startup = slots.clone();
+    let MockEnv {
+        mut runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(
+        name,
+        per_node(vec![
+            (
+                6379,
+                Box::new(move |cmd: &[u8]| {
+                    startup.respond(cmd)?;
+                    Err(moved(3300, name, 6380))
+                }) as Box<dyn Fn(&[u8]) -> _ + Send + Sync>,
+            ),
+            (
+                6380,
+                Box::new(move |cmd: &[u8]| {
+                    slots.respond(cmd)?;
+                    if contains_slice(cmd, b"GET") {
+                        Err(Ok(Value::Data(b"1".to_vec())))
+                    } else {
+                        Err(Ok(Value::Okay))
+                    }
+                }),
+            ),
+        ]),
+    );
+
+    // "b" is in slot 3300, which the first node says has moved
+    let values = runtime
+        .block_on(
+            redis::pipe()
+                .cmd("SET")
+                .arg("b")
+                .arg(1)
+                .ignore()
+                .cmd("GET")
+                .arg("b")
+                .query_async::<_, Vec<i32>>(&mut connection),
+        )
+        .unwrap();
+    assert_eq!(values, vec![1]);
+}
+
+#[test]
+fn atomic_pipeline() {
+    let _ = env_logger::try_init();
+    let name = "atomic_pipeline";
+
+    let counter = atomic::AtomicI64::new(0);
+    let MockEnv {
+        mut runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], _| {
+        respond_startup(name, cmd)?;
+        assert!(contains_slice(cmd, b"INCR"));
+        let value = counter.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+        Err(Ok(Value::Int(value)))
+    });
+
+    let values = runtime
+        .block_on(
+            redis::pipe()
+                .atomic()
+                .incr("{test}counter", 1)
+                .incr("{test}counter", 1)
+                .query_async::<_, (i32, i32)>(&mut connection),
+        )
+        .unwrap();
+    assert_eq!(values, (1, 2));
+}
+
+#[test]
+fn cluster_info_on_random_node() {
+    let _ = env_logger::try_init();
+    let name = "cluster_info_on_random_node";
+
+    const INFO: &str = "cluster_state:ok\r\ncluster_slots_assigned:16384\r\n\
+        cluster_slots_ok:16384\r\ncluster_slots_pfail:0\r\ncluster_slots_fail:0\r\n\
+        cluster_known_nodes:2\r\ncluster_size:2\r\ncluster_current_epoch:2\r\n\
+        cluster_my_epoch:1\r\n";
+
+    let received = Arc::new(Mutex::new(HashMap::new()));
+    let mut slots = MockSlots::new(name);
+    slots.add(0, 8191, 6379, &[]).add(8192, 16383, 6380, &[]);
+    let mut env = MockEnv::new(name, {
+        let received = received.clone();
+        move |cmd: &[u8], port| {
+            slots.respond(cmd)?;
+            assert!(contains_slice(cmd, b"INFO"));
+            *received.lock().unwrap().entry(port).or_insert(0) += 1;
+            Err(Ok(Value::Data(INFO.as_bytes().to_vec())))
+        }
+    });
+    env.client.set_rng_seed(1);
+    let mut connection = env
+        .runtime
+        .block_on(env.client.get_generic_connection::<MockConnection>())
+        .unwrap();
+
+    // Routed by the hash of "INFO" every request would reach the same master
+    for _ in 0..20 {
+        let info = env.runtime.block_on(connection.cluster_info()).unwrap();
+        assert!(info.state_ok);
+    }
+    let received = received.lock().unwrap();
+    assert_eq!(received.values().sum::<usize>(), 20);
+    assert_eq!(received.len(), 2, "{:?}", received);
+}
+
+#[test]
+fn scenario() {
+    let _ = env_logger::try_init();
+
+    let mut scenario = Scenario::new("scenario");
+    scenario.slots(0, 8191, 6379).slots(8192, 16383, 6380);
+    scenario.set_default_reply(Value::Data(b"3".to_vec()));
+    // "b" is in slot 3300
+    scenario
+        .node(6379)
+        .moved(3300, 6380)
+        .times(2)
+        .timeout()
+        .move_slots(0, 8191, 6381);
+    scenario.node(6380).reply(Value::Data(b"1".to_vec())).times(2);
+
+    let MockEnv {
+        mut runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = scenario.env();
+
+    let values = (0..3)
+        .map(|_| {
+            runtime.block_on(
+                cmd("GET")
+                    .arg("b")
+                    .query_async::<_, Option<i32>>(&mut connection),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(values, vec![Ok(Some(1)), Ok(Some(1)), Ok(Some(3))]);
+
+    let requests = scenario
+        .requests()
+        .into_iter()
+        .map(|(port, request)| {
+            assert_eq!(request, "GET b");
+            port
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        requests,
+        vec![
+            6379, 6380, // MOVED
+            6379, 6380, // MOVED
+            6379, // Timed out, so another node is tried
+            6380, // MOVED back to 6379
+            6379, // Moves the slots and answers MOVED
+            6381,
+        ]
+    );
+    // Connecting and one refresh after each MOVED
+    assert_eq!(scenario.refreshes(), 4);
+}
+
+fn action() -> impl Strategy<Value = Action> {
+    prop_oneof![
+        Just(Action::TryAgain),
+        Just(Action::ClusterDown),
+        Just(Action::Timeout),
+        Just(Action::Error("ERR simulated".into())),
+        Just(Action::Reply(Value::Data(b"1".to_vec()))),
+        (6379..6381u16).prop_map(|to| Action::Moved { slot: 3300, to }),
+        (6379..6381u16).prop_map(|to| Action::Ask { slot: 3300, to }),
+    ]
+}
+
+type Simulation = (Vec<Result<Option<i32>, String>>, Vec<(u16, String)>);
+
+// Runs `requests` concurrent `GET b` through a scenario, returning the results and the requests
+// the nodes received
+fn simulate(
+    name: &str,
+    seed: u64,
+    scripts: &[Vec<Action>],
+    requests: usize,
+) -> Simulation {
+    let mut scenario = Scenario::new(name);
+    scenario.slots(0, 8191, 6379).slots(8192, 16383, 6380);
+    for (port, script) in (6379..).zip(scripts) {
+        let mut node = scenario.node(port);
+        for action in script {
+            node.then(action.clone());
+        }
+    }
+
+    let clock = VirtualClock::new();
+    let mut env = scenario.env();
+    env.client
+        .set_clock(Arc::new(clock.clone()))
+        .set_rng_seed(seed);
+    let connection = env
+        .runtime
+        .block_on(env.client.get_generic_connection::<MockConnection>())
+        .unwrap();
+
+    let results = env.runtime.block_on(clock.run(future::join_all((0..requests).map(|_| {
+        let mut connection = connection.clone();
+        async move {
+            cmd("GET")
+                .arg("b")
+                .query_async::<_, Option<i32>>(&mut connection)
+                .await
+                .map_err(|err| err.to_string())
+        }
+    }))));
+    (results, scenario.requests())
+}
+
+#[test]
+fn simulation() {
+    let _ = env_logger::try_init();
+    let runs = atomic::AtomicUsize::new(0);
+
+    proptest!(
+        ProptestConfig { cases: 200, failure_persistence: None, .. Default::default() },
+        |(
+            seed in any::<u64>(),
+            scripts in proptest::collection::vec(proptest::collection::vec(action(), 0..8), 2),
+            requests in 1..4usize,
+        )| {
+            let name = format!("simulation{}", runs.fetch_add(1, atomic::Ordering::SeqCst));
+            let (results, received) = simulate(&name, seed, &scripts, requests);
+            // `join_all` only finishes once every request has been answered
+            prop_assert_eq!(results.len(), requests);
+
+            // The same faults and seed lead to the same requests and results
+            let replay = simulate(&name, seed, &scripts, requests);
+            prop_assert_eq!((results, received), replay);
+        }
+    );
+}
+
+lazy_static::lazy_static! {
+    // Held to stop `SlowRefresh` connections from answering `CLUSTER SLOTS`
+    static ref REFRESH_GATE: futures::lock::Mutex<()> = futures::lock::Mutex::new(());
+}
+
+// A mock connection whose refreshes wait for `REFRESH_GATE`
+#[derive(Clone)]
+struct SlowRefresh(MockConnection);
+
+impl Connect for SlowRefresh {
+    fn connect<'a, T>(info: T) -> RedisFuture<'a, Self>
+    where
+        T: IntoConnectionInfo + Send + 'a,
+    {
+        MockConnection::connect(info).map_ok(SlowRefresh).boxed()
+    }
+}
+
+impl ConnectionLike for SlowRefresh {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a redis::Cmd) -> RedisFuture<'a, Value> {
+        async move {
+            if contains_slice(&cmd.get_packed_command(), b"SLOTS") {
+                let _gate = REFRESH_GATE.lock().await;
+            }
+            self.0.req_packed_command(cmd).await
+        }
+        .boxed()
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        self.0.req_packed_commands(pipeline, offset, count)
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+#[test]
+fn requests_during_refresh() {
+    let _ = env_logger::try_init();
+
+    let mut scenario = Scenario::new("requests_during_refresh");
+    scenario.slots(0, 8191, 6379).slots(8192, 16383, 6380);
+    scenario.set_default_reply(Value::Int(1));
+    // "b" is in slot 3300
+    scenario.node(6379).move_slots(0, 8191, 6380);
+
+    let mut env = scenario.env();
+    let mut connection = env
+        .runtime
+        .block_on(env.client.get_generic_connection::<SlowRefresh>())
+        .unwrap();
+
+    env.runtime.block_on(async {
+        let gate = REFRESH_GATE.lock().await;
+        let get = |key: &'static str| {
+            let mut connection = connection.clone();
+            async move {
+                let mut get = cmd("GET");
+                get.arg(key);
+                tokio::time::timeout(Duration::from_secs(5), get.query_async(&mut connection))
+                    .await
+                    .expect("Request waited for the refresh")
+            }
+        };
+        // The MOVED starts a refresh, which can not finish until the gate is released
+        assert_eq!(get("b").await, Ok(1i32));
+        assert_eq!(get("b").await, Ok(1));
+        assert_eq!(get("foo").await, Ok(1));
+        // `scenario.env()` and `get_generic_connection` both loaded the slots
+        assert_eq!(scenario.refreshes(), 2);
+        drop(gate);
+
+        let slots = connection.slots().await.unwrap();
+        assert_eq!(slots.ranges[0].master, "redis://requests_during_refresh:6380");
+    });
+    assert_eq!(scenario.refreshes(), 3);
+    assert_eq!(
+        scenario.requests(),
+        vec![
+            (6379, "GET b".to_string()),
+            (6380, "GET b".to_string()),
+            // Sent with the old slot map while the refresh was running
+            (6379, "GET b".to_string()),
+            (6380, "GET b".to_string()),
+            (6380, "GET foo".to_string()),
+        ]
+    );
+}
+
+// Sends `SET b <value>` for every value at once and returns the requests the nodes received
+fn send_in_order(scenario: &Scenario, values: &[i32]) -> Vec<(u16, String)> {
+    let clock = VirtualClock::new();
+    let mut env = scenario.env();
+    env.client.set_clock(Arc::new(clock.clone()));
+    let connection = env
+        .runtime
+        .block_on(env.client.get_generic_connection::<MockConnection>())
+        .unwrap();
+
+    let results = env
+        .runtime
+        .block_on(clock.run(future::join_all(values.iter().map(|&value| {
+            let mut connection = connection.clone();
+            async move {
+                cmd("SET")
+                    .arg("b")
+                    .arg(value)
+                    .query_async::<_, ()>(&mut connection)
+                    .await
+            }
+        }))));
+    assert!(results.iter().all(Result::is_ok), "{:?}", results);
+    scenario.requests()
+}
+
+#[test]
+fn ordering_across_tryagain() {
+    let _ = env_logger::try_init();
+
+    let mut scenario = Scenario::new("ordering_across_tryagain");
+    scenario.slots(0, 16383, 6379);
+    // Both requests are told to try again, then only the first one a second time
+    scenario.node(6379).try_again().times(3);
+
+    let requests = send_in_order(&scenario, &[1, 2]);
+    assert_eq!(
+        requests,
+        vec![
+            (6379, "SET b 1".to_string()),
+            (6379, "SET b 2".to_string()),
+            (6379, "SET b 1".to_string()),
+            (6379, "SET b 1".to_string()),
+            (6379, "SET b 2".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn ordering_across_redirects() {
+    let _ = env_logger::try_init();
+
+    let mut scenario = Scenario::new("ordering_across_redirects");
+    scenario.slots(0, 8191, 6379).slots(8192, 16383, 6380);
+    // "b" is in slot 3300. Every request is moved to 6380, which is busy with the first one
+    // before it accepts the slot.
+    scenario.node(6379).move_slots(0, 8191, 6380);
+    scenario.node(6380).try_again().ask(3300, 6381);
+
+    let requests = send_in_order(&scenario, &[1, 2, 3]);
+    assert_eq!(
+        requests,
+        vec![
+            (6379, "SET b 1".to_string()),
+            (6379, "SET b 2".to_string()),
+            (6379, "SET b 3".to_string()),
+            (6380, "SET b 1".to_string()),
+            (6380, "SET b 1".to_string()),
+            (6381, "ASKING".to_string()),
+            (6381, "SET b 1".to_string()),
+            (6380, "SET b 2".to_string()),
+            (6380, "SET b 3".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn auto_pipelining() {
+    let _ = env_logger::try_init();
+
+    let mut scenario = Scenario::new("auto_pipelining");
+    scenario.slots(0, 8191, 6379).slots(8192, 16383, 6380);
+    // "b" is in slot 3300 and "foo" in slot 12182
+    scenario.node(6379).move_slots(0, 8191, 6380);
+
+    let clock = VirtualClock::new();
+    let mut env = scenario.env();
+    env.client
+        .set_clock(Arc::new(clock.clone()))
+        .set_auto_pipelining(Duration::from_millis(1));
+    let connection = env
+        .runtime
+        .block_on(env.client.get_generic_connection::<MockConnection>())
+        .unwrap();
+
+    env.runtime.block_on(async {
+        let get = |key: &'static str| {
+            let mut connection = connection.clone();
+            tokio::spawn(async move {
+                cmd("GET")
+                    .arg(key)
+                    .query_async::<_, String>(&mut connection)
+                    .await
+            })
+        };
+        let idle = || tokio::time::delay_for(Duration::from_millis(10));
+
+        let b = get("b");
+        idle().await;
+        let foo = get("foo");
+        idle().await;
+        // Both are held until the window has passed
+        assert_eq!(scenario.requests(), vec![]);
+
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(b.await.unwrap(), Ok("OK".to_string()));
+        assert_eq!(foo.await.unwrap(), Ok("OK".to_string()));
+    });
+    assert_eq!(
+        scenario.requests(),
+        vec![
+            (6379, "GET b".to_string()),
+            (6380, "GET foo".to_string()),
+            // The redirect is sent on its own
+            (6380, "GET b".to_string()),
+        ]
+    );
+}
+
+lazy_static::lazy_static! {
+    // The commands of each pipeline sent over a `Batching` connection
+    static ref PIPELINES: Mutex<Vec<Vec<String>>> = Mutex::default();
+}
+
+// A mock connection which records the pipelines sent over it
+#[derive(Clone)]
+struct Batching(MockConnection);
+
+impl Connect for Batching {
+    fn connect<'a, T>(info: T) -> RedisFuture<'a, Self>
+    where
+        T: IntoConnectionInfo + Send + 'a,
+    {
+        MockConnection::connect(info).map_ok(Batching).boxed()
+    }
+}
+
+impl ConnectionLike for Batching {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a redis::Cmd) -> RedisFuture<'a, Value> {
+        self.0.req_packed_command(cmd)
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let cmds = pipeline
+            .cmd_iter()
+            .map(|cmd| {
+                let args = cmd.args_iter().map(|arg| match arg {
+                    redis::Arg::Simple(arg) => String::from_utf8_lossy(arg).into_owned(),
+                    redis::Arg::Cursor => "0".to_string(),
+                });
+                args.collect::<Vec<_>>().join(" ")
+            })
+            .collect();
+        PIPELINES.lock().unwrap().push(cmds);
+        self.0.req_packed_commands(pipeline, offset, count)
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+#[test]
+fn auto_pipelining_batches() {
+    let _ = env_logger::try_init();
+
+    let mut scenario = Scenario::new("auto_pipelining_batches");
+    scenario.slots(0, 16383, 6379);
+    scenario.set_default_reply(Value::Int(3));
+
+    let clock = VirtualClock::new();
+    let mut env = scenario.env();
+    env.client
+        .set_clock(Arc::new(clock.clone()))
+        .set_auto_pipelining(Duration::from_millis(1));
+    let connection = env
+        .runtime
+        .block_on(env.client.get_generic_connection::<Batching>())
+        .unwrap();
+
+    // "{b}1" to "{b}4" are in slot 3300
+    let keys = (1..=4).map(|i| format!("{{b}}{}", i)).collect::<Vec<_>>();
+    let gets = keys
+        .iter()
+        .map(|key| format!("GET {}", key))
+        .collect::<Vec<_>>();
+    let mut get_all = || {
+        // Sent in order, and held until the window has passed
+        let gets = keys.iter().map(|key| {
+            let mut connection = connection.clone();
+            async move {
+                cmd("GET")
+                    .arg(key)
+                    .query_async::<_, i64>(&mut connection)
+                    .await
+            }
+        });
+        let window = async {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            clock.advance(Duration::from_millis(1));
+        };
+        env.runtime
+            .block_on(future::join(future::join_all(gets), window))
+            .0
+    };
+
+    let mut node = scenario.node(6379);
+    for i in 1..=4 {
+        node.reply(Value::Int(i));
+    }
+    assert_eq!(get_all(), vec![Ok(1), Ok(2), Ok(3), Ok(4)]);
+    assert_eq!(
+        PIPELINES.lock().unwrap().drain(..).collect::<Vec<_>>(),
+        vec![gets.clone()]
+    );
+    assert_eq!(scenario.requests().len(), 4);
+
+    // The ASK fails the pipeline, so each command is sent again for its own reply, and only
+    // "{b}3" follows the ASK. 6380 answers it with the default reply.
+    let mut node = scenario.node(6379);
+    for _ in 0..2 {
+        node.reply(Value::Int(1))
+            .reply(Value::Int(2))
+            .ask(3300, 6380)
+            .reply(Value::Int(4));
+    }
+    assert_eq!(get_all(), vec![Ok(1), Ok(2), Ok(3), Ok(4)]);
+    assert_eq!(
+        PIPELINES.lock().unwrap().drain(..).collect::<Vec<_>>(),
+        vec![gets.clone(), vec!["ASKING".to_string(), gets[2].clone()]]
+    );
+    let mut requests = scenario.requests().split_off(4);
+    assert_eq!(
+        requests.split_off(8),
+        vec![(6380, "ASKING".to_string()), (6380, gets[2].clone())]
+    );
+    assert_eq!(
+        requests,
+        gets.iter()
+            .chain(&gets)
+            .map(|get| (6379, get.clone()))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn last_slot_of_range() {
+    let _ = env_logger::try_init();
+
+    let mut scenario = Scenario::new("last_slot_of_range");
+    // "b" is in slot 3300, the last one of the first range
+    scenario.slots(0, 3300, 6379).slots(3301, 16383, 6380);
+
+    let values = (0..20).collect::<Vec<_>>();
+    let requests = send_in_order(&scenario, &values);
+    assert_eq!(
+        requests,
+        values
+            .iter()
+            .map(|value| (6379, format!("SET b {}", value)))
+            .collect::<Vec<_>>()
+    );
+}
+
+lazy_static::lazy_static! {
+    // The number of `Numbered` connections opened so far to each host and the clock of their
+    // `SLOW` replies, so that tests which run in parallel do not see each other's connections
+    static ref NUMBERED: Mutex<HashMap<String, (i64, VirtualClock)>> = Mutex::default();
+}
+
+fn numbered(host: &str) -> (i64, VirtualClock) {
+    let mut numbered = NUMBERED.lock().unwrap();
+    numbered.entry(host.to_string()).or_default().clone()
+}
+
+// A connection to a single node cluster on port 6379 of any host which answers with the order it
+// was opened in. It never answers `BLOCK`, answers `SLOW` after 50ms on the clock of its host and
+// fails `BREAK` with an I/O error.
+#[derive(Clone)]
+struct Numbered {
+    host: String,
+    number: i64,
+    clock: VirtualClock,
+}
+
+impl Connect for Numbered {
+    fn connect<'a, T>(info: T) -> RedisFuture<'a, Self>
+    where
+        T: IntoConnectionInfo + Send + 'a,
+    {
+        let host = match *info.into_connection_info().unwrap().addr {
+            ConnectionAddr::Tcp(host, _) => host,
+            addr => panic!("Unexpected address {:?}", addr),
+        };
+        let mut numbered = NUMBERED.lock().unwrap();
+        let (count, clock) = numbered.entry(host.clone()).or_default();
+        let number = *count;
+        *count += 1;
+        let clock = clock.clone();
+        future::ok(Numbered {
+            host,
+            number,
+            clock,
+        })
+        .boxed()
+    }
+}
+
+impl ConnectionLike for Numbered {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a redis::Cmd) -> RedisFuture<'a, Value> {
+        let packed = cmd.get_packed_command();
+        if let Err(response) = MockSlots::single(&self.host, 6379).respond(&packed) {
+            return future::ready(response).boxed();
+        }
+        let number = self.number;
+        if contains_slice(&packed, b"BLOCK") {
+            future::pending().boxed()
+        } else if contains_slice(&packed, b"SLOW") {
+            let delay = self.clock.delay(Duration::from_millis(50));
+            delay.map(move |_| Ok(Value::Int(number))).boxed()
+        } else if contains_slice(&packed, b"BREAK") {
+            let err = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+            future::err(err.into()).boxed()
+        } else {
+            future::ok(Value::Int(number)).boxed()
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        _: &'a redis::Pipeline,
+        _: usize,
+        _: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let err = RedisError::from((
+            redis::ErrorKind::ClientError,
+            "Numbered does not answer pipelines",
+        ));
+        future::err(err).boxed()
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+#[test]
+fn connection_pool() {
+    let _ = env_logger::try_init();
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let connect = |size, strategy| {
+        let mut client = Client::open(vec!["redis://connection_pool:6379"]).unwrap();
+        let size = NonZeroUsize::new(size).unwrap();
+        client.set_pool_size(size).set_pool_strategy(strategy);
+        async move {
+            let (first, _) = numbered("connection_pool");
+            let connection = client.get_generic_connection::<Numbered>().await;
+            (first, connection.unwrap())
+        }
+    };
+
+    runtime.block_on(async {
+        let query = |connection: &mut redis_cluster_async::Connection<Numbered>, name| {
+            let mut connection = connection.clone();
+            async move { cmd(name).query_async::<_, i64>(&mut connection).await }
+        };
+
+        // The connection used to load the slots is the first of the pool
+        let (first, mut connection) = connect(2, PoolStrategy::LeastLoaded).await;
+        assert_eq!(query(&mut connection, "GET").await, Ok(first));
+        let blocked = tokio::spawn(query(&mut connection, "BLOCK"));
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        // The other requests are not held up by the blocked one
+        assert_eq!(query(&mut connection, "GET").await, Ok(first + 1));
+        assert_eq!(query(&mut connection, "GET").await, Ok(first + 1));
+        // The broken connection is replaced while the blocked one is kept
+        assert!(query(&mut connection, "BREAK").await.is_err());
+        assert_eq!(query(&mut connection, "GET").await, Ok(first + 2));
+        drop(blocked);
+
+        let (first, mut connection) = connect(3, PoolStrategy::RoundRobin).await;
+        let mut numbers = Vec::new();
+        for _ in 0..4 {
+            numbers.push(query(&mut connection, "GET").await.unwrap() - first);
+        }
+        assert_eq!(numbers, vec![0, 1, 2, 0]);
+    });
+}
+
+#[test]
+fn overload() {
+    let _ = env_logger::try_init();
+
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    let (_, clock) = numbered("overload");
+    let one = NonZeroUsize::new(1);
+    let connect = |per_node, global, policy, queued| {
+        let mut client = Client::open(vec!["redis://overload:6379"]).unwrap();
+        client
+            .set_max_in_flight_per_node(per_node)
+            .set_max_in_flight(global)
+            .set_max_queued_per_node(queued)
+            .set_overload_policy(policy);
+        async move { client.get_generic_connection::<Numbered>().await.unwrap() }
+    };
+
+    runtime.block_on(async {
+        let query = |connection: &redis_cluster_async::Connection<Numbered>, name| {
+            let mut connection = connection.clone();
+            async move { cmd(name).query_async::<_, i64>(&mut connection).await }
+        };
+        // Lets the spawned requests reach the connection
+        let yield_now = || async {
+            for _ in 0..10 {
+                let () = tokio::task::yield_now().await;
+            }
+        };
+
+        for &(per_node, global) in &[(one, None), (None, one)] {
+            let connection = connect(per_node, global, OverloadPolicy::Fail, 0).await;
+            let blocked = tokio::spawn(query(&connection, "BLOCK"));
+            yield_now().await;
+            let err = query(&connection, "GET").await.unwrap_err();
+            assert!(is_overloaded(&err), "{}", err);
+            drop(blocked);
+
+            // There is room again once the request is answered
+            let connection = connect(per_node, global, OverloadPolicy::Fail, 0).await;
+            let slow = tokio::spawn(query(&connection, "SLOW"));
+            yield_now().await;
+            assert!(query(&connection, "GET").await.is_err());
+            clock.run(slow).await.unwrap().unwrap();
+            query(&connection, "GET").await.unwrap();
+        }
+
+        // A request waits for a node which reached its limit until its queue is full
+        let connection = connect(one, None, OverloadPolicy::Fail, 1).await;
+        let _blocked = tokio::spawn(query(&connection, "BLOCK"));
+        let _queued = tokio::spawn(query(&connection, "GET"));
+        yield_now().await;
+        let err = query(&connection, "GET").await.unwrap_err();
+        assert!(is_overloaded(&err), "{}", err);
+
+        for &(per_node, global) in &[(one, None), (None, one)] {
+            let connection = connect(per_node, global, OverloadPolicy::Wait, 100).await;
+            let slow = tokio::spawn(query(&connection, "SLOW"));
+            yield_now().await;
+            let start = clock.elapsed();
+            clock.run(query(&connection, "GET")).await.unwrap();
+            // Waited for the slow request to be answered
+            assert_eq!(clock.elapsed() - start, Duration::from_millis(50));
+            slow.await.unwrap().unwrap();
+        }
+
+        // The connection takes no other requests while the queue of a node is full
+        for &(queued, taken) in &[(1, false), (2, true)] {
+            let mut connection = connect(one, None, OverloadPolicy::Wait, queued).await;
+            let _blocked = tokio::spawn(query(&connection, "BLOCK"));
+            let _queued = tokio::spawn(query(&connection, "GET"));
+            yield_now().await;
+            let nodes = connection.nodes();
+            let timeout = clock.delay(Duration::from_secs(1));
+            let result = clock.run(future::select(nodes.boxed(), timeout)).await;
+            assert_eq!(matches!(result, future::Either::Left(_)), taken);
+        }
+    });
+}
+
+lazy_static::lazy_static! {
+    // The database and password of each `Subscriber` connection opened
+    static ref SUBSCRIBED: Mutex<Vec<(i64, Option<String>)>> = Mutex::default();
+}
+
+// A subscription connection which confirms `PSUBSCRIBE` and then pushes one message
+struct Subscriber {
+    pushed: bool,
+}
+
+impl Connect for Subscriber {
+    fn connect<'a, T>(info: T) -> RedisFuture<'a, Self>
+    where
+        T: IntoConnectionInfo + Send + 'a,
+    {
+        let info = info.into_connection_info().unwrap();
+        SUBSCRIBED.lock().unwrap().push((info.db, info.passwd));
+        future::ok(Subscriber { pushed: false }).boxed()
+    }
+}
+
+impl ConnectionLike for Subscriber {
+    fn req_packed_command<'a>(&'a mut self, _: &'a redis::Cmd) -> RedisFuture<'a, Value> {
+        unreachable!("Subscriptions only send pipelines")
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a redis::Pipeline,
+        _: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let data = |data: &str| Value::Data(data.as_bytes().to_vec());
+        // An empty pipeline reads the next message
+        if pipeline.cmd_iter().next().is_some() {
+            let confirm = Value::Bulk(vec![data("psubscribe"), data("__keyspace@2__:*")]);
+            return future::ok(vec![confirm; count]).boxed();
+        }
+        if self.pushed {
+            return future::pending().boxed();
+        }
+        self.pushed = true;
+        let message = Value::Bulk(vec![
+            data("pmessage"),
+            data("__keyspace@2__:*"),
+            data("__keyspace@2__:a"),
+            data("set"),
+        ]);
+        future::ok(vec![message]).boxed()
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+#[test]
+fn keyspace_subscription_settings() {
+    let _ = env_logger::try_init();
+    let name = "keyspace_subscription_settings";
+
+    let _handler = register_handler(name, move |cmd: &[u8], _| {
+        respond_startup(name, cmd)?;
+        panic!("Unexpected command {}", String::from_utf8_lossy(cmd))
+    });
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    let client = Client::open(vec![format!("redis://:secret@{}:6379/2", name)]).unwrap();
+
+    let mut events = runtime
+        .block_on(
+            client.subscribe_keyspace_events_generic::<MockConnection, Subscriber, _>(vec![
+                "__keyspace@2__:*",
+            ]),
+        )
+        .unwrap();
+    let message = runtime.block_on(events.next()).unwrap();
+    assert_eq!(message.node(), format!("redis://{}:6379", name));
+    assert_eq!(message.channel(), "__keyspace@2__:a");
+    assert_eq!(message.payload::<String>(), Ok("set".to_string()));
+    // The subscription uses the settings of the initial node rather than just its address
+    assert_eq!(
+        *SUBSCRIBED.lock().unwrap(),
+        vec![(2, Some("secret".to_string()))]
+    );
+}
+
+#[test]
+fn migrate_slot() {
+    let _ = env_logger::try_init();
+    let name = "migrate_slot";
+    let (from, to) = ("redis://migrate_slot:6379", "redis://migrate_slot:6380");
+    let data = |value: &str| Value::Data(value.as_bytes().to_vec());
+    let keys = |keys: &[&str]| Value::Bulk(keys.iter().map(|key| data(key)).collect());
+
+    let mut scenario = Scenario::new(name);
+    scenario.slots(0, 8191, 6379).slots(8192, 16383, 6380);
+    scenario
+        .node(6379)
+        .reply(data("source"))
+        .reply(Value::Okay)
+        .reply(keys(&["a", "b"]))
+        .error("IOERR timeout")
+        .reply(Value::Okay)
+        .reply(keys(&[]))
+        .reply(Value::Okay);
+    scenario
+        .node(6380)
+        .reply(data("target"))
+        .reply(Value::Okay)
+        .reply(Value::Okay);
+    let MockEnv {
+        mut runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = scenario.env();
+
+    let progress = runtime.block_on(connection.migrate_slot(100, from, to).collect::<Vec<_>>());
+    assert_eq!(
+        progress,
+        vec![
+            Ok(MigrationProgress::Started {
+                slot: 100,
+                from: from.to_string(),
+                to: to.to_string(),
+            }),
+            Ok(MigrationProgress::KeysMoved {
+                slot: 100,
+                count: 2,
+                total: 2,
+            }),
+            Ok(MigrationProgress::Finished { slot: 100, keys: 2 }),
+        ]
+    );
+    let migrate = "MIGRATE migrate_slot 6380  0 5000 REPLACE KEYS a b";
+    assert_eq!(
+        scenario.requests(),
+        vec![
+            (6379, "CLUSTER MYID".to_string()),
+            (6380, "CLUSTER MYID".to_string()),
+            (6380, "CLUSTER SETSLOT 100 IMPORTING source".to_string()),
+            (6379, "CLUSTER SETSLOT 100 MIGRATING target".to_string()),
+            (6379, "CLUSTER GETKEYSINSLOT 100 100".to_string()),
+            // An IOERR is retried
+            (6379, migrate.to_string()),
+            (6379, migrate.to_string()),
+            (6379, "CLUSTER GETKEYSINSLOT 100 100".to_string()),
+            (6380, "CLUSTER SETSLOT 100 NODE target".to_string()),
+            (6379, "CLUSTER SETSLOT 100 NODE target".to_string()),
+        ]
+    );
+
+    // A MIGRATE which fails leaves the slot migrating and stops the rebalance
+    let mut scenario = Scenario::new(name);
+    scenario.slots(0, 8191, 6379).slots(8192, 16383, 6380);
+    scenario
+        .node(6379)
+        .reply(data("source"))
+        .reply(Value::Okay)
+        .reply(keys(&["a"]))
+        .error("ERR Target instance replied with error");
+    scenario.node(6380).reply(data("target")).reply(Value::Okay);
+    let MockEnv {
+        mut runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = scenario.env();
+
+    let mut plan = RebalancePlan::new();
+    plan.move_slots((100, 101), from, to);
+    let progress = runtime.block_on(connection.rebalance(&plan).collect::<Vec<_>>());
+    assert_eq!(progress.len(), 2);
+    assert!(matches!(
+        progress[0],
+        Ok(MigrationProgress::Started { slot: 100, .. })
+    ));
+    let err = progress[1].as_ref().unwrap_err();
+    assert_eq!(err.code(), Some("ERR"));
+    let requests = scenario.requests();
+    assert_eq!(requests.len(), 6);
+    assert_eq!(
+        requests[5],
+        (
+            6379,
+            "MIGRATE migrate_slot 6380  0 5000 REPLACE KEYS a".to_string()
+        )
+    );
+}
+
+#[test]
+fn resume_migration() {
+    let _ = env_logger::try_init();
+    let name = "resume_migration";
+    let (from, to) = (
+        "redis://resume_migration:6379",
+        "redis://resume_migration:6380",
+    );
+
+    // An interrupted migration already copied "a" to the target without deleting it
+    let mut initial = HashMap::new();
+    initial.insert(6379, vec!["a".to_string(), "b".to_string()]);
+    initial.insert(6380, vec!["a".to_string()]);
+    let keys = Arc::new(Mutex::new(initial));
+    let mut slots = MockSlots::new(name);
+    slots.add(0, 8191, 6379, &[]).add(8192, 16383, 6380, &[]);
+    let MockEnv {
+        mut runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let keys = keys.clone();
+        move |cmd: &[u8], port| {
+            slots.respond(cmd)?;
+            let cmd = String::from_utf8_lossy(cmd);
+            let args = cmd.split("\r\n").skip(2).step_by(2).collect::<Vec<_>>();
+            let mut keys = keys.lock().unwrap();
+            match &args[..] {
+                ["CLUSTER", "MYID"] => Err(Ok(Value::Data(port.to_string().into_bytes()))),
+                ["CLUSTER", "SETSLOT", ..] => Err(Ok(Value::Okay)),
+                ["CLUSTER", "GETKEYSINSLOT", ..] => Err(Ok(Value::Bulk(
+                    keys[&port]
+                        .iter()
+                        .map(|key| Value::Data(key.as_bytes().to_vec()))
+                        .collect(),
+                ))),
+                ["MIGRATE", _, _, _, _, _, options @ ..] => {
+                    let split = options.iter().position(|arg| *arg == "KEYS").unwrap();
+                    let (options, moved) = options.split_at(split);
+                    let moved = &moved[1..];
+                    if !options.contains(&"REPLACE")
+                        && moved.iter().any(|key| keys[&6380].iter().any(|k| k == key))
+                    {
+                        return Err(parse_redis_value(
+                            b"-BUSYKEY Target key name already exists.\r\n",
+                        ));
+                    }
+                    let source = keys.get_mut(&6379).unwrap();
+                    source.retain(|key| !moved.contains(&&key[..]));
+                    let target = keys.get_mut(&6380).unwrap();
+                    target.extend(moved.iter().map(|key| key.to_string()));
+                    target.sort();
+                    target.dedup();
+                    Err(Ok(Value::Okay))
+                }
+                _ => panic!("Unexpected command {}", cmd),
+            }
+        }
+    });
+
+    let progress = runtime.block_on(connection.migrate_slot(100, from, to).collect::<Vec<_>>());
+    assert_eq!(
+        progress.last(),
+        Some(&Ok(MigrationProgress::Finished { slot: 100, keys: 2 })),
+        "{:?}",
+        progress
+    );
+    let keys = keys.lock().unwrap();
+    assert!(keys[&6379].is_empty());
+    assert_eq!(keys[&6380], vec!["a", "b"]);
+}
+
+// A span as seen by `Captured`. Its id is its index in `CapturedSpans::spans` plus one.
+#[cfg(feature = "tracing")]
+#[derive(Debug)]
+struct CapturedSpan {
+    name: &'static str,
+    parent: Option<u64>,
+    fields: HashMap<String, String>,
+}
+
+#[cfg(feature = "tracing")]
+#[derive(Default)]
+struct CapturedSpans {
+    spans: Vec<CapturedSpan>,
+    // The spans entered on the thread of the test, for spans with a contextual parent
+    entered: Vec<u64>,
+}
+
+// Collects every span with its parent and fields
+#[cfg(feature = "tracing")]
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<CapturedSpans>>);
+
+#[cfg(feature = "tracing")]
+struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+#[cfg(feature = "tracing")]
+impl tracing::field::Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl tracing::Subscriber for Captured {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attrs: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        let mut captured = self.0.lock().unwrap();
+        let parent = match attrs.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if attrs.is_contextual() => captured.entered.last().copied(),
+            None => None,
+        };
+        let mut fields = HashMap::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+        captured.spans.push(CapturedSpan {
+            name: attrs.metadata().name(),
+            parent,
+            fields,
+        });
+        tracing::span::Id::from_u64(captured.spans.len() as u64)
+    }
+
+    fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+        let mut captured = self.0.lock().unwrap();
+        let span = &mut captured.spans[span.into_u64() as usize - 1];
+        values.record(&mut FieldVisitor(&mut span.fields));
+    }
+
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+    fn event(&self, _: &tracing::Event<'_>) {}
+
+    fn enter(&self, span: &tracing::span::Id) {
+        self.0.lock().unwrap().entered.push(span.into_u64());
+    }
+
+    fn exit(&self, span: &tracing::span::Id) {
+        let entered = &mut self.0.lock().unwrap().entered;
+        if let Some(i) = entered.iter().rposition(|id| *id == span.into_u64()) {
+            entered.remove(i);
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_spans() {
+    let _ = env_logger::try_init();
+    let name = "tracing_spans";
+
+    let MockEnv {
+        mut runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup(name, cmd)?;
+        let cmd = String::from_utf8_lossy(cmd);
+        let cmd = cmd.split("\r\n").nth(2).unwrap().to_string();
+        match (port, &cmd[..]) {
+            (6379, "GET") => Err(moved(123, name, 6380)),
+            (6380, "GET") => Err(parse_redis_value(
+                format!("-ASK 123 {}:6381\r\n", name).as_bytes(),
+            )),
+            (6381, "ASKING") => Err(Ok(Value::Okay)),
+            (6381, "GET") => Err(Ok(Value::Data(b"123".to_vec()))),
+            _ => panic!("Unexpected request {} to {}", cmd, port),
+        }
+    });
+
+    let captured = Captured::default();
+    tracing::subscriber::with_default(captured.clone(), || {
+        let value = runtime.block_on(
+            cmd("GET")
+                .arg("test")
+                .query_async::<_, Option<i32>>(&mut connection),
+        );
+        assert_eq!(value, Ok(Some(123)));
+        // Waits for the recovery which the MOVED started
+        runtime.block_on(connection.slots()).unwrap();
+    });
+
+    let captured = captured.0.lock().unwrap();
+    let spans = &captured.spans;
+    let ids = |name: &str, parent: Option<u64>| {
+        (1..)
+            .zip(spans)
+            .filter(|(_, span)| span.name == name && span.parent == parent)
+            .map(|(id, _)| id)
+            .collect::<Vec<u64>>()
+    };
+    let field = |id: u64, field: &str| spans[id as usize - 1].fields.get(field).cloned();
+
+    let request = ids("redis_cluster.request", None);
+    assert_eq!(request.len(), 1, "{:#?}", spans);
+    let request = request[0];
+    assert_eq!(field(request, "command").as_deref(), Some("GET"));
+    let slot = redis_cluster_async::key_slot(b"test").to_string();
+    assert_eq!(field(request, "slot"), Some(slot));
+    assert_eq!(field(request, "attempts").as_deref(), Some("3"));
+
+    let attempts = ids("redis_cluster.attempt", Some(request))
+        .into_iter()
+        .map(|id| {
+            (
+                field(id, "retry").unwrap(),
+                field(id, "redirect"),
+                field(id, "node").unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let node = |port: u16| format!("redis://{}:{}", name, port);
+    assert_eq!(
+        attempts,
+        vec![
+            ("0".to_string(), None, node(6379)),
+            ("1".to_string(), Some("moved".to_string()), node(6380)),
+            ("2".to_string(), Some("ask".to_string()), node(6381)),
+        ]
+    );
+
+    let recovery = ids("redis_cluster.recover", None);
+    assert_eq!(recovery.len(), 1, "{:#?}", spans);
+    assert_eq!(field(recovery[0], "reason").as_deref(), Some("moved"));
+    let refreshes = ids("redis_cluster.refresh_slots", Some(recovery[0]));
+    assert_eq!(refreshes.len(), 1, "{:#?}", spans);
+}
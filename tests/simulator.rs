@@ -0,0 +1,108 @@
+use redis_cluster_async::{
+    redis::{cmd, RedisResult},
+    testing::SimCluster,
+};
+
+#[tokio::test]
+async fn routes_to_the_owner() -> RedisResult<()> {
+    let _ = env_logger::try_init();
+    let cluster = SimCluster::start(3).await?;
+    let mut connection = cluster.client()?.get_connection().await?;
+
+    for i in 0..20 {
+        let key = format!("key{}", i);
+        let () = cmd("SET")
+            .arg(&key)
+            .arg(i)
+            .query_async(&mut connection)
+            .await?;
+        let value: i32 = cmd("GET").arg(&key).query_async(&mut connection).await?;
+        assert_eq!(value, i);
+    }
+
+    let values: (i32, i32) = redis::pipe()
+        .cmd("INCR")
+        .arg("{user}:a")
+        .cmd("INCR")
+        .arg("{user}:b")
+        .query_async(&mut connection)
+        .await?;
+    assert_eq!(values, (1, 1));
+    Ok(())
+}
+
+#[tokio::test]
+async fn follows_migrations() -> RedisResult<()> {
+    let _ = env_logger::try_init();
+    let cluster = SimCluster::start(3).await?;
+    let mut connection = cluster.client()?.get_connection().await?;
+
+    // "foo" and "{foo}:new" are in slot 12182
+    let () = cmd("SET")
+        .arg("foo")
+        .arg("bar")
+        .query_async(&mut connection)
+        .await?;
+    let to = (cluster.owner(12182) + 1) % 3;
+    cluster.start_migration(12182, to);
+
+    // New keys are written to the target of the migration with ASK
+    let () = cmd("SET")
+        .arg("{foo}:new")
+        .arg("1")
+        .query_async(&mut connection)
+        .await?;
+    let value: String = cmd("GET").arg("foo").query_async(&mut connection).await?;
+    assert_eq!(value, "bar");
+
+    cluster.finish_migration(12182);
+    assert_eq!(cluster.owner(12182), to);
+    let value: String = cmd("GET").arg("foo").query_async(&mut connection).await?;
+    assert_eq!(value, "bar");
+    let value: String = cmd("GET")
+        .arg("{foo}:new")
+        .query_async(&mut connection)
+        .await?;
+    assert_eq!(value, "1");
+
+    let slots = connection.slots().await?;
+    let range = slots
+        .ranges
+        .iter()
+        .find(|range| range.start <= 12182 && 12182 <= range.end)
+        .unwrap();
+    assert_eq!(range.master, cluster.nodes()[to]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn recovers_from_a_killed_node() -> RedisResult<()> {
+    let _ = env_logger::try_init();
+    let cluster = SimCluster::start(3).await?;
+    let mut connection = cluster.client()?.get_connection().await?;
+
+    let () = cmd("SET")
+        .arg("foo")
+        .arg("bar")
+        .query_async(&mut connection)
+        .await?;
+    let owner = cluster.owner(12182);
+    let to = (owner + 1) % 3;
+    cluster.kill(owner);
+    cluster.assign_slots(0..=16383, to);
+
+    // The key was lost along with the node
+    let value: Option<String> = cmd("GET").arg("foo").query_async(&mut connection).await?;
+    assert_eq!(value, None);
+    let () = cmd("SET")
+        .arg("foo")
+        .arg("baz")
+        .query_async(&mut connection)
+        .await?;
+    assert_eq!(cluster.get(owner, b"foo"), Some(b"bar".to_vec()));
+    assert_eq!(cluster.get(to, b"foo"), Some(b"baz".to_vec()));
+
+    let nodes = connection.nodes().await?;
+    assert_eq!(nodes.nodes, vec![cluster.nodes()[to].clone()]);
+    Ok(())
+}